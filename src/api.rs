@@ -1,64 +1,421 @@
 use std::any::Any;
-use std::io::ErrorKind;
+use std::collections::HashSet;
+use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{bail, Context};
-use axum::extract::rejection::JsonRejection;
-use axum::extract::{self, Query, State};
+use axum::error_handling::HandleErrorLayer;
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{self, DefaultBodyLimit, FromRef, Query, State};
+use axum::handler::Handler;
+use axum::http::header::{
+    ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+    VARY,
+};
+use axum::http::{HeaderMap, Method, StatusCode};
 use axum::response::IntoResponse;
 use axum::response::Response;
-use axum::{Json, Router};
+use axum::routing::{MethodFilter, MethodRouter};
+use axum::{Extension, Json, Router};
 use serde::{Deserialize, Serialize};
+use tokio_util::io::ReaderStream;
+use tower::timeout::TimeoutLayer;
+use tower::{BoxError, Layer, ServiceBuilder};
 use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::normalize_path::NormalizePathLayer;
 use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::TraceLayer;
 
+use crate::config::FileStoreLayout;
+use crate::file_store::{self, FileStore};
 use crate::hash;
+use crate::index;
 use crate::location;
 use crate::metadata;
+use crate::metadata::IdIndex;
 use crate::metrics::{
-    self, register_build_info_metrics, register_process_metrics, HttpMetrics, RepositoryMetrics,
+    self, register_build_info_metrics, register_process_metrics, CacheMetrics, GitMetrics,
+    HttpMetrics, MetricsRegistries, RepositoryMetrics,
 };
 use crate::outpack_file::OutpackFile;
-use crate::responses::{OutpackError, OutpackSuccess};
+use crate::rate_limit::{self, RateLimiter};
+use crate::responses::{coded_error, json_format, ErrorCode, OutpackError, OutpackSuccess};
+use crate::server::{self, ConnectionSettings};
 use crate::store;
-use crate::upload::{Upload, UploadLayer};
-use crate::{config, git};
+use crate::upload::{self, PartialUpload, Upload, UploadConfig, UploadLayer};
+use crate::worker_pool::WorkerPool;
+use crate::{config, git, schema, verify, zip};
 
 type OutpackResult<T> = Result<OutpackSuccess<T>, OutpackError>;
 
+/// Size limit applied to request bodies, other than file uploads, when `--max-body-size` isn't
+/// given. This matches axum's own default.
+const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Time limit applied to requests when `--request-timeout` isn't given.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options controlling the behaviour of the API server.
+///
+/// These can be set from the command line when starting the server. The defaults are chosen to
+/// match the behaviour of the server before the option was introduced.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ServerOptions {
+    /// List unknown locations as empty, rather than returning a 404.
+    #[arg(long)]
+    pub allow_unknown_locations: bool,
+
+    /// Allow serving a repository that uses this hash algorithm, in addition to sha256. May be
+    /// given more than once.
+    #[arg(long = "allow-hash-algorithm", value_name = "ALGORITHM")]
+    pub allowed_hash_algorithms: Vec<hash::HashAlgorithm>,
+
+    /// Close connections which have had no activity for this many seconds, including keep-alive
+    /// connections waiting for their next request. Defaults to 120 seconds. Zero keeps idle
+    /// connections open indefinitely.
+    #[arg(long, alias = "keep-alive-timeout", value_name = "SECONDS")]
+    pub idle_timeout: Option<u64>,
+
+    /// Close connections which take longer than this many seconds to send the headers of a
+    /// request. Defaults to 30 seconds.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub header_read_timeout: Option<u64>,
+
+    /// Accept at most this many connections at once. Further clients wait until a connection is
+    /// closed. Defaults to 1024.
+    #[arg(long, value_name = "CONNECTIONS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connections: Option<u64>,
+
+    /// Abort requests which take longer than this many seconds to complete. File downloads and
+    /// uploads are not subject to this limit. Defaults to 30 seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub request_timeout: Option<u64>,
+
+    /// Reject request bodies larger than this many bytes, such as very long lists of ids, with a
+    /// `413` error. File uploads are not subject to this limit. Defaults to 2MiB.
+    #[arg(long, value_name = "BYTES")]
+    pub max_body_size: Option<usize>,
+
+    /// Look for inconsistencies in the repository when starting, such as metadata which doesn't
+    /// parse or location entries for unknown packets, and log a warning for each one. The server
+    /// starts regardless.
+    #[arg(long)]
+    pub check: bool,
+
+    /// Check the metadata of new packets against the outpack metadata schema, rejecting any that
+    /// don't conform.
+    #[arg(long)]
+    pub validate_metadata: bool,
+
+    /// Namespace given to the server's metrics. Defaults to `outpack_server`.
+    #[arg(long, value_name = "NAMESPACE", value_parser = metrics::parse_namespace)]
+    pub metrics_namespace: Option<String>,
+
+    /// Namespace given to the HTTP request metrics. Defaults to `http`.
+    #[arg(long, value_name = "NAMESPACE", value_parser = metrics::parse_namespace)]
+    pub http_metrics_namespace: Option<String>,
+
+    /// Compute the repository metrics, such as the number and size of files, on a background
+    /// thread every this many seconds, rather than whenever metrics are requested. This keeps
+    /// requests for metrics quick for large repositories, at the cost of the values being out of
+    /// date.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub metrics_refresh_interval: Option<u64>,
+
+    /// Upper bounds, in seconds, of the histogram buckets for HTTP request durations, as a
+    /// comma-separated list in increasing order. Defaults to the Prometheus client's buckets,
+    /// which go up to 10 seconds.
+    #[arg(long, value_name = "SECONDS", value_delimiter = ',')]
+    pub request_duration_buckets: Vec<f64>,
+
+    /// Number of threads used to hash files, when they are uploaded or the repository is
+    /// verified. Further work waits until a thread is free. Defaults to the number of CPUs.
+    #[arg(long, value_name = "THREADS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub hashing_threads: Option<u64>,
+
+    /// Directory in which in-progress uploads are stored, relative to the repository root. It
+    /// must be on the same filesystem as the repository. Defaults to `.outpack/.tmp`.
+    #[arg(long, value_name = "PATH")]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Also delete stale temporary files in the upload directory every this many seconds while the
+    /// server is running. Files more than a day old, left behind by uploads that never finished,
+    /// are deleted. By default this is only done when the server starts.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub temp_cleanup_interval: Option<u64>,
+
+    /// Allow at most this many requests per second, across all clients, to the routes which
+    /// write to the repository: uploading files and packets, and fetching from git. Further
+    /// requests are rejected with a `429` error. By default, writes are not limited.
+    #[arg(long, value_name = "REQUESTS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub write_rate_limit: Option<u32>,
+
+    /// Serve every route under this prefix, such as `/outpack`, for use behind a reverse proxy
+    /// which forwards requests without removing it. By default, routes are served from `/`.
+    #[arg(long, value_name = "PATH", value_parser = parse_base_path)]
+    pub base_path: Option<String>,
+
+    /// Let panics in request handlers propagate, rather than turning them into a `500` error, so
+    /// that their backtrace is logged. A panic then drops the connection without a response, so
+    /// this is only meant for debugging.
+    #[arg(long)]
+    pub disable_catch_panic: bool,
+}
+
+/// Check a base path given on the command line, removing any trailing slash.
+fn parse_base_path(path: &str) -> Result<String, String> {
+    let trimmed = path.trim_end_matches('/');
+    if !path.starts_with('/') {
+        Err(format!("base path '{}' must start with '/'", path))
+    } else if trimmed.is_empty() {
+        Err(String::from(
+            "base path must not be '/'; leave it out to serve from the root",
+        ))
+    } else {
+        Ok(trimmed.to_owned())
+    }
+}
+
+/// The state shared by all the request handlers.
+///
+/// Handlers can extract any of the individual fields using the `State` extractor, thanks to the
+/// `FromRef` implementations below.
+#[derive(Clone)]
+struct AppState {
+    root: PathBuf,
+    /// The layout of the file store, read from the configuration when the server starts.
+    layout: FileStoreLayout,
+    files: Arc<dyn FileStore>,
+    ids: IdIndex,
+    options: ServerOptions,
+    git_lock: GitLock,
+    git_metrics: GitMetrics,
+    routes: Arc<Vec<RouteInfo>>,
+}
+
+/// Held while running git operations which modify the repository, so that concurrent requests
+/// don't interleave their changes.
+#[derive(Clone, Default)]
+struct GitLock(Arc<tokio::sync::Mutex<()>>);
+
+impl FromRef<AppState> for PathBuf {
+    fn from_ref(state: &AppState) -> PathBuf {
+        state.root.clone()
+    }
+}
+
+impl FromRef<AppState> for IdIndex {
+    fn from_ref(state: &AppState) -> IdIndex {
+        state.ids.clone()
+    }
+}
+
+impl FromRef<AppState> for FileStoreLayout {
+    fn from_ref(state: &AppState) -> FileStoreLayout {
+        state.layout
+    }
+}
+
+impl FromRef<AppState> for Arc<Vec<RouteInfo>> {
+    fn from_ref(state: &AppState) -> Arc<Vec<RouteInfo>> {
+        state.routes.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn FileStore> {
+    fn from_ref(state: &AppState) -> Arc<dyn FileStore> {
+        state.files.clone()
+    }
+}
+
+impl FromRef<AppState> for ServerOptions {
+    fn from_ref(state: &AppState) -> ServerOptions {
+        state.options.clone()
+    }
+}
+
+impl FromRef<AppState> for GitLock {
+    fn from_ref(state: &AppState) -> GitLock {
+        state.git_lock.clone()
+    }
+}
+
+impl FromRef<AppState> for GitMetrics {
+    fn from_ref(state: &AppState) -> GitMetrics {
+        state.git_metrics.clone()
+    }
+}
+
 // This mostly exists to smooth over a difference with original
 // version, which used Root as the object; soon we will update this to
 // report actual versions back.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ApiRoot {
     pub schema_version: String,
+    /// The routes served, so that clients can check which features are available.
+    pub routes: Vec<RouteInfo>,
+}
+
+/// A route, in axum's syntax where `:name` is a parameter and `*name` matches the rest of the
+/// path, along with the methods it accepts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouteInfo {
+    pub path: String,
+    pub methods: Vec<String>,
+}
+
+/// Builds a router while recording the routes added to it, so that they can be listed by `GET /`.
+#[derive(Default)]
+struct RouteTable {
+    router: Router<AppState>,
+    routes: Vec<RouteInfo>,
+}
+
+impl RouteTable {
+    fn route(mut self, path: &str, methods: RouteMethods) -> RouteTable {
+        let mut listed: Vec<String> = methods.methods.iter().map(Method::to_string).collect();
+        listed.sort();
+        self.routes.push(RouteInfo {
+            path: path.to_owned(),
+            methods: listed,
+        });
+        self.router = self.router.route(path, methods.router);
+        self
+    }
+
+    fn finish(self) -> (Router<AppState>, Vec<RouteInfo>) {
+        (self.router, self.routes)
+    }
+}
+
+/// A route's handlers, along with the methods they accept, so that `RouteTable` can list them.
+/// This is started with `get` or `post` and chained in the same way as axum's `MethodRouter`.
+/// `HEAD` isn't listed, as it is implied by `GET`.
+struct RouteMethods {
+    router: MethodRouter<AppState>,
+    methods: Vec<Method>,
+}
+
+impl RouteMethods {
+    fn on<H, T>(mut self, method: Method, handler: H) -> RouteMethods
+    where
+        H: Handler<T, AppState>,
+        T: 'static,
+    {
+        let filter = MethodFilter::try_from(method.clone()).expect("method can be routed");
+        self.router = self.router.on(filter, handler);
+        self.methods.push(method);
+        self
+    }
+
+    fn get<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> RouteMethods {
+        self.on(Method::GET, handler)
+    }
+
+    fn post<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> RouteMethods {
+        self.on(Method::POST, handler)
+    }
+
+    fn patch<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> RouteMethods {
+        self.on(Method::PATCH, handler)
+    }
+
+    fn delete<H: Handler<T, AppState>, T: 'static>(self, handler: H) -> RouteMethods {
+        self.on(Method::DELETE, handler)
+    }
+}
+
+fn route_methods() -> RouteMethods {
+    RouteMethods {
+        router: MethodRouter::new(),
+        methods: Vec::new(),
+    }
+}
+
+fn get<H: Handler<T, AppState>, T: 'static>(handler: H) -> RouteMethods {
+    route_methods().get(handler)
+}
+
+fn post<H: Handler<T, AppState>, T: 'static>(handler: H) -> RouteMethods {
+    route_methods().post(handler)
 }
 
 fn internal_error(_err: Box<dyn Any + Send + 'static>) -> Response {
     OutpackError {
         error: String::from("UNKNOWN_ERROR"),
         detail: String::from("Something went wrong"),
+        code: ErrorCode::InternalError,
         kind: Some(ErrorKind::Other),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
     }
     .into_response()
 }
 
+async fn request_timed_out(_err: BoxError) -> OutpackError {
+    OutpackError {
+        error: String::from("TIMEOUT"),
+        detail: String::from("The request took too long to complete"),
+        code: ErrorCode::Timeout,
+        kind: Some(ErrorKind::TimedOut),
+        status: StatusCode::GATEWAY_TIMEOUT,
+    }
+}
+
 async fn not_found() -> OutpackError {
     OutpackError {
         error: String::from("NOT_FOUND"),
         detail: String::from("This route does not exist"),
+        code: ErrorCode::RouteNotFound,
         kind: Some(ErrorKind::NotFound),
+        status: StatusCode::NOT_FOUND,
     }
 }
 
-async fn index() -> OutpackResult<ApiRoot> {
+/// Whether the request has an `If-None-Match: *` header, asking for a resource to only be created
+/// if it doesn't already exist.
+fn only_if_absent(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .any(|value| value.to_str().is_ok_and(|v| v.trim() == "*"))
+}
+
+fn already_exists(what: String) -> OutpackError {
+    OutpackError {
+        error: String::from("PRECONDITION_FAILED"),
+        detail: format!("{} already exists", what),
+        code: ErrorCode::PreconditionFailed,
+        kind: Some(ErrorKind::AlreadyExists),
+        status: StatusCode::PRECONDITION_FAILED,
+    }
+}
+
+async fn index(routes: State<Arc<Vec<RouteInfo>>>) -> OutpackResult<ApiRoot> {
     Ok(OutpackSuccess::from(ApiRoot {
         schema_version: String::from("0.1.1"),
+        routes: routes.to_vec(),
     }))
 }
 
+/// Browsers ask for an icon when the server is opened in one. Answering with no content, rather
+/// than falling through to `not_found`, keeps these requests out of the logged and counted 404s.
+async fn favicon() -> StatusCode {
+    StatusCode::NO_CONTENT
+}
+
+async fn list_locations(root: State<PathBuf>) -> OutpackResult<Vec<config::LocationSummary>> {
+    let config = config::read_config(&root)?;
+    let locations: Vec<config::LocationSummary> = config
+        .location
+        .iter()
+        .map(config::Location::summary)
+        .collect();
+    Ok(OutpackSuccess::from(locations))
+}
+
 async fn list_location_metadata(
     root: State<PathBuf>,
 ) -> OutpackResult<Vec<location::LocationEntry>> {
@@ -67,25 +424,335 @@ async fn list_location_metadata(
         .map(OutpackSuccess::from)
 }
 
+/// The header used to report the total number of items available, when only a page of them has
+/// been returned.
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+#[derive(Deserialize)]
+struct Paging {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+async fn list_named_location_metadata(
+    root: State<PathBuf>,
+    options: State<ServerOptions>,
+    name: extract::Path<String>,
+    paging: Query<Paging>,
+) -> Result<impl IntoResponse, OutpackError> {
+    let entries = match location::read_named_location(&root, &name) {
+        Err(e) if e.kind() == ErrorKind::NotFound && options.allow_unknown_locations => Vec::new(),
+        result => result?,
+    };
+    let page = location::page_entries(entries, paging.offset, paging.limit);
+    Ok((
+        [(TOTAL_COUNT_HEADER, page.total.to_string())],
+        OutpackSuccess::from(page.entries),
+    ))
+}
+
 #[derive(Deserialize)]
 struct KnownSince {
     known_since: Option<f64>,
 }
 
+#[derive(Deserialize)]
+struct KnownUntil {
+    known_until: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct TimeFieldOption {
+    #[serde(default)]
+    time_field: metadata::TimeField,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PackitMetadataFormat {
+    /// A single JSON response, containing an array of packets.
+    #[default]
+    Json,
+    /// Newline-delimited JSON, with one packet per line and no response envelope, which is
+    /// streamed as the packets are read.
+    Jsonl,
+}
+
+#[derive(Deserialize)]
+struct PackitMetadataOptions {
+    #[serde(default)]
+    format: PackitMetadataFormat,
+}
+
+/// The content type of newline-delimited JSON.
+const JSONL_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Stream items as newline-delimited JSON, reading them on a blocking thread.
+///
+/// Once streaming has started the status can no longer be changed, so if an item can't be read
+/// the response is cut short, and the client sees an incomplete body.
+fn stream_json_lines<T: Serialize>(
+    what: &'static str,
+    items: impl Iterator<Item = io::Result<T>> + Send + 'static,
+) -> axum::body::Body {
+    let (tx, rx) = tokio::sync::mpsc::channel::<io::Result<Vec<u8>>>(16);
+    tokio::task::spawn_blocking(move || {
+        for item in items {
+            let line = item.and_then(|p| {
+                let mut line = serde_json::to_vec(&p)?;
+                line.push(b'\n');
+                Ok(line)
+            });
+            if let Err(e) = &line {
+                tracing::error!("error while streaming {}: {}", what, e);
+            }
+            let failed = line.is_err();
+            // The client has gone away if the receiver was dropped.
+            if tx.blocking_send(line).is_err() || failed {
+                break;
+            }
+        }
+    });
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|line| (line, rx))
+    });
+    axum::body::Body::from_stream(stream)
+}
+
+/// Whether a response last modified at the given time can be skipped, because the client's copy,
+/// according to its `If-Modified-Since` header, is at least as recent.
+///
+/// HTTP dates only have a resolution of one second, so times are compared to the second. An
+/// invalid header is ignored, as the specification requires.
+fn is_not_modified(headers: &HeaderMap, last_modified: SystemTime) -> bool {
+    headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .is_some_and(|since| last_modified <= since)
+}
+
+/// Clients poll this endpoint, so it reports when the most recent packet was added as
+/// `Last-Modified`, and responds with `304 Not Modified` to conditional requests if nothing has
+/// been added since. New packets always have a later time than those already known, so this is
+/// also correct in combination with `known_since` and `known_until`, whichever `time_field` they
+/// apply to, as a newly added packet still changes the time reported.
 async fn get_metadata_since(
     root: State<PathBuf>,
-    query: Query<KnownSince>,
+    headers: HeaderMap,
+    since: Result<Query<KnownSince>, QueryRejection>,
+    until: Result<Query<KnownUntil>, QueryRejection>,
+    field: Result<Query<TimeFieldOption>, QueryRejection>,
+    options: Result<Query<PackitMetadataOptions>, QueryRejection>,
+) -> Result<Response, OutpackError> {
+    // Axum's own rejection is plain text, which clients can't parse as an error response.
+    let invalid = |name: &str| {
+        coded_error(
+            ErrorKind::InvalidInput,
+            ErrorCode::InvalidRequest,
+            format!("invalid {} parameter", name),
+        )
+    };
+    let range = metadata::TimeRange {
+        since: since.map_err(|_| invalid("known_since"))?.known_since,
+        until: until.map_err(|_| invalid("known_until"))?.known_until,
+        field: field
+            .map_err(|_| {
+                coded_error(
+                    ErrorKind::InvalidInput,
+                    ErrorCode::InvalidRequest,
+                    "invalid time_field parameter, expected 'imported' or 'created'",
+                )
+            })?
+            .time_field,
+    };
+    let options = options.map_err(|_| {
+        coded_error(
+            ErrorKind::InvalidInput,
+            ErrorCode::InvalidRequest,
+            "invalid format parameter, expected 'json' or 'jsonl'",
+        )
+    })?;
+
+    // Conditional requests are only an optimisation, so if the locations can't be read the full
+    // response is sent, which will report any problem with the repository itself.
+    let last_modified = location::last_modified(&root)
+        .ok()
+        .flatten()
+        .map(|time| UNIX_EPOCH + Duration::from_secs(time.max(0.) as u64));
+
+    let mut response_headers = HeaderMap::new();
+    if let Some(last_modified) = last_modified {
+        let value = httpdate::fmt_http_date(last_modified);
+        response_headers.insert(LAST_MODIFIED, value.parse().unwrap());
+        if is_not_modified(&headers, last_modified) {
+            return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+        }
+    }
+
+    if options.format == PackitMetadataFormat::Jsonl {
+        let packets = metadata::iter_packit_metadata_from_date(&root, range)?;
+        response_headers.insert(CONTENT_TYPE, JSONL_CONTENT_TYPE.parse().unwrap());
+        return Ok((
+            response_headers,
+            stream_json_lines("packit metadata", packets),
+        )
+            .into_response());
+    }
+
+    let packets = metadata::get_packit_metadata_from_date(&root, range)?;
+    Ok((response_headers, OutpackSuccess::from(packets)).into_response())
+}
+
+/// Every packet's metadata as newline-delimited JSON, so that a new mirror can fetch it all in one
+/// request rather than one per packet.
+async fn get_all_metadata(root: State<PathBuf>) -> Result<Response, OutpackError> {
+    let packets = metadata::iter_raw_metadata(&root)?;
+    Ok((
+        [(CONTENT_TYPE, JSONL_CONTENT_TYPE)],
+        stream_json_lines("metadata", packets),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct PacketSearch {
+    query: String,
+}
+
+/// Find the packets matching a query, returning the same metadata as `/packit/metadata` so that
+/// clients don't need to fetch each packet in turn.
+async fn search_packets(
+    root: State<PathBuf>,
+    query: Result<Query<PacketSearch>, QueryRejection>,
 ) -> OutpackResult<Vec<metadata::PackitPacket>> {
-    metadata::get_packit_metadata_from_date(&root, query.known_since)
+    let query = query.map_err(|_| {
+        coded_error(
+            ErrorKind::InvalidInput,
+            ErrorCode::InvalidRequest,
+            "missing or invalid query parameter",
+        )
+    })?;
+    metadata::search_packit_metadata(&root, &query.query)
         .map_err(OutpackError::from)
         .map(OutpackSuccess::from)
 }
 
+/// The header used to report the expected hash of a packet's metadata, so that clients can
+/// verify what they received.
+const PACKET_HASH_HEADER: &str = "x-outpack-hash";
+
+/// Headers carrying the packet's known hash, if any location has recorded one.
+fn packet_hash_headers(root: &Path, id: &str) -> Result<HeaderMap, OutpackError> {
+    let mut headers = HeaderMap::new();
+    if let Some(hash) = location::get_packet_hash(root, id)? {
+        if let Ok(value) = hash.parse() {
+            headers.insert(PACKET_HASH_HEADER, value);
+        }
+    }
+    Ok(headers)
+}
+
 async fn get_metadata_by_id(
     root: State<PathBuf>,
     id: extract::Path<String>,
-) -> OutpackResult<serde_json::Value> {
-    metadata::get_metadata_by_id(&root, &id)
+) -> Result<impl IntoResponse, OutpackError> {
+    let packet = metadata::get_metadata_by_id(&root, &id)?;
+    let headers = packet_hash_headers(&root, &id)?;
+    Ok((headers, OutpackSuccess::from(packet)))
+}
+
+/// Whether a request's `Accept` header prefers plain text to JSON. Media types are compared by
+/// their quality values, and JSON wins ties, including when there is no header at all.
+fn prefers_text(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mut text: f32 = 0.0;
+    let mut json: f32 = 0.0;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default();
+        let quality = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        if media_type.eq_ignore_ascii_case("text/plain") {
+            text = text.max(quality);
+        } else if media_type.eq_ignore_ascii_case("application/json") {
+            json = json.max(quality);
+        }
+    }
+    text > json
+}
+
+/// Serve a packet's metadata either as JSON, like `/metadata/:id/json`, or as the text it is
+/// stored as, like `/metadata/:id/text`, according to the `Accept` header.
+async fn get_metadata(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, OutpackError> {
+    let response = if prefers_text(&headers) {
+        get_metadata_raw(root, id).await?.into_response()
+    } else {
+        get_metadata_by_id(root, id).await?.into_response()
+    };
+    Ok(([(VARY, "accept")], response).into_response())
+}
+
+async fn get_packet_files(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+) -> OutpackResult<Vec<metadata::PacketFileStatus>> {
+    metadata::get_packet_files(&root, &id)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[derive(Serialize, Deserialize)]
+struct PacketExists {
+    exists: bool,
+}
+
+async fn packet_exists(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+) -> OutpackResult<PacketExists> {
+    metadata::packet_exists(&root, &id)
+        .map_err(OutpackError::from)
+        .map(|exists| OutpackSuccess::from(PacketExists { exists }))
+}
+
+async fn touch_packet(
+    root: State<PathBuf>,
+    ids: State<IdIndex>,
+    id: extract::Path<String>,
+) -> OutpackResult<location::LocationEntry> {
+    let entry = metadata::touch_packet(&root, &id)?;
+    ids.add(&entry.packet, true);
+    Ok(OutpackSuccess::from(entry))
+}
+
+#[derive(Deserialize)]
+struct DependencyDepth {
+    depth: Option<usize>,
+}
+
+async fn get_packet_dependencies(
+    root: State<PathBuf>,
+    id: extract::Path<String>,
+    query: Result<Query<DependencyDepth>, QueryRejection>,
+) -> OutpackResult<index::DependencyGraph> {
+    let query = query.map_err(|_| {
+        coded_error(
+            ErrorKind::InvalidInput,
+            ErrorCode::InvalidRequest,
+            "invalid depth parameter",
+        )
+    })?;
+    index::get_dependency_graph(&root, &id, query.depth)
         .map_err(OutpackError::from)
         .map(OutpackSuccess::from)
 }
@@ -93,79 +760,456 @@ async fn get_metadata_by_id(
 async fn get_metadata_raw(
     root: State<PathBuf>,
     id: extract::Path<String>,
-) -> Result<String, OutpackError> {
-    metadata::get_metadata_text(&root, &id).map_err(OutpackError::from)
+) -> Result<impl IntoResponse, OutpackError> {
+    let text = metadata::get_metadata_text(&root, &id)?;
+    let headers = packet_hash_headers(&root, &id)?;
+    Ok((headers, text))
 }
 
 async fn get_file(
-    root: State<PathBuf>,
+    files: State<Arc<dyn FileStore>>,
     hash: extract::Path<String>,
 ) -> Result<OutpackFile, OutpackError> {
-    let path = store::file_path(&root, &hash);
-    OutpackFile::open(hash.to_owned(), path?)
-        .await
-        .map_err(OutpackError::from)
+    let file = files.get(&hash).await?;
+    Ok(OutpackFile::new(hash.0, file))
+}
+
+/// Download a packet's file by its path, sparing clients from looking up its hash first.
+async fn get_packet_file(
+    root: State<PathBuf>,
+    files: State<Arc<dyn FileStore>>,
+    extract::Path((id, path)): extract::Path<(String, String)>,
+) -> Result<OutpackFile, OutpackError> {
+    let hash = metadata::get_packet_file_hash(&root, &id, &path)?;
+    let file = files.get(&hash).await?;
+    Ok(OutpackFile::new(hash, file))
 }
 
 #[derive(Deserialize)]
 struct Algorithm {
     alg: Option<String>,
+    query: Option<String>,
 }
 
-async fn get_checksum(root: State<PathBuf>, query: Query<Algorithm>) -> OutpackResult<String> {
-    metadata::get_ids_digest(&root, query.0.alg)
+async fn get_checksum(
+    root: State<PathBuf>,
+    ids: State<IdIndex>,
+    query: Query<Algorithm>,
+) -> OutpackResult<String> {
+    metadata::get_ids_digest(&root, &ids, query.0.alg, query.0.query.as_deref())
         .map_err(OutpackError::from)
         .map(OutpackSuccess::from)
 }
 
+fn default_strict() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct Strictness {
+    #[serde(default = "default_strict")]
+    strict: bool,
+}
+
+/// By default an invalid id fails the whole request. With `strict=false`, invalid ids are
+/// reported in the response's `errors` instead, and the valid ones are still checked.
 async fn get_missing_packets(
-    root: State<PathBuf>,
+    index: State<IdIndex>,
+    strictness: Result<Query<Strictness>, QueryRejection>,
     ids: Result<Json<Ids>, JsonRejection>,
+) -> OutpackResult<Vec<String>> {
+    let strictness = strictness.map_err(|_| {
+        coded_error(
+            ErrorKind::InvalidInput,
+            ErrorCode::InvalidRequest,
+            "invalid strict parameter, expected 'true' or 'false'",
+        )
+    })?;
+    let ids = ids?;
+    if strictness.strict {
+        return metadata::get_missing_ids(&index, &ids.ids, ids.unpacked)
+            .map_err(OutpackError::from)
+            .map(OutpackSuccess::from);
+    }
+    let (valid, errors) = metadata::partition_valid_ids(&ids.ids);
+    let missing = metadata::get_missing_ids(&index, &valid, ids.unpacked)?;
+    let errors = errors.into_iter().map(OutpackError::from).collect();
+    Ok(OutpackSuccess::with_errors(missing, errors))
+}
+
+async fn get_unknown_packets(
+    index: State<IdIndex>,
+    ids: Result<Json<KnownIds>, JsonRejection>,
 ) -> OutpackResult<Vec<String>> {
     let ids = ids?;
-    metadata::get_missing_ids(&root, &ids.ids, ids.unpacked)
+    metadata::get_unknown_ids(&index, &ids.known_ids, ids.unpacked)
         .map_err(OutpackError::from)
         .map(OutpackSuccess::from)
 }
 
-async fn get_missing_files(
+async fn get_missing_packet_files(
     root: State<PathBuf>,
+    ids: Result<Json<PacketIds>, JsonRejection>,
+) -> OutpackResult<metadata::MissingPacketFiles> {
+    let ids = ids?;
+    metadata::get_missing_packet_files(&root, &ids.ids)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+/// List the hash of every file in the store, as JSON lines.
+async fn list_files(
+    root: State<PathBuf>,
+    layout: State<FileStoreLayout>,
+) -> Result<Response, OutpackError> {
+    let hashes = store::list_files(&root, *layout)?.map(Ok::<_, io::Error>);
+    let headers = [(CONTENT_TYPE, JSONL_CONTENT_TYPE)];
+    Ok((headers, stream_json_lines("file hashes", hashes)).into_response())
+}
+
+async fn get_missing_files(
+    files: State<Arc<dyn FileStore>>,
     hashes: Result<Json<Hashes>, JsonRejection>,
 ) -> OutpackResult<Vec<String>> {
     let hashes = hashes?;
-    store::get_missing_files(&root, &hashes.hashes)
+    files
+        .missing(&hashes.hashes)
+        .await
         .map_err(OutpackError::from)
         .map(OutpackSuccess::from)
 }
 
-async fn add_file(
+async fn get_present_files(
+    files: State<Arc<dyn FileStore>>,
+    hashes: Result<Json<Hashes>, JsonRejection>,
+) -> OutpackResult<Vec<String>> {
+    let hashes = hashes?;
+    files
+        .present(&hashes.hashes)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ArchiveRequest {
+    Files { files: Vec<ArchiveFile> },
+    Hashes { hashes: Vec<String> },
+}
+
+#[derive(Deserialize)]
+struct ArchiveFile {
+    hash: String,
+    path: String,
+}
+
+/// Check that every requested file can be added to an archive, returning each entry's name and
+/// the path of its contents in the store.
+///
+/// This is all done before anything is sent, as errors can't be reported once the archive has
+/// started streaming.
+fn prepare_archive(
+    root: &Path,
+    layout: FileStoreLayout,
+    files: Vec<(String, String)>,
+) -> Result<Vec<(String, PathBuf)>, OutpackError> {
+    let mut names = HashSet::new();
+    let mut missing = Vec::new();
+    let mut entries = Vec::new();
+    for (hash, name) in files {
+        hash.parse::<hash::Hash>()?;
+        zip::validate_name(&name)?;
+        if !names.insert(name.clone()) {
+            return Err(coded_error(
+                ErrorKind::InvalidInput,
+                ErrorCode::InvalidArchive,
+                format!("Duplicate path in archive '{}'", name),
+            )
+            .into());
+        }
+        let path = store::file_path(root, layout, &hash)?;
+        match std::fs::metadata(&path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => missing.push(hash),
+            Err(e) => return Err(e.into()),
+        }
+        entries.push((name, path));
+    }
+
+    if !missing.is_empty() {
+        return Err(coded_error(
+            ErrorKind::NotFound,
+            ErrorCode::FileNotFound,
+            format!("Files missing from the store: {}", missing.join(", ")),
+        )
+        .into());
+    }
+
+    Ok(entries)
+}
+
+async fn get_files_archive(
     root: State<PathBuf>,
+    layout: State<FileStoreLayout>,
+    request: Result<Json<ArchiveRequest>, JsonRejection>,
+) -> Result<Response, OutpackError> {
+    let files = match request?.0 {
+        ArchiveRequest::Files { files } => files.into_iter().map(|f| (f.hash, f.path)).collect(),
+        // Without explicit paths, entries are laid out the same way as in the store.
+        ArchiveRequest::Hashes { hashes } => hashes
+            .into_iter()
+            .map(|h| {
+                let name = h.replacen(':', "/", 1);
+                (h, name)
+            })
+            .collect(),
+    };
+    let entries = prepare_archive(&root, *layout, files)?;
+
+    // The archive is written into one end of a pipe while the other is streamed to the client, so
+    // at most one buffer's worth is held in memory.
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        if let Err(e) = zip::write_archive(writer, entries).await {
+            tracing::error!("error while writing archive: {}", e);
+        }
+    });
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/zip")
+        .header(CONTENT_DISPOSITION, "attachment; filename=\"files.zip\"")
+        .body(axum::body::Body::from_stream(ReaderStream::new(reader)))
+        .unwrap())
+}
+
+async fn get_duplicate_files(root: State<PathBuf>) -> OutpackResult<Vec<metadata::DuplicateFile>> {
+    metadata::get_duplicate_files(&root)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn add_file(
+    files: State<Arc<dyn FileStore>>,
+    headers: HeaderMap,
     hash: extract::Path<String>,
     file: Upload,
 ) -> Result<OutpackSuccess<()>, OutpackError> {
-    tokio::task::spawn_blocking(move || {
-        store::put_file(&root, file, &hash)
-            .map_err(OutpackError::from)
-            .map(OutpackSuccess::from)
-    })
-    .await
-    .unwrap()
+    if only_if_absent(&headers)
+        && !files
+            .present(std::slice::from_ref(&hash.0))
+            .await?
+            .is_empty()
+    {
+        return Err(already_exists(format!("file '{}'", hash.0)));
+    }
+    files
+        .put(&hash, file)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+/// Hash the request body without storing it, so that clients can check that the server computes
+/// the hash they expect. Uses the repository's hash algorithm unless another is given.
+async fn hash_content(
+    root: State<PathBuf>,
+    workers: Extension<WorkerPool>,
+    query: Query<Algorithm>,
+    file: Upload,
+) -> OutpackResult<String> {
+    let algorithm = match &query.alg {
+        Some(name) => name.parse::<hash::HashAlgorithm>()?,
+        None => config::read_config(&root)?.core.hash_algorithm,
+    };
+    let hash = workers.run(move || file.hash(algorithm)).await?;
+    Ok(OutpackSuccess::from(hash.to_string()))
 }
 
+/// Partial uploads which haven't received any data for this long are deleted whenever a new upload
+/// is started. Temporary files this old are deleted when the server starts.
+const STALE_UPLOAD_AGE: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// The directory in which uploads are stored while in progress.
+fn temp_dir(root: &Path, options: &ServerOptions) -> PathBuf {
+    match &options.temp_dir {
+        Some(path) => root.join(path),
+        None => store::default_temp_dir(root),
+    }
+}
+
+/// Delete temporary files left behind by uploads that never finished, e.g. because the server
+/// stopped. Failing to do so isn't fatal, so any error is only logged.
+fn remove_stale_temp_files(temp_dir: &Path) {
+    match upload::remove_stale_temp_files(temp_dir, STALE_UPLOAD_AGE) {
+        Ok(0) => {}
+        Ok(n) => tracing::info!(
+            "removed {} stale temporary files from {}",
+            n,
+            temp_dir.display()
+        ),
+        Err(e) => tracing::warn!(
+            "could not remove stale temporary files from {}: {}",
+            temp_dir.display(),
+            e
+        ),
+    }
+}
+
+async fn start_upload(
+    uploads: Extension<UploadConfig>,
+    hash: extract::Path<String>,
+) -> OutpackResult<PartialUpload> {
+    // Fail early on a malformed hash, rather than after the whole file has been sent.
+    hash.parse::<hash::Hash>()?;
+    uploads.remove_stale_partials(STALE_UPLOAD_AGE).await?;
+    uploads
+        .start_partial()
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn get_upload_status(
+    uploads: Extension<UploadConfig>,
+    extract::Path((_hash, id)): extract::Path<(String, String)>,
+) -> OutpackResult<PartialUpload> {
+    uploads
+        .partial_status(&id)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[derive(Deserialize)]
+struct ChunkOffset {
+    offset: u64,
+}
+
+async fn append_upload(
+    uploads: Extension<UploadConfig>,
+    extract::Path((_hash, id)): extract::Path<(String, String)>,
+    query: Query<ChunkOffset>,
+    body: axum::body::Body,
+) -> OutpackResult<PartialUpload> {
+    uploads
+        .append_partial(&id, query.offset, body)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn finish_upload(
+    files: State<Arc<dyn FileStore>>,
+    uploads: Extension<UploadConfig>,
+    extract::Path((hash, id)): extract::Path<(String, String)>,
+) -> OutpackResult<()> {
+    let file = uploads.finish_partial(&id)?;
+    files
+        .put(&hash, file)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+async fn abort_upload(
+    uploads: Extension<UploadConfig>,
+    extract::Path((_hash, id)): extract::Path<(String, String)>,
+) -> OutpackResult<()> {
+    uploads
+        .abort_partial(&id)
+        .await
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[derive(Deserialize)]
+struct VerifyOptions {
+    #[serde(default)]
+    hashes: bool,
+}
+
+async fn verify_repository(
+    root: State<PathBuf>,
+    workers: Extension<WorkerPool>,
+    query: Query<VerifyOptions>,
+) -> OutpackResult<verify::VerifyReport> {
+    workers
+        .run(move || {
+            verify::verify_repository(&root, query.hashes)
+                .map_err(OutpackError::from)
+                .map(OutpackSuccess::from)
+        })
+        .await
+}
+
+async fn prune_locations(
+    root: State<PathBuf>,
+    ids: State<IdIndex>,
+) -> OutpackResult<location::PruneReport> {
+    let report = location::prune(&root)?;
+    ids.invalidate();
+    Ok(OutpackSuccess::from(report))
+}
+
+#[derive(Deserialize)]
+struct DryRun {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// The result of checking, but not importing, a packet.
+#[derive(Serialize, Deserialize)]
+struct PacketCheck {
+    id: String,
+}
+
+/// Import a packet, or with `?dry_run=true` only check that it could be imported. The data of a
+/// dry run's response is the packet's id, rather than `null`.
 async fn add_packet(
     root: State<PathBuf>,
+    ids: State<IdIndex>,
+    options: State<ServerOptions>,
+    headers: HeaderMap,
     hash: extract::Path<String>,
+    query: Result<Query<DryRun>, QueryRejection>,
     packet: String,
-) -> Result<OutpackSuccess<()>, OutpackError> {
+) -> OutpackResult<Option<PacketCheck>> {
+    let query = query.map_err(|_| {
+        coded_error(
+            ErrorKind::InvalidInput,
+            ErrorCode::InvalidRequest,
+            "invalid dry_run parameter",
+        )
+    })?;
     let hash = hash.parse::<hash::Hash>().map_err(OutpackError::from)?;
-    metadata::add_packet(&root, &packet, &hash)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+    if options.validate_metadata {
+        schema::validate_metadata(&packet).map_err(OutpackError::from)?;
+    }
+    if only_if_absent(&headers) {
+        let id = metadata::packet_id(&packet)?;
+        if metadata::packet_exists(&root, &id)? {
+            return Err(already_exists(format!("packet '{}'", id)));
+        }
+    }
+    if query.dry_run {
+        let id = metadata::check_packet(&root, &packet, &hash)?;
+        return Ok(OutpackSuccess::from(Some(PacketCheck { id })));
+    }
+    metadata::add_packet(&root, &packet, &hash, config::LOCAL_LOCATION)?;
+    ids.add(&metadata::packet_id(&packet)?, true);
+    Ok(OutpackSuccess::from(None))
 }
 
-async fn git_fetch(root: State<PathBuf>) -> Result<OutpackSuccess<()>, OutpackError> {
+async fn git_fetch(
+    root: State<PathBuf>,
+    State(lock): State<GitLock>,
+    State(metrics): State<GitMetrics>,
+) -> Result<OutpackSuccess<()>, OutpackError> {
+    let _guard = lock.0.lock().await;
     tokio::task::spawn_blocking(move || {
-        git::git_fetch(&root)
+        metrics
+            .fetch(|| git::git_fetch(&root))
             .map_err(OutpackError::from)
             .map(OutpackSuccess::from)
     })
@@ -173,13 +1217,100 @@ async fn git_fetch(root: State<PathBuf>) -> Result<OutpackSuccess<()>, OutpackEr
     .unwrap()
 }
 
+async fn git_status(
+    root: State<PathBuf>,
+    State(metrics): State<GitMetrics>,
+) -> OutpackResult<git::StatusResponse> {
+    tokio::task::spawn_blocking(move || {
+        let result = git::git_status(&root);
+        metrics.record("status", &result);
+        result.map_err(OutpackError::from).map(OutpackSuccess::from)
+    })
+    .await
+    .unwrap()
+}
+
+/// Guess the content type of a file from its extension. Anything unrecognised is served as
+/// binary data.
+fn content_type_for(path: &str) -> mime::Mime {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("txt" | "r" | "py" | "md" | "yml" | "yaml" | "sql") => mime::TEXT_PLAIN_UTF_8,
+        Some("csv") => mime::TEXT_CSV_UTF_8,
+        Some("html" | "htm") => mime::TEXT_HTML_UTF_8,
+        Some("css") => mime::TEXT_CSS_UTF_8,
+        Some("js") => mime::APPLICATION_JAVASCRIPT_UTF_8,
+        Some("json") => mime::APPLICATION_JSON,
+        Some("xml") => mime::TEXT_XML,
+        Some("pdf") => mime::APPLICATION_PDF,
+        Some("png") => mime::IMAGE_PNG,
+        Some("jpg" | "jpeg") => mime::IMAGE_JPEG,
+        Some("gif") => mime::IMAGE_GIF,
+        Some("svg") => mime::IMAGE_SVG,
+        _ => mime::APPLICATION_OCTET_STREAM,
+    }
+}
+
+/// Download a file as it is in a git revision, such as a branch or commit.
+async fn git_file(
+    root: State<PathBuf>,
+    State(metrics): State<GitMetrics>,
+    extract::Path((revision, path)): extract::Path<(String, String)>,
+) -> Result<Response, OutpackError> {
+    let content_type = content_type_for(&path);
+    let content = tokio::task::spawn_blocking(move || git::git_read_file(&root, &revision, &path))
+        .await
+        .unwrap();
+    metrics.record("file", &content);
+    let content = content?;
+    Ok(([(CONTENT_TYPE, content_type.as_ref())], content).into_response())
+}
+
+#[derive(Deserialize, Default)]
+struct PullRequest {
+    branch: Option<String>,
+}
+
+async fn git_pull(
+    root: State<PathBuf>,
+    State(lock): State<GitLock>,
+    State(metrics): State<GitMetrics>,
+    body: axum::body::Bytes,
+) -> OutpackResult<git::PullResponse> {
+    // Like `/git/fetch`, this can be called with an empty body, to pull the current branch.
+    let request: PullRequest = if body.is_empty() {
+        PullRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| {
+            coded_error(
+                ErrorKind::InvalidInput,
+                ErrorCode::InvalidRequest,
+                e.to_string(),
+            )
+        })?
+    };
+    let _guard = lock.0.lock().await;
+    tokio::task::spawn_blocking(move || {
+        let result = git::git_pull(&root, request.branch.as_deref());
+        metrics.record("pull", &result);
+        result.map_err(OutpackError::from).map(OutpackSuccess::from)
+    })
+    .await
+    .unwrap()
+}
+
 async fn git_list_branches(
     root: State<PathBuf>,
+    State(metrics): State<GitMetrics>,
 ) -> Result<OutpackSuccess<git::BranchResponse>, OutpackError> {
     tokio::task::spawn_blocking(move || {
-        git::git_list_branches(&root)
-            .map_err(OutpackError::from)
-            .map(OutpackSuccess::from)
+        let default_branch = config::read_config(&root)?.core.default_branch;
+        let result = git::git_list_branches(&root, default_branch);
+        metrics.record("branches", &result);
+        result.map_err(OutpackError::from).map(OutpackSuccess::from)
     })
     .await
     .unwrap()
@@ -191,12 +1322,31 @@ struct Ids {
     unpacked: bool,
 }
 
+#[derive(Serialize, Deserialize)]
+struct KnownIds {
+    known_ids: Vec<String>,
+    #[serde(default)]
+    unpacked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PacketIds {
+    ids: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Hashes {
     hashes: Vec<String>,
 }
 
-pub fn check_config(config: &config::Config) -> anyhow::Result<()> {
+/// Check that the repository's configuration can be served.
+///
+/// Repositories must use sha256, unless their algorithm is explicitly listed in
+/// `allowed_hash_algorithms`.
+pub fn check_config(
+    config: &config::Config,
+    allowed_hash_algorithms: &[hash::HashAlgorithm],
+) -> anyhow::Result<()> {
     // These two are probably always constraints for using the server:
     if !config.core.use_file_store {
         bail!("Outpack must be configured to use a file store");
@@ -205,22 +1355,24 @@ pub fn check_config(config: &config::Config) -> anyhow::Result<()> {
         bail!("Outpack must be configured to require a complete tree");
     }
     // These two we can relax over time:
-    if config.core.hash_algorithm != hash::HashAlgorithm::Sha256 {
+    if config.core.hash_algorithm != hash::HashAlgorithm::Sha256
+        && !allowed_hash_algorithms.contains(&config.core.hash_algorithm)
+    {
         bail!(
             "Outpack must be configured to use hash algorithm 'sha256', but you are using '{}'",
             config.core.hash_algorithm
         );
     }
-    if config.core.path_archive.is_some() {
+    if let Some(path_archive) = &config.core.path_archive {
         bail!(
             "Outpack must be configured to *not* use an archive, but your path_archive is '{}'",
-            config.core.path_archive.as_ref().unwrap()
+            path_archive
         );
     }
     Ok(())
 }
 
-pub fn preflight(root: &Path) -> anyhow::Result<()> {
+pub fn preflight(root: &Path, options: &ServerOptions) -> anyhow::Result<()> {
     if !root.join(".outpack").exists() {
         bail!("Outpack root not found at '{}'", root.display());
     }
@@ -228,7 +1380,21 @@ pub fn preflight(root: &Path) -> anyhow::Result<()> {
     let config = config::read_config(root)
         .with_context(|| format!("Failed to read outpack config from '{}'", root.display()))?;
 
-    check_config(&config)?;
+    check_config(&config, &options.allowed_hash_algorithms)?;
+
+    if options.check {
+        match verify::check_repository(root) {
+            Ok(problems) => {
+                for problem in &problems {
+                    tracing::warn!("{}", problem);
+                }
+                if problems.is_empty() {
+                    tracing::info!("No problems found in repository");
+                }
+            }
+            Err(e) => tracing::warn!("Could not check repository: {}", e),
+        }
+    }
     Ok(())
 }
 
@@ -244,57 +1410,253 @@ fn make_request_span(request: &axum::extract::Request) -> tracing::span::Span {
     )
 }
 
-pub fn api(root: &Path) -> anyhow::Result<Router> {
-    use axum::routing::{get, post};
+#[derive(Deserialize)]
+struct MetricsQuery {
+    collectors: Option<String>,
+}
+
+/// Render the metrics, or with `?collectors=http` only some groups of them, as a comma-separated
+/// list.
+fn get_metrics(
+    registries: &MetricsRegistries,
+    query: Query<MetricsQuery>,
+) -> Result<impl IntoResponse, OutpackError> {
+    let groups = match &query.collectors {
+        None => metrics::MetricsGroup::ALL.to_vec(),
+        Some(collectors) => collectors
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| coded_error(ErrorKind::InvalidInput, ErrorCode::InvalidRequest, e))?,
+    };
+    Ok(metrics::render(registries, &groups))
+}
+
+pub fn api(root: &Path, options: ServerOptions) -> anyhow::Result<Router> {
+    let registries = MetricsRegistries::new();
+    let registry = &registries.http;
+    register_process_metrics(registry).expect("process metrics registered");
+    let namespace = options
+        .metrics_namespace
+        .as_deref()
+        .unwrap_or(metrics::DEFAULT_NAMESPACE);
+    let http_namespace = options
+        .http_metrics_namespace
+        .as_deref()
+        .unwrap_or(metrics::DEFAULT_HTTP_NAMESPACE);
+    register_build_info_metrics(registry, namespace).expect("build info metrics registered");
+    CacheMetrics::register(registry, namespace).expect("cache metrics registered");
+    let git_metrics = GitMetrics::register(registry, namespace).expect("git metrics registered");
+    let metrics_refresh_interval = options.metrics_refresh_interval.map(Duration::from_secs);
+    let ids = IdIndex::new(root);
+    RepositoryMetrics::register(
+        &registries.repository,
+        root,
+        ids.clone(),
+        namespace,
+        metrics_refresh_interval,
+    )
+    .expect("repository metrics registered");
+    let duration_buckets = match options.request_duration_buckets.as_slice() {
+        [] => metrics::DEFAULT_DURATION_BUCKETS,
+        buckets => buckets,
+    };
+    let http_metrics = HttpMetrics::register(registry, http_namespace, duration_buckets)
+        .context("Invalid request duration buckets")?;
+
+    preflight(root, &options)?;
+    let config = config::read_config(root)?;
 
-    let registry = prometheus::Registry::new();
-    register_process_metrics(&registry).expect("process metrics registered");
-    register_build_info_metrics(&registry).expect("build info metrics registered");
-    RepositoryMetrics::register(&registry, root).expect("repository metrics registered");
-    let http_metrics = HttpMetrics::register(&registry).expect("http metrics registered");
+    let temp_dir = temp_dir(root, &options);
+    std::fs::create_dir_all(&temp_dir)
+        .with_context(|| format!("Could not create directory {}", temp_dir.display()))?;
+    remove_stale_temp_files(&temp_dir);
 
-    preflight(root)?;
+    let workers = WorkerPool::new(
+        options
+            .hashing_threads
+            .map_or_else(WorkerPool::default_size, |n| n as usize),
+    );
 
-    let routes = Router::new()
+    let request_timeout = options
+        .request_timeout
+        .map_or(DEFAULT_REQUEST_TIMEOUT, Duration::from_secs);
+    let max_body_size = options.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE);
+    let write_limit = axum::middleware::from_fn_with_state(
+        options.write_rate_limit.map(RateLimiter::new),
+        rate_limit::limit_writes,
+    );
+
+    let mut state = AppState {
+        root: root.to_owned(),
+        layout: config.core.file_store_layout,
+        files: file_store::open(root, &config.core, temp_dir.clone(), workers.clone()),
+        ids,
+        options: options.clone(),
+        git_lock: GitLock::default(),
+        git_metrics,
+        routes: Arc::default(),
+    };
+
+    // Transferring files can legitimately take a long time on a slow connection, so these routes
+    // are kept separate from the rest and don't get a time limit.
+    let (transfers, mut listing) = RouteTable::default()
+        .route(
+            "/file/:hash",
+            get(get_file).post(add_file.layer(write_limit.clone())),
+        )
+        .route("/metadata/:id/file/*path", get(get_packet_file))
+        .route("/hash", post(hash_content))
+        .route("/file/:hash/upload", post(start_upload))
+        .route(
+            "/file/:hash/upload/:id",
+            get(get_upload_status)
+                .patch(append_upload)
+                .post(finish_upload)
+                .delete(abort_upload),
+        )
+        .finish();
+
+    let (routes, more_routes) = RouteTable::default()
         .route("/", get(index))
+        .route("/favicon.ico", get(favicon))
+        .route("/locations", get(list_locations))
         .route("/metadata/list", get(list_location_metadata))
+        .route(
+            "/metadata/list/:location",
+            get(list_named_location_metadata),
+        )
+        .route("/metadata/all", get(get_all_metadata))
+        .route("/metadata/:id", get(get_metadata))
         .route("/metadata/:id/json", get(get_metadata_by_id))
         .route("/metadata/:id/text", get(get_metadata_raw))
+        .route("/metadata/:id/files", get(get_packet_files))
         .route("/checksum", get(get_checksum))
         .route("/packets/missing", post(get_missing_packets))
+        .route("/packets/missing-files", post(get_missing_packet_files))
+        .route("/diff", post(get_unknown_packets))
+        .route("/files", get(list_files))
         .route("/files/missing", post(get_missing_files))
+        .route("/files/present", post(get_present_files))
+        .route("/files/archive", post(get_files_archive))
         .route("/packit/metadata", get(get_metadata_since))
-        .route("/file/:hash", get(get_file).post(add_file))
-        .route("/packet/:hash", post(add_packet))
-        .route("/git/fetch", post(git_fetch))
+        .route("/packits", get(search_packets))
+        .route("/stats/duplicate-files", get(get_duplicate_files))
+        .route("/packet/:hash", post(add_packet.layer(write_limit.clone())))
+        .route("/packet/:id/exists", get(packet_exists))
+        .route("/packet/:id/dependencies", get(get_packet_dependencies))
+        .route(
+            "/packet/:id/touch",
+            post(touch_packet.layer(write_limit.clone())),
+        )
+        .route("/admin/verify", get(verify_repository))
+        .route(
+            "/admin/prune-locations",
+            post(prune_locations.layer(write_limit.clone())),
+        )
+        .route("/git/fetch", post(git_fetch.layer(write_limit.clone())))
+        .route("/git/pull", post(git_pull.layer(write_limit)))
         .route("/git/branches", get(git_list_branches))
-        .route("/metrics", get(|| async move { metrics::render(registry) }))
+        .route("/git/status", get(git_status))
+        .route("/git/file/:revision/*path", get(git_file))
+        .route(
+            "/metrics",
+            get(|query| async move { get_metrics(&registries, query) }),
+        )
+        .finish();
+    listing.extend(more_routes);
+    listing.sort_by(|a, b| a.path.cmp(&b.path));
+    state.routes = Arc::new(listing);
+
+    let routes = routes
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(request_timed_out))
+                .layer(TimeoutLayer::new(request_timeout)),
+        )
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .merge(transfers)
         .fallback(not_found)
-        .with_state(root.to_owned());
+        .with_state(state);
 
-    Ok(routes
-        .layer(UploadLayer::new(root.join(".outpack").join("files")))
+    let routes = routes
+        .layer(UploadLayer::new(temp_dir))
+        .layer(Extension(workers))
         .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
         .layer(PropagateRequestIdLayer::x_request_id())
-        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
-        .layer(CatchPanicLayer::custom(internal_error))
-        .layer(http_metrics.layer()))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+    let routes = if options.disable_catch_panic {
+        routes
+    } else {
+        routes.layer(CatchPanicLayer::custom(internal_error))
+    };
+
+    let routes = routes
+        .layer(axum::middleware::from_fn(json_format))
+        .layer(http_metrics.layer());
+
+    // Requests outside of the base path don't reach any of the layers above, so aren't counted
+    // in the HTTP metrics.
+    let routes = match &options.base_path {
+        Some(base_path) => Router::new().nest(base_path, routes).fallback(not_found),
+        None => routes,
+    };
+
+    // Layers added to a router only run once a route has been matched, so trailing slashes must be
+    // trimmed by a service wrapping the whole router instead.
+    Ok(Router::new().fallback_service(NormalizePathLayer::trim_trailing_slash().layer(routes)))
+}
+
+/// The limits on connections to the server, given its options.
+fn connection_settings(options: &ServerOptions) -> ConnectionSettings {
+    let defaults = ConnectionSettings::default();
+    ConnectionSettings {
+        idle_timeout: match options.idle_timeout {
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => defaults.idle_timeout,
+        },
+        header_read_timeout: options
+            .header_read_timeout
+            .map_or(defaults.header_read_timeout, Duration::from_secs),
+        max_connections: options
+            .max_connections
+            .map_or(defaults.max_connections, |n| n as usize),
+    }
 }
 
-pub fn serve(root: &Path, addr: &SocketAddr) -> anyhow::Result<()> {
+pub fn serve(root: &Path, addr: &SocketAddr, options: ServerOptions) -> anyhow::Result<()> {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::TRACE)
         .init();
 
-    let app = api(root)?;
+    let connections = connection_settings(&options);
+    let cleanup_interval = options
+        .temp_cleanup_interval
+        .map(std::time::Duration::from_secs);
+    let temp_dir = temp_dir(root, &options);
+    let app = api(root, options)?;
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()?
         .block_on(async {
+            if let Some(interval) = cleanup_interval {
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(interval).await;
+                        let temp_dir = temp_dir.clone();
+                        let _ =
+                            tokio::task::spawn_blocking(move || remove_stale_temp_files(&temp_dir))
+                                .await;
+                    }
+                });
+            }
             let listener = tokio::net::TcpListener::bind(addr).await?;
             tracing::info!("listening on {}", listener.local_addr().unwrap());
-            axum::serve(listener, app).await?;
+            tracing::info!("connections: {}", connections);
+            server::serve(listener, app, connections).await?;
             Ok(())
         })
 }
@@ -315,36 +1677,130 @@ mod tests {
             path_archive,
             use_file_store,
             require_complete_tree,
+            compress_metadata: false,
+            default_branch: None,
+            file_store_layout: Default::default(),
+            metadata_backend: Default::default(),
+            file_store: Default::default(),
         };
         config::Config { location, core }
     }
 
+    #[test]
+    fn connection_settings_have_defaults() {
+        assert_eq!(
+            connection_settings(&ServerOptions::default()),
+            ConnectionSettings::default()
+        );
+        let options = ServerOptions {
+            idle_timeout: Some(0),
+            header_read_timeout: Some(5),
+            max_connections: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            connection_settings(&options),
+            ConnectionSettings {
+                idle_timeout: None,
+                header_read_timeout: Duration::from_secs(5),
+                max_connections: 10,
+            }
+        );
+        let options = ServerOptions {
+            idle_timeout: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            connection_settings(&options).idle_timeout,
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn can_parse_base_path() {
+        assert_eq!(parse_base_path("/outpack").unwrap(), "/outpack");
+        assert_eq!(parse_base_path("/outpack/").unwrap(), "/outpack");
+        assert_eq!(parse_base_path("/a/b").unwrap(), "/a/b");
+        assert!(parse_base_path("outpack").is_err());
+        assert!(parse_base_path("/").is_err());
+        assert!(parse_base_path("").is_err());
+    }
+
+    #[test]
+    fn can_negotiate_metadata_format() {
+        let accept = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, value.parse().unwrap());
+            prefers_text(&headers)
+        };
+        assert!(!prefers_text(&HeaderMap::new()));
+        assert!(accept("text/plain"));
+        assert!(!accept("application/json"));
+        assert!(!accept("*/*"));
+        assert!(!accept("text/plain, application/json"));
+        assert!(accept("application/json;q=0.5, text/plain"));
+        assert!(!accept(
+            "text/plain; charset=utf-8; q=0.2, application/json; q=0.9"
+        ));
+    }
+
+    #[test]
+    fn content_type_is_guessed_from_extension() {
+        assert_eq!(content_type_for("data.csv"), mime::TEXT_CSV_UTF_8);
+        assert_eq!(content_type_for("src/orderly.R"), mime::TEXT_PLAIN_UTF_8);
+        assert_eq!(content_type_for("figures/plot.PNG"), mime::IMAGE_PNG);
+        assert_eq!(content_type_for("data.rds"), mime::APPLICATION_OCTET_STREAM);
+        assert_eq!(content_type_for("README"), mime::APPLICATION_OCTET_STREAM);
+    }
+
     #[test]
     fn can_validate_config() {
-        let res = check_config(&make_config(hash::HashAlgorithm::Sha1, None, true, true));
+        let res = check_config(
+            &make_config(hash::HashAlgorithm::Sha1, None, true, true),
+            &[],
+        );
         assert_eq!(
             res.unwrap_err().to_string(),
             "Outpack must be configured to use hash algorithm 'sha256', but you are using 'sha1'"
         );
 
-        let res = check_config(&make_config(hash::HashAlgorithm::Sha256, None, false, true));
+        let res = check_config(
+            &make_config(hash::HashAlgorithm::Sha256, None, false, true),
+            &[],
+        );
         assert_eq!(
             res.unwrap_err().to_string(),
             "Outpack must be configured to use a file store"
         );
 
-        let res = check_config(&make_config(hash::HashAlgorithm::Sha256, None, true, false));
+        let res = check_config(
+            &make_config(hash::HashAlgorithm::Sha256, None, true, false),
+            &[],
+        );
         assert_eq!(
             res.unwrap_err().to_string(),
             "Outpack must be configured to require a complete tree"
         );
 
-        let res = check_config(&make_config(
-            hash::HashAlgorithm::Sha256,
-            Some(String::from("archive")),
-            true,
-            true,
-        ));
+        let res = check_config(
+            &make_config(
+                hash::HashAlgorithm::Sha256,
+                Some(String::from("archive")),
+                true,
+                true,
+            ),
+            &[],
+        );
         assert_eq!(res.unwrap_err().to_string(), "Outpack must be configured to *not* use an archive, but your path_archive is 'archive'");
     }
+
+    #[test]
+    fn can_allow_other_hash_algorithms() {
+        let config = make_config(hash::HashAlgorithm::Sha1, None, true, true);
+        assert!(check_config(&config, &[hash::HashAlgorithm::Sha1]).is_ok());
+        assert!(check_config(&config, &[hash::HashAlgorithm::Md5]).is_err());
+
+        let config = make_config(hash::HashAlgorithm::Sha256, None, true, true);
+        assert!(check_config(&config, &[hash::HashAlgorithm::Sha1]).is_ok());
+    }
 }
@@ -1,3 +1,4 @@
+use outpack::api::ServerOptions;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
@@ -24,6 +25,12 @@ pub enum Command {
         /// Require a complete tree.
         #[arg(long)]
         require_complete_tree: bool,
+
+        /// The git branch reported as the default by the server, rather than that of the remote.
+        /// If the path is a git repository without any commits, its first commit will also be
+        /// made on this branch.
+        #[arg(long, value_name = "BRANCH")]
+        default_branch: Option<String>,
     },
 
     /// Search for a packet in a repository
@@ -33,8 +40,40 @@ pub enum Command {
         query: String,
     },
 
+    /// Import a packet's metadata into a repository
+    ///
+    /// All the files referenced by the packet must already be present in the repository.
+    Import {
+        #[arg(short, long)]
+        root: PathBuf,
+        metadata_file: PathBuf,
+        /// The location the packet was obtained from, which must be configured in the
+        /// repository. Defaults to the repository's own location.
+        #[arg(long, default_value = outpack::config::LOCAL_LOCATION)]
+        location: String,
+    },
+
+    /// Check the integrity of a repository
+    ///
+    /// Every file in the store is re-hashed, and every file and dependency referenced by a packet
+    /// must be present. Exits with a non-zero status if any problem is found.
+    Verify {
+        #[arg(short, long)]
+        root: PathBuf,
+
+        /// Before verifying, remove location entries for packets which have no metadata.
+        #[arg(long)]
+        fix: bool,
+    },
+
     /// Parse an outpack query, without evaluating it
-    Parse { query: String },
+    Parse {
+        query: String,
+
+        /// Format used to print the parsed query.
+        #[arg(long, value_enum, default_value_t = ParseFormat::Human)]
+        format: ParseFormat,
+    },
 
     /// Start the outpack API server
     StartServer {
@@ -43,5 +82,18 @@ pub enum Command {
 
         #[arg(long, default_value = "0.0.0.0:8000")]
         listen: SocketAddr,
+
+        #[command(flatten)]
+        options: Box<ServerOptions>,
     },
 }
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ParseFormat {
+    /// Debug representation of the query, intended for humans.
+    Human,
+    /// Stable JSON representation of the query, intended for tooling.
+    Json,
+    /// The query in a canonical form, which can be parsed again.
+    Query,
+}
@@ -1,9 +1,14 @@
 mod args;
-use args::{Args, Command};
+mod verify;
+use args::{Args, Command, ParseFormat};
 
+use anyhow::Context;
 use clap::Parser;
+use outpack::config::read_config;
+use outpack::hash::hash_data;
 use outpack::init::outpack_init;
-use outpack::query::{parse_query, run_query};
+use outpack::metadata::{add_packet, Packet};
+use outpack::query::{parse_query, query_to_json, run_query};
 
 fn main() -> anyhow::Result<()> {
     let cli = Args::parse();
@@ -13,8 +18,15 @@ fn main() -> anyhow::Result<()> {
             path_archive,
             use_file_store,
             require_complete_tree,
+            default_branch,
         } => {
-            outpack_init(&path, path_archive, use_file_store, require_complete_tree)?;
+            outpack_init(
+                &path,
+                path_archive,
+                use_file_store,
+                require_complete_tree,
+                default_branch,
+            )?;
         }
 
         Command::Search { root, query } => {
@@ -22,13 +34,40 @@ fn main() -> anyhow::Result<()> {
             println!("{}", result);
         }
 
-        Command::Parse { query } => {
+        Command::Import {
+            root,
+            metadata_file,
+            location,
+        } => {
+            let data = std::fs::read_to_string(&metadata_file)
+                .with_context(|| format!("Could not read {}", metadata_file.display()))?;
+            let packet: Packet = serde_json::from_str(&data)?;
+            let hash = hash_data(data.as_bytes(), read_config(&root)?.core.hash_algorithm);
+            add_packet(&root, &data, &hash, &location)?;
+            println!("{}", packet.id);
+        }
+
+        Command::Verify { root, fix } => {
+            verify::verify(&root, fix)?;
+        }
+
+        Command::Parse { query, format } => {
             let result = parse_query(&query)?;
-            println!("{:?}", result);
+            match format {
+                ParseFormat::Human => println!("{:?}", result),
+                ParseFormat::Query => println!("{}", result),
+                ParseFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&query_to_json(&result))?)
+                }
+            }
         }
 
-        Command::StartServer { root, listen } => {
-            outpack::api::serve(&root, &listen)?;
+        Command::StartServer {
+            root,
+            listen,
+            options,
+        } => {
+            outpack::api::serve(&root, &listen, *options)?;
         }
     }
     Ok(())
@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use anyhow::bail;
+use outpack::location;
+use outpack::verify::verify_repository;
+
+/// Check the integrity of the repository at `root`, printing a summary of any problems found.
+/// With `fix`, location entries for packets without metadata are first removed.
+///
+/// Returns an error if any problem was found, so that the command exits with a non-zero status.
+pub fn verify(root: &Path, fix: bool) -> anyhow::Result<()> {
+    if fix {
+        let pruned = location::prune(root)?;
+        for entry in &pruned.entries {
+            println!(
+                "Removed entry for packet {} from location {}",
+                entry.packet, entry.location
+            );
+        }
+        println!("Pruned {} location entries", pruned.pruned);
+    }
+
+    let report = verify_repository(root, true)?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+
+    println!(
+        "Checked {} packets and {} stored files: {} problem(s) found",
+        report.packets,
+        report.files,
+        report.problems.len()
+    );
+
+    if !report.problems.is_empty() {
+        bail!("Repository verification failed");
+    }
+    Ok(())
+}
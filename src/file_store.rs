@@ -0,0 +1,189 @@
+//! Where the contents of the repository's files are kept.
+//!
+//! The handlers which transfer files go through the `FileStore` trait, rather than the functions
+//! in `store`, so that they don't depend on the file store being a directory on the local disk.
+//! The backend is chosen by `core.file_store` in the repository's configuration. `LocalFileStore`,
+//! which keeps files under `.outpack/files`, is the only implementation so far.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
+use crate::config::{Core, FileStoreBackend, FileStoreLayout};
+use crate::responses::{coded_error, ErrorCode};
+use crate::store;
+use crate::upload::Upload;
+use crate::worker_pool::WorkerPool;
+
+/// The contents of a stored file, read as they are streamed.
+pub struct StoredFile {
+    pub size: u64,
+    pub contents: BoxStream<'static, io::Result<Bytes>>,
+}
+
+#[axum::async_trait]
+pub trait FileStore: Send + Sync {
+    /// Open the file with the given hash, failing with `FILE_NOT_FOUND` if it isn't stored.
+    async fn get(&self, hash: &str) -> io::Result<StoredFile>;
+
+    /// Add a file to the store, once its contents have been checked against the hash. Adding a
+    /// file which is already stored succeeds without changing it.
+    async fn put(&self, hash: &str, file: Upload) -> io::Result<()>;
+
+    /// The hashes which are in the store, in the order they were given. Fails if any of them is
+    /// invalid.
+    async fn present(&self, hashes: &[String]) -> io::Result<Vec<String>>;
+
+    /// The hashes which aren't in the store, in the order they were given. Fails if any of them is
+    /// invalid.
+    async fn missing(&self, hashes: &[String]) -> io::Result<Vec<String>> {
+        let present: HashSet<String> = self.present(hashes).await?.into_iter().collect();
+        Ok(hashes
+            .iter()
+            .filter(|h| !present.contains(*h))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Open the file store of a repository, as chosen by its configuration. Files are staged in
+/// `temp_dir` while they are added, and hashed on the given pool.
+pub fn open(
+    root: &Path,
+    core: &Core,
+    temp_dir: PathBuf,
+    workers: WorkerPool,
+) -> Arc<dyn FileStore> {
+    match core.file_store {
+        FileStoreBackend::Local => Arc::new(LocalFileStore::new(
+            root.to_owned(),
+            core.file_store_layout,
+            temp_dir,
+            workers,
+        )),
+    }
+}
+
+/// A file store kept in the repository's `.outpack/files` directory.
+pub struct LocalFileStore {
+    root: PathBuf,
+    layout: FileStoreLayout,
+    temp_dir: PathBuf,
+    workers: WorkerPool,
+}
+
+impl LocalFileStore {
+    /// Files are arranged by the given layout, which must be the one in the repository's
+    /// configuration. They are staged in `temp_dir` while their hash is checked, which must be on
+    /// the same filesystem as the repository. The hashing is run on the given pool.
+    pub fn new(
+        root: PathBuf,
+        layout: FileStoreLayout,
+        temp_dir: PathBuf,
+        workers: WorkerPool,
+    ) -> LocalFileStore {
+        LocalFileStore {
+            root,
+            layout,
+            temp_dir,
+            workers,
+        }
+    }
+}
+
+#[axum::async_trait]
+impl FileStore for LocalFileStore {
+    async fn get(&self, hash: &str) -> io::Result<StoredFile> {
+        let path = store::file_path(&self.root, self.layout, hash)?;
+        let file = File::open(path).await.map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => coded_error(
+                io::ErrorKind::NotFound,
+                ErrorCode::FileNotFound,
+                format!("hash '{}' not found", hash),
+            ),
+            _ => e,
+        })?;
+        let size = file.metadata().await?.len();
+        Ok(StoredFile {
+            size,
+            contents: ReaderStream::new(file).boxed(),
+        })
+    }
+
+    async fn put(&self, hash: &str, file: Upload) -> io::Result<()> {
+        let root = self.root.clone();
+        let layout = self.layout;
+        let temp_dir = self.temp_dir.clone();
+        let hash = hash.to_owned();
+        self.workers
+            .run(move || store::put_file_via(&root, layout, &temp_dir, file, &hash))
+            .await
+    }
+
+    async fn present(&self, hashes: &[String]) -> io::Result<Vec<String>> {
+        store::get_present_files(&self.root, self.layout, hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{hash_data, HashAlgorithm};
+    use crate::test_utils::tests::get_empty_outpack_root;
+    use futures::TryStreamExt;
+
+    fn local_store() -> LocalFileStore {
+        let root = get_empty_outpack_root();
+        let temp_dir = store::default_temp_dir(&root);
+        LocalFileStore::new(
+            root,
+            FileStoreLayout::default(),
+            temp_dir,
+            WorkerPool::new(1),
+        )
+    }
+
+    #[tokio::test]
+    async fn local_store_can_put_and_get_files() {
+        let files = local_store();
+        let data = b"Testing 123.";
+        let hash = hash_data(data, HashAlgorithm::Sha256).to_string();
+        let other = hash_data(b"other", HashAlgorithm::Sha256).to_string();
+
+        files.put(&hash, Upload::from(data)).await.unwrap();
+        // Adding the same file again is allowed.
+        files.put(&hash, Upload::from(data)).await.unwrap();
+
+        let stored = files.get(&hash).await.unwrap();
+        assert_eq!(stored.size, data.len() as u64);
+        let contents: Vec<Bytes> = stored.contents.try_collect().await.unwrap();
+        assert_eq!(contents.concat(), data);
+
+        let hashes = vec![other.clone(), hash.clone()];
+        assert_eq!(files.present(&hashes).await.unwrap(), vec![hash]);
+        assert_eq!(files.missing(&hashes).await.unwrap(), vec![other]);
+    }
+
+    #[tokio::test]
+    async fn local_store_rejects_missing_and_mismatched_files() {
+        let files = local_store();
+        let hash = hash_data(b"expected", HashAlgorithm::Sha256).to_string();
+
+        let e = files.get(&hash).await.err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(e.to_string(), format!("hash '{}' not found", hash));
+
+        let e = files.put(&hash, Upload::from(b"actual")).await;
+        assert!(e.is_err());
+        assert!(files.get(&hash).await.is_err());
+
+        let e = files.present(&[String::from("sha256:xyz")]).await;
+        assert!(e.is_err());
+    }
+}
@@ -1,10 +1,13 @@
 use std::path::Path;
 
-use git2::{Branch, BranchType, Reference, Repository};
+use git2::build::CheckoutBuilder;
+use git2::{
+    Branch, BranchType, Commit, ErrorClass, ErrorCode, Reference, Repository, StatusOptions,
+};
 use serde::{Deserialize, Serialize};
 
 pub fn git_fetch(root: &Path) -> Result<(), git2::Error> {
-    let repo = Repository::open(root)?;
+    let repo = open_repository(root)?;
     let mut remote = repo.find_remote("origin")?;
     let ref_specs_iter = remote.fetch_refspecs()?;
     let ref_specs: Vec<&str> = ref_specs_iter.iter().map(|spec| spec.unwrap()).collect();
@@ -34,33 +37,46 @@ fn get_branch_name(reference: &Reference) -> String {
         .to_string()
 }
 
+/// A commit's message, split into lines.
+fn commit_message(commit: &Commit) -> Vec<String> {
+    String::from_utf8_lossy(commit.message_bytes())
+        .split_terminator("\n")
+        .map(String::from)
+        .collect()
+}
+
 fn get_branch_info(branch: Branch) -> Result<BranchInfo, git2::Error> {
     let git_ref = branch.get().resolve()?;
     let name = get_branch_name(&git_ref);
     let branch_commit = git_ref.peel_to_commit()?;
-    let message: Vec<String> = String::from_utf8_lossy(branch_commit.message_bytes())
-        .split_terminator("\n")
-        .map(String::from)
-        .collect();
     Ok(BranchInfo {
         name,
         commit_hash: branch_commit.id().to_string(),
         time: branch_commit.time().seconds(),
-        message,
+        message: commit_message(&branch_commit),
     })
 }
 
-pub fn git_list_branches(root: &Path) -> Result<BranchResponse, git2::Error> {
-    let repo = Repository::open(root)?;
+/// List the branches of the repository's remote.
+///
+/// The default branch is the one given, if any, and otherwise that of the remote's `HEAD`.
+pub fn git_list_branches(
+    root: &Path,
+    default_branch: Option<String>,
+) -> Result<BranchResponse, git2::Error> {
+    let repo = open_repository(root)?;
 
-    let default_branch = repo
-        .find_branch("origin/HEAD", BranchType::Remote)
-        .ok()
-        .map(|b| -> Result<String, git2::Error> {
-            let git_ref = b.get().resolve()?;
-            Ok(get_branch_name(&git_ref))
-        })
-        .transpose()?;
+    let default_branch = match default_branch {
+        Some(name) => Some(name),
+        None => repo
+            .find_branch("origin/HEAD", BranchType::Remote)
+            .ok()
+            .map(|b| -> Result<String, git2::Error> {
+                let git_ref = b.get().resolve()?;
+                Ok(get_branch_name(&git_ref))
+            })
+            .transpose()?,
+    };
 
     let branches = repo
         .branches(Some(BranchType::Remote))?
@@ -79,6 +95,176 @@ pub fn git_list_branches(root: &Path) -> Result<BranchResponse, git2::Error> {
     })
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullResponse {
+    branch: String,
+    commit_hash: String,
+}
+
+/// Fetch from the remote, then fast-forward a branch to match its remote counterpart and check
+/// it out.
+///
+/// The branch defaults to the one currently checked out, and is created if there is no local
+/// branch of that name yet. A branch which has diverged from the remote is left untouched and
+/// reported as an error, so that the server never has to resolve a merge. Returns the commit
+/// the branch ends up at.
+pub fn git_pull(root: &Path, branch: Option<&str>) -> Result<PullResponse, git2::Error> {
+    git_fetch(root)?;
+    let repo = open_repository(root)?;
+
+    let branch = match branch {
+        Some(name) => name.to_string(),
+        None => {
+            let head = repo.head()?;
+            match head.shorthand() {
+                Some(name) if head.is_branch() => name.to_string(),
+                _ => {
+                    return Err(git2::Error::new(
+                        ErrorCode::Invalid,
+                        ErrorClass::Reference,
+                        "HEAD is not on a branch, so a branch to pull must be given",
+                    ))
+                }
+            }
+        }
+    };
+
+    let target = repo
+        .find_branch(&format!("origin/{}", branch), BranchType::Remote)?
+        .get()
+        .peel_to_commit()?;
+    let commit = match repo.find_branch(&branch, BranchType::Local) {
+        Ok(local) => {
+            let current = local.get().peel_to_commit()?;
+            if current.id() == target.id() || repo.graph_descendant_of(current.id(), target.id())? {
+                current
+            } else if repo.graph_descendant_of(target.id(), current.id())? {
+                target
+            } else {
+                return Err(git2::Error::new(
+                    ErrorCode::NotFastForward,
+                    ErrorClass::Reference,
+                    format!(
+                        "branch '{}' has diverged from the remote and cannot be fast-forwarded",
+                        branch
+                    ),
+                ));
+            }
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => target,
+        Err(e) => return Err(e),
+    };
+
+    // The working tree is updated first, so that if it has changes which would be overwritten
+    // the checkout fails before any references are moved.
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    let reference = repo.reference(
+        &format!("refs/heads/{}", branch),
+        commit.id(),
+        true,
+        "pull: fast-forward",
+    )?;
+    repo.set_head(reference.name().unwrap())?;
+
+    Ok(PullResponse {
+        branch,
+        commit_hash: commit.id().to_string(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusResponse {
+    branch: Option<String>,
+    commit_hash: Option<String>,
+    message: Vec<String>,
+    dirty: bool,
+}
+
+/// Open the git repository at the root, reporting clearly if there isn't one.
+fn open_repository(root: &Path) -> Result<Repository, git2::Error> {
+    Repository::open(root).map_err(|e| match e.code() {
+        ErrorCode::NotFound => git2::Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Repository,
+            format!("'{}' is not a git repository", root.display()),
+        ),
+        _ => e,
+    })
+}
+
+/// Describe the commit the working tree is on, and whether it has changes.
+///
+/// The branch is `None` if `HEAD` is detached, and the commit is `None` if nothing has been
+/// committed yet. Only changes to tracked files make the working tree dirty; untracked files
+/// are ignored.
+pub fn git_status(root: &Path) -> Result<StatusResponse, git2::Error> {
+    let repo = open_repository(root)?;
+
+    let (branch, commit) = match repo.head() {
+        Ok(head) => {
+            let branch = head
+                .is_branch()
+                .then(|| head.shorthand().map(String::from))
+                .flatten();
+            (branch, Some(head.peel_to_commit()?))
+        }
+        Err(e) if e.code() == ErrorCode::UnbornBranch => {
+            let head = repo.find_reference("HEAD")?;
+            let branch = head
+                .symbolic_target()
+                .and_then(|target| target.strip_prefix("refs/heads/"))
+                .map(String::from);
+            (branch, None)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let statuses = repo.statuses(Some(
+        StatusOptions::new()
+            .include_untracked(false)
+            .exclude_submodules(true),
+    ))?;
+
+    Ok(StatusResponse {
+        branch,
+        commit_hash: commit.as_ref().map(|c| c.id().to_string()),
+        message: commit.as_ref().map(commit_message).unwrap_or_default(),
+        dirty: !statuses.is_empty(),
+    })
+}
+
+/// Read the contents of a file as it is in the given git revision, which may be anything `git
+/// rev-parse` understands, such as a branch, tag or commit hash.
+pub fn git_read_file(root: &Path, revision: &str, path: &str) -> Result<Vec<u8>, git2::Error> {
+    let repo = open_repository(root)?;
+    let tree = repo.revparse_single(revision)?.peel_to_tree()?;
+    let object = tree.get_path(Path::new(path))?.to_object(&repo)?;
+    let content = match object.as_blob() {
+        Some(blob) => blob.content().to_vec(),
+        None => {
+            return Err(git2::Error::new(
+                ErrorCode::NotFound,
+                ErrorClass::Tree,
+                format!("'{}' is not a file in '{}'", path, revision),
+            ))
+        }
+    };
+    Ok(content)
+}
+
+/// Point `HEAD` at the given branch in a git repository which has no commits yet, so that the
+/// first commit creates that branch. Does nothing if the path isn't a git repository, or it
+/// already has commits.
+pub fn set_initial_branch(root: &Path, name: &str) -> Result<(), git2::Error> {
+    let Ok(repo) = Repository::open(root) else {
+        return Ok(());
+    };
+    if repo.is_empty()? {
+        repo.set_head(&format!("refs/heads/{}", name))?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use test_utils::{git_get_latest_commit, git_remote_branches, initialise_git_repo};
@@ -117,7 +303,7 @@ mod tests {
         let local_path = &test_git.dir.path().join("local");
         git_fetch(local_path).unwrap();
 
-        let branch_response = git_list_branches(local_path).unwrap();
+        let branch_response = git_list_branches(local_path, None).unwrap();
         let default_branch = branch_response.default_branch.unwrap();
         let branches_list = branch_response.branches;
 
@@ -131,5 +317,142 @@ mod tests {
         );
         assert_eq!(branches_list[1].name, String::from("other"));
         assert_eq!(branches_list[1].message, vec![String::from("Third commit")]);
+
+        let branch_response = git_list_branches(local_path, Some(String::from("other"))).unwrap();
+        assert_eq!(branch_response.default_branch.unwrap(), "other");
+    }
+
+    #[test]
+    fn can_pull_current_branch() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+        let remote_head = git_get_latest_commit(&test_git.remote, "HEAD");
+
+        let res = git_pull(&local_path, None).unwrap();
+        assert_eq!(res.commit_hash, remote_head.id().to_string());
+        assert_eq!(
+            git_get_latest_commit(&test_git.local, "HEAD").id(),
+            remote_head.id()
+        );
+        assert!(local_path.join("new_file2").exists());
+
+        // Pulling again has nothing to do.
+        let again = git_pull(&local_path, None).unwrap();
+        assert_eq!(again.commit_hash, res.commit_hash);
+    }
+
+    #[test]
+    fn can_pull_new_branch() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+
+        let res = git_pull(&local_path, Some("other")).unwrap();
+        assert_eq!(res.branch, "other");
+        let head = test_git.local.head().unwrap();
+        assert_eq!(head.name(), Some("refs/heads/other"));
+        assert_eq!(
+            head.peel_to_commit().unwrap().message(),
+            Some("Third commit")
+        );
+        assert!(local_path.join("new_file3").exists());
+
+        let err = git_pull(&local_path, Some("missing")).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn pull_refuses_to_merge_diverged_branch() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+
+        let repo = &test_git.local;
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        let signature = git2::Signature::now("Test User", "test.user@example.com").unwrap();
+        let local_commit = repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "Local commit",
+                &parent.tree().unwrap(),
+                &[&parent],
+            )
+            .unwrap();
+
+        let err = git_pull(&local_path, None).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFastForward);
+        assert!(err.message().contains("has diverged from the remote"));
+        assert_eq!(git_get_latest_commit(repo, "HEAD").id(), local_commit);
+    }
+
+    #[test]
+    fn can_read_file_from_revision() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+        git_fetch(&local_path).unwrap();
+
+        let content = git_read_file(&local_path, "origin/other", "new_file3").unwrap();
+        assert_eq!(content, b"File contents");
+
+        let err = git_read_file(&local_path, "HEAD", "new_file3").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        let err = git_read_file(&local_path, "missing", "new_file").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn can_get_status() {
+        let test_git = initialise_git_repo(None);
+        let local_path = test_git.dir.path().join("local");
+        let head = git_get_latest_commit(&test_git.local, "HEAD");
+
+        let status = git_status(&local_path).unwrap();
+        assert_eq!(status.branch.as_deref(), Some("master"));
+        assert_eq!(status.commit_hash, Some(head.id().to_string()));
+        assert_eq!(status.message, vec![String::from("First commit")]);
+        assert!(!status.dirty);
+
+        // Untracked files don't count as changes, but modified ones do.
+        std::fs::write(local_path.join("untracked"), "new").unwrap();
+        assert!(!git_status(&local_path).unwrap().dirty);
+        std::fs::write(local_path.join("new_file"), "changed").unwrap();
+        assert!(git_status(&local_path).unwrap().dirty);
+
+        test_git.local.set_head_detached(head.id()).unwrap();
+        let status = git_status(&local_path).unwrap();
+        assert_eq!(status.branch, None);
+        assert_eq!(status.commit_hash, Some(head.id().to_string()));
+    }
+
+    #[test]
+    fn status_of_empty_and_missing_repositories() {
+        let tmp = tempdir::TempDir::new("repo").unwrap();
+        Repository::init(tmp.path()).unwrap();
+        set_initial_branch(tmp.path(), "develop").unwrap();
+        let status = git_status(tmp.path()).unwrap();
+        assert_eq!(status.branch.as_deref(), Some("develop"));
+        assert_eq!(status.commit_hash, None);
+        assert!(status.message.is_empty());
+
+        let tmp = tempdir::TempDir::new("outpack").unwrap();
+        let err = git_status(tmp.path()).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(err.message().contains("is not a git repository"));
+    }
+
+    #[test]
+    fn can_set_initial_branch() {
+        let tmp = tempdir::TempDir::new("repo").unwrap();
+        let repo = Repository::init(tmp.path()).unwrap();
+        set_initial_branch(tmp.path(), "develop").unwrap();
+        assert_eq!(
+            repo.find_reference("HEAD").unwrap().symbolic_target(),
+            Some("refs/heads/develop")
+        );
+
+        // Not a git repository, so nothing to do.
+        let tmp = tempdir::TempDir::new("outpack").unwrap();
+        set_initial_branch(tmp.path(), "develop").unwrap();
+        assert!(!tmp.path().join(".git").exists());
     }
 }
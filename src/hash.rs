@@ -6,6 +6,8 @@ use std::fmt;
 use std::fmt::LowerHex;
 use std::path::Path;
 
+use crate::responses::{coded_error, ErrorCode};
+
 #[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum HashAlgorithm {
@@ -41,8 +43,24 @@ impl HashError {
     pub fn new(kind: HashErrorKind, explanation: String) -> Self {
         HashError { kind, explanation }
     }
+
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self.kind {
+            HashErrorKind::HashesDontMatch => ErrorCode::HashMismatch,
+            HashErrorKind::FileReadFailed => ErrorCode::InvalidInput,
+            _ => ErrorCode::InvalidHash,
+        }
+    }
 }
 
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.explanation)
+    }
+}
+
+impl std::error::Error for HashError {}
+
 impl From<std::io::Error> for HashError {
     fn from(e: std::io::Error) -> Self {
         HashError::new(HashErrorKind::FileReadFailed, e.to_string())
@@ -51,7 +69,7 @@ impl From<std::io::Error> for HashError {
 
 // Helper for the reverse, this is not pretty and will go away later.
 pub fn hash_error_to_io_error(e: HashError) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::InvalidInput, e.explanation.clone())
+    coded_error(std::io::ErrorKind::InvalidInput, e.code(), e.explanation)
 }
 
 impl fmt::Display for HashAlgorithm {
@@ -76,8 +94,9 @@ impl fmt::Display for Hash {
 impl std::str::FromStr for HashAlgorithm {
     type Err = HashError;
 
+    /// Algorithm names are matched case-insensitively, as some clients send e.g. `SHA256`.
     fn from_str(s: &str) -> Result<HashAlgorithm, Self::Err> {
-        match s {
+        match s.to_ascii_lowercase().as_str() {
             "md5" => Ok(HashAlgorithm::Md5),
             "sha1" => Ok(HashAlgorithm::Sha1),
             "sha256" => Ok(HashAlgorithm::Sha256),
@@ -141,6 +160,33 @@ pub fn hash_stream(
     Ok(Hash { algorithm, value })
 }
 
+fn hash_chunks_impl<D>(chunks: impl IntoIterator<Item = impl AsRef<[u8]>>) -> String
+where
+    D: Digest,
+    digest::Output<D>: LowerHex,
+{
+    let mut hasher = D::new();
+    for chunk in chunks {
+        hasher.update(chunk);
+    }
+    hex_string(hasher.finalize())
+}
+
+/// Hash the concatenation of several pieces of data, without joining them together first.
+pub fn hash_chunks(
+    chunks: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    algorithm: HashAlgorithm,
+) -> Hash {
+    let value = match algorithm {
+        HashAlgorithm::Md5 => hash_chunks_impl::<md5::Md5>(chunks),
+        HashAlgorithm::Sha1 => hash_chunks_impl::<sha1::Sha1>(chunks),
+        HashAlgorithm::Sha256 => hash_chunks_impl::<sha2::Sha256>(chunks),
+        HashAlgorithm::Sha384 => hash_chunks_impl::<sha2::Sha384>(chunks),
+        HashAlgorithm::Sha512 => hash_chunks_impl::<sha2::Sha512>(chunks),
+    };
+    Hash { algorithm, value }
+}
+
 pub fn hash_data(data: &[u8], algorithm: HashAlgorithm) -> Hash {
     hash_stream(data, algorithm).expect("reading from memory cannot fail")
 }
@@ -200,6 +246,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn can_parse_mixed_case_hash_algorithm() {
+        for algorithm in [
+            HashAlgorithm::Md5,
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha384,
+            HashAlgorithm::Sha512,
+        ] {
+            let name = algorithm.to_string();
+            let mut capitalised = name.clone();
+            capitalised[..1].make_ascii_uppercase();
+            for prefix in [name.to_ascii_uppercase(), capitalised] {
+                assert_eq!(prefix.parse(), Ok(algorithm));
+                assert_eq!(
+                    format!("{prefix}:abcd").parse(),
+                    Ok(Hash {
+                        algorithm,
+                        value: String::from("abcd")
+                    })
+                );
+            }
+        }
+
+        // Hashes are always written with a lowercase algorithm.
+        let hash: Hash = "SHA256:abcd".parse().unwrap();
+        assert_eq!(hash.to_string(), "sha256:abcd");
+
+        assert_eq!(
+            "SHA666:1234".parse::<Hash>(),
+            Err(HashError::new(
+                HashErrorKind::InvalidHashAlgorithm,
+                String::from("Invalid hash algorithm 'SHA666'")
+            ))
+        );
+    }
+
     #[test]
     fn can_deparse_hash() {
         let h = Hash {
@@ -232,6 +315,13 @@ mod tests {
                 String::from("Invalid hash format 'md51234'")
             ))
         );
+        assert_eq!(
+            "sha256:../../../etc".parse::<Hash>(),
+            Err(HashError::new(
+                HashErrorKind::InvalidHashFormat,
+                String::from("Invalid hash format 'sha256:../../../etc'")
+            ))
+        );
         assert_eq!(
             "sha666:1234".parse::<Hash>(),
             Err(HashError::new(
@@ -286,6 +376,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn hashing_chunks_matches_hashing_joined_data() {
+        let chunks = ["abc", "", "defg", "h"];
+        for algorithm in [
+            HashAlgorithm::Md5,
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha384,
+            HashAlgorithm::Sha512,
+        ] {
+            assert_eq!(
+                hash_chunks(chunks, algorithm),
+                hash_data(b"abcdefgh", algorithm)
+            );
+        }
+        assert_eq!(
+            hash_chunks(Vec::<String>::new(), HashAlgorithm::Sha256),
+            hash_data(b"", HashAlgorithm::Sha256)
+        );
+    }
+
     #[test]
     fn can_validate_hash() {
         let expect_md5 = "md5:81dc9bdb52d04dc20036dbd8313ed055";
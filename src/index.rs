@@ -1,4 +1,7 @@
-use crate::metadata::{get_metadata_from_date, Packet};
+use crate::metadata::{get_metadata_from_date, get_valid_id, Packet, TimeRange};
+use crate::responses::{coded_error, ErrorCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::path::Path;
 
@@ -7,14 +10,113 @@ pub struct Index {
     pub packets: Vec<Packet>,
 }
 
+impl Index {
+    /// The ids of the packets that each packet directly depends on, in the order they are listed
+    /// in its metadata and without repeats.
+    pub fn dependencies(&self) -> HashMap<&str, Vec<&str>> {
+        self.packets
+            .iter()
+            .map(|packet| {
+                let mut seen = HashSet::new();
+                let depends = packet
+                    .depends
+                    .iter()
+                    .map(|d| d.packet.as_str())
+                    .filter(|id| seen.insert(*id))
+                    .collect();
+                (packet.id.as_str(), depends)
+            })
+            .collect()
+    }
+}
+
 pub fn get_packet_index(root: &Path) -> io::Result<Index> {
-    let packets = get_metadata_from_date(root, None)?;
+    let packets = get_metadata_from_date(root, TimeRange::default())?;
     Ok(Index { packets })
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DependencyEdge {
+    pub packet: String,
+    pub depends_on: String,
+}
+
+/// The packets a packet depends on, directly or indirectly, as a list of edges.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DependencyGraph {
+    pub packet: String,
+    pub edges: Vec<DependencyEdge>,
+}
+
+/// Follow a packet's dependencies, breadth first, through at most `depth` levels, or all of them
+/// if `depth` is `None`.
+///
+/// Each packet is only followed once, so shared dependencies and cycles don't repeat edges.
+/// Dependencies that aren't in the repository appear as edges but can't be followed further.
+pub fn get_dependency_graph(
+    root: &Path,
+    id: &str,
+    depth: Option<usize>,
+) -> io::Result<DependencyGraph> {
+    let id = get_valid_id(id)?;
+    let index = get_packet_index(root)?;
+    let dependencies = index.dependencies();
+    if !dependencies.contains_key(id.as_str()) {
+        return Err(coded_error(
+            io::ErrorKind::NotFound,
+            ErrorCode::PacketNotFound,
+            format!("packet with id '{}' does not exist", id),
+        ));
+    }
+
+    let mut edges = Vec::new();
+    let mut visited = HashSet::from([id.as_str()]);
+    let mut queue = VecDeque::from([(id.as_str(), 0)]);
+    while let Some((packet, level)) = queue.pop_front() {
+        if depth.is_some_and(|depth| level >= depth) {
+            continue;
+        }
+        for &dependency in dependencies.get(packet).into_iter().flatten() {
+            edges.push(DependencyEdge {
+                packet: packet.to_string(),
+                depends_on: dependency.to_string(),
+            });
+            if visited.insert(dependency) {
+                queue.push_back((dependency, level + 1));
+            }
+        }
+    }
+
+    Ok(DependencyGraph {
+        packet: id.clone(),
+        edges,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hash::{hash_data, HashAlgorithm};
+    use crate::metadata::add_metadata;
+    use crate::metadata::DependencyFile;
+    use crate::test_utils::tests::{get_empty_outpack_root, random_id, start_packet};
+
+    fn add_packet_depending_on(root: &Path, depends: &[&str]) -> String {
+        let mut builder = start_packet("data");
+        for dependency in depends {
+            builder.add_dependency(*dependency, Vec::<DependencyFile>::new());
+        }
+        let (id, data, hash) = builder.finish();
+        add_metadata(root, &data, &hash).unwrap();
+        id
+    }
+
+    fn edge(packet: &str, depends_on: &str) -> DependencyEdge {
+        DependencyEdge {
+            packet: packet.to_string(),
+            depends_on: depends_on.to_string(),
+        }
+    }
 
     #[test]
     fn can_get_packet_index() {
@@ -30,4 +132,59 @@ mod tests {
         assert_eq!(ids[2], "20180220-095832-16a4bbed");
         assert_eq!(ids[3], "20180818-164043-7cdcde4b");
     }
+
+    #[test]
+    fn can_follow_dependencies() {
+        let root = get_empty_outpack_root();
+        let a = add_packet_depending_on(&root, &[]);
+        let b = add_packet_depending_on(&root, &[&a]);
+        let c = add_packet_depending_on(&root, &[&a, &b, &a]);
+        let d = add_packet_depending_on(&root, &[&c]);
+
+        let graph = get_dependency_graph(&root, &d, None).unwrap();
+        assert_eq!(graph.packet, d);
+        assert_eq!(
+            graph.edges,
+            vec![edge(&d, &c), edge(&c, &a), edge(&c, &b), edge(&b, &a)]
+        );
+
+        let graph = get_dependency_graph(&root, &d, Some(2)).unwrap();
+        assert_eq!(graph.edges, vec![edge(&d, &c), edge(&c, &a), edge(&c, &b)]);
+
+        let graph = get_dependency_graph(&root, &d, Some(0)).unwrap();
+        assert!(graph.edges.is_empty());
+    }
+
+    #[test]
+    fn dependency_cycles_are_followed_once() {
+        let root = get_empty_outpack_root();
+        let a = random_id();
+        let b = add_packet_depending_on(&root, &[&a]);
+
+        // A packet can't really depend on one created after it, but its metadata could claim to.
+        let (_, data, _) = start_packet("cycle")
+            .add_dependency(&b, Vec::<DependencyFile>::new())
+            .finish();
+        let mut packet: Packet = serde_json::from_str(&data).unwrap();
+        packet.id = a.clone();
+        let data = serde_json::to_string(&packet).unwrap();
+        let hash = hash_data(data.as_bytes(), HashAlgorithm::Sha256);
+        add_metadata(&root, &data, &hash).unwrap();
+
+        let graph = get_dependency_graph(&root, &a, None).unwrap();
+        assert_eq!(graph.edges, vec![edge(&a, &b), edge(&b, &a)]);
+    }
+
+    #[test]
+    fn dependency_graph_of_unknown_packet_is_not_found() {
+        let root = Path::new("tests/example");
+        let err = get_dependency_graph(root, &String::from("20170818-164847-00000000"), None)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // Dependencies missing from the repository are listed, but not followed.
+        let id = String::from("20170818-164847-7574883b");
+        let graph = get_dependency_graph(root, &id, None).unwrap();
+        assert_eq!(graph.edges, vec![edge(&id, "20170818-164043-7cdcde4b")]);
+    }
 }
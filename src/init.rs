@@ -2,16 +2,22 @@ use anyhow::bail;
 use std::fs;
 use std::path::Path;
 
-use crate::config;
+use crate::{config, git};
 
 pub fn outpack_init(
     path: &Path,
     path_archive: Option<String>,
     use_file_store: bool,
     require_complete_tree: bool,
+    default_branch: Option<String>,
 ) -> anyhow::Result<()> {
     let path_outpack = path.join(".outpack");
-    let cfg = config::Config::new(path_archive, use_file_store, require_complete_tree)?;
+    let cfg = config::Config::new(
+        path_archive,
+        use_file_store,
+        require_complete_tree,
+        default_branch.clone(),
+    )?;
 
     if path_outpack.exists() {
         let prev = config::read_config(path)?;
@@ -21,8 +27,11 @@ pub fn outpack_init(
     } else {
         fs::create_dir_all(&path_outpack)?;
         config::write_config(&cfg, path)?;
-        fs::create_dir_all(path_outpack.join("location").join("local"))?;
+        fs::create_dir_all(path_outpack.join("location").join(config::LOCAL_LOCATION))?;
         fs::create_dir_all(path_outpack.join("metadata"))?;
+        if let Some(branch) = &default_branch {
+            git::set_initial_branch(path, branch)?;
+        }
         if use_file_store {
             fs::create_dir_all(path_outpack.join("files"))?;
         }
@@ -42,11 +51,11 @@ mod tests {
     fn can_create_empty_config() {
         let tmp = tempfile::TempDir::new().unwrap();
         let path = tmp.path();
-        let res = outpack_init(path, None, true, true);
+        let res = outpack_init(path, None, true, true, None);
         assert!(res.is_ok());
         assert_eq!(
             config::read_config(path).unwrap(),
-            config::Config::new(None, true, true).unwrap()
+            config::Config::new(None, true, true, None).unwrap()
         );
     }
 
@@ -54,18 +63,33 @@ mod tests {
     fn can_reinit_an_existing_repo() {
         let tmp = tempfile::TempDir::new().unwrap();
         let path = tmp.path();
-        let res = outpack_init(path, Some(String::from("archive")), false, false);
+        let res = outpack_init(path, Some(String::from("archive")), false, false, None);
         assert!(res.is_ok());
         assert_eq!(
             config::read_config(path).unwrap(),
-            config::Config::new(Some(String::from("archive")), false, false).unwrap()
+            config::Config::new(Some(String::from("archive")), false, false, None).unwrap()
         );
 
-        let res = outpack_init(path, Some(String::from("archive")), false, false);
+        let res = outpack_init(path, Some(String::from("archive")), false, false, None);
         assert!(res.is_ok());
         assert_eq!(
             config::read_config(path).unwrap(),
-            config::Config::new(Some(String::from("archive")), false, false).unwrap()
+            config::Config::new(Some(String::from("archive")), false, false, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn can_set_default_branch() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path();
+        let repo = git2::Repository::init(path).unwrap();
+        outpack_init(path, None, true, true, Some(String::from("develop"))).unwrap();
+
+        let cfg = config::read_config(path).unwrap();
+        assert_eq!(cfg.core.default_branch.as_deref(), Some("develop"));
+        assert_eq!(
+            repo.find_reference("HEAD").unwrap().symbolic_target(),
+            Some("refs/heads/develop")
         );
     }
 
@@ -73,8 +97,8 @@ mod tests {
     fn error_if_config_has_changed() {
         let tmp = tempfile::TempDir::new().unwrap();
         let path = tmp.path();
-        outpack_init(path, Some(String::from("archive")), false, false).unwrap();
-        let res = outpack_init(path, None, true, true);
+        outpack_init(path, Some(String::from("archive")), false, false, None).unwrap();
+        let res = outpack_init(path, None, true, true, None);
         assert!(res.is_err());
         assert_eq!(
             res.unwrap_err().to_string(),
@@ -3,17 +3,26 @@ mod test_utils;
 
 pub mod api;
 pub mod config;
+pub mod hash;
 pub mod index;
 pub mod init;
+pub mod location;
+pub mod metadata;
 pub mod query;
+pub mod storage;
+pub mod store;
+pub mod verify;
 
+mod file_store;
 mod git;
-mod hash;
-mod location;
-mod metadata;
+mod metadata_store;
 mod metrics;
 mod outpack_file;
+mod rate_limit;
 mod responses;
-mod store;
+mod schema;
+mod server;
 mod upload;
 mod utils;
+mod worker_pool;
+mod zip;
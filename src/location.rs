@@ -1,11 +1,14 @@
 use crate::config::Location;
+use crate::metadata_store;
+use crate::responses::{coded_error, ErrorCode};
 use crate::utils::time_as_num;
-use cached::cached_result;
 use cached::instant::SystemTime;
+use cached::{cached_result, Cached};
 use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
 use std::fs::DirEntry;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{fs, io};
 
 use super::config;
@@ -16,8 +19,17 @@ pub struct LocationEntry {
     pub packet: String,
     pub time: f64,
     pub hash: String,
+    /// The name of the location the entry was read from. This is implied by the directory the
+    /// entry is stored in, so it is not written to the entry's file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
 }
 
+/// Held while checking whether an entry exists and writing it, and while pruning entries, so that
+/// an entry for a packet which is being imported can't be pruned just before the import would have
+/// written it.
+static ENTRY_LOCK: Mutex<()> = Mutex::new(());
+
 cached_result! {
     ENTRY_CACHE: cached::UnboundCache<PathBuf, LocationEntry> = cached::UnboundCache::new();
     fn read_entry(path: PathBuf) -> io::Result<LocationEntry> = {
@@ -36,10 +48,18 @@ fn get_order(location_config: &[Location], entry: &DirEntry) -> usize {
 }
 
 pub fn read_location(path: PathBuf) -> io::Result<Vec<LocationEntry>> {
-    let mut packets = fs::read_dir(path)?
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from);
+    let mut packets = fs::read_dir(&path)?
         .filter_map(|e| e.ok())
         .filter(|e| utils::is_packet(&e.file_name()))
-        .map(|entry| read_entry(entry.path()))
+        .map(|entry| {
+            let mut entry = read_entry(entry.path())?;
+            entry.location.clone_from(&name);
+            Ok(entry)
+        })
         .collect::<io::Result<Vec<LocationEntry>>>()?;
 
     packets.sort_by(|a, b| a.packet.cmp(&b.packet));
@@ -70,6 +90,106 @@ pub fn read_locations(root_path: &Path) -> io::Result<Vec<LocationEntry>> {
     Ok(packets)
 }
 
+/// Read the entries of a single location, identified by its name.
+///
+/// Returns a `NotFound` error if the location is not configured in the repository. A configured
+/// location that has never had any packets is listed as empty.
+pub fn read_named_location(root_path: &Path, name: &str) -> io::Result<Vec<LocationEntry>> {
+    let location_config = config::read_config(root_path)?.location;
+    if !location_config.iter().any(|l| l.name == name) {
+        return Err(coded_error(
+            io::ErrorKind::NotFound,
+            ErrorCode::LocationNotFound,
+            format!("location '{}' does not exist", name),
+        ));
+    }
+
+    let path = root_path.join(".outpack").join("location").join(name);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    read_location(path)
+}
+
+/// The time the most recent packet was added to any location, if there are any.
+pub fn last_modified(root_path: &Path) -> io::Result<Option<f64>> {
+    Ok(read_locations(root_path)?
+        .into_iter()
+        .map(|e| e.time)
+        .max_by(f64::total_cmp))
+}
+
+/// The hash of a packet's metadata, as recorded by the first location, in the configured order,
+/// that knows of the packet. Packets without any location entry have no known hash.
+pub fn get_packet_hash(root_path: &Path, id: &str) -> io::Result<Option<String>> {
+    Ok(read_locations(root_path)?
+        .into_iter()
+        .find(|e| e.packet == id)
+        .map(|e| e.hash))
+}
+
+/// A page of a location's entries, along with the total number of entries in the location.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocationPage {
+    pub entries: Vec<LocationEntry>,
+    pub total: usize,
+}
+
+/// Select a page of location entries, ordered by the time they were added.
+///
+/// Entries added at the same time are ordered by packet id, so that pages are stable. Without a
+/// `limit`, all entries after `offset` are returned.
+pub fn page_entries(
+    mut entries: Vec<LocationEntry>,
+    offset: usize,
+    limit: Option<usize>,
+) -> LocationPage {
+    entries.sort_by(|a, b| {
+        a.time
+            .total_cmp(&b.time)
+            .then_with(|| a.packet.cmp(&b.packet))
+    });
+    let total = entries.len();
+    let entries = entries
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+    LocationPage { entries, total }
+}
+
+/// Write a location entry, unless one exists already and `replace` is false.
+fn write_entry(
+    root: &Path,
+    location_id: &str,
+    entry: &LocationEntry,
+    replace: bool,
+) -> io::Result<()> {
+    let location_path = root.join(".outpack").join("location").join(location_id);
+
+    fs::create_dir_all(&location_path)?;
+    let path = location_path.join(&entry.packet);
+    let _guard = ENTRY_LOCK.lock().unwrap();
+    if path.exists() && !replace {
+        return Ok(());
+    }
+    fs::File::create(&path)?;
+    let json = serde_json::to_string(entry)?;
+    fs::write(&path, json)?;
+    // Entries are cached by path, so a replaced entry would otherwise still be read.
+    ENTRY_CACHE.lock().unwrap().cache_remove(&path);
+    Ok(())
+}
+
+fn new_entry(packet_id: &str, hash: &str, time: SystemTime) -> LocationEntry {
+    LocationEntry {
+        packet: String::from(packet_id),
+        time: time_as_num(time),
+        hash: String::from(hash),
+        location: None,
+    }
+}
+
 pub fn mark_packet_known(
     packet_id: &str,
     location_id: &str,
@@ -77,28 +197,99 @@ pub fn mark_packet_known(
     time: SystemTime,
     root: &Path,
 ) -> io::Result<()> {
-    let entry = LocationEntry {
-        packet: String::from(packet_id),
-        time: time_as_num(time),
-        hash: String::from(hash),
-    };
+    write_entry(root, location_id, &new_entry(packet_id, hash, time), false)
+}
 
-    let location_path = root.join(".outpack").join("location").join(location_id);
+/// Like `mark_packet_known`, but replacing any existing entry for the packet. Returns the entry
+/// that was written.
+pub fn replace_packet_entry(
+    packet_id: &str,
+    location_id: &str,
+    hash: &str,
+    time: SystemTime,
+    root: &Path,
+) -> io::Result<LocationEntry> {
+    let entry = new_entry(packet_id, hash, time);
+    write_entry(root, location_id, &entry, true)?;
+    Ok(LocationEntry {
+        location: Some(String::from(location_id)),
+        ..entry
+    })
+}
 
-    fs::create_dir_all(&location_path)?;
-    let path = location_path.join(packet_id);
+/// A location entry removed by `prune`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrunedEntry {
+    pub packet: String,
+    pub location: String,
+}
+
+/// The result of pruning location entries.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PruneReport {
+    /// The number of entries removed.
+    pub pruned: usize,
+    pub entries: Vec<PrunedEntry>,
+}
+
+/// Remove location entries for packets whose metadata is not in the repository, such as those
+/// left behind when packets are removed.
+///
+/// Packets' metadata is written before their location entries, and this holds the same lock as
+/// writing an entry, so it is safe to run alongside imports made by this process. It isn't safe
+/// against imports made by another process, so a running server's repository should be pruned
+/// through the server.
+pub fn prune(root: &Path) -> io::Result<PruneReport> {
+    let path = root.join(".outpack").join("location");
     if !path.exists() {
-        fs::File::create(&path)?;
-        let json = serde_json::to_string(&entry)?;
-        fs::write(path, json)?;
+        return Ok(PruneReport {
+            pruned: 0,
+            entries: Vec::new(),
+        });
     }
-    Ok(())
+    let store = metadata_store::open(root)?;
+
+    let mut dirs: Vec<_> = fs::read_dir(path)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    dirs.sort();
+
+    let mut entries = Vec::new();
+    for dir in dirs {
+        let location = dir.file_name().unwrap().to_string_lossy().into_owned();
+        let mut packets: Vec<_> = fs::read_dir(&dir)?
+            .filter_map(|e| e.ok())
+            .filter(|e| utils::is_packet(&e.file_name()))
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        packets.sort();
+
+        let _guard = ENTRY_LOCK.lock().unwrap();
+        for packet in packets {
+            if store.contains(&packet)? {
+                continue;
+            }
+            let entry_path = dir.join(&packet);
+            fs::remove_file(&entry_path)?;
+            ENTRY_CACHE.lock().unwrap().cache_remove(&entry_path);
+            entries.push(PrunedEntry {
+                packet,
+                location: location.clone(),
+            });
+        }
+    }
+
+    Ok(PruneReport {
+        pruned: entries.len(),
+        entries,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_utils::tests::get_temp_outpack_root;
+    use crate::test_utils::tests::{get_log_outpack_root, get_temp_outpack_root};
     use std::time::{Duration, SystemTime};
 
     #[test]
@@ -110,6 +301,74 @@ mod tests {
         assert_eq!(entries[3].packet, "20180818-164043-7cdcde4b");
     }
 
+    #[test]
+    fn last_modified_is_time_of_latest_entry() {
+        let time = last_modified(Path::new("tests/example")).unwrap();
+        assert_eq!(time, Some(1662480556.1778));
+    }
+
+    #[test]
+    fn can_get_packet_hash() {
+        let root = Path::new("tests/example");
+        let hash = get_packet_hash(root, "20170818-164847-7574883b").unwrap();
+        assert_eq!(
+            hash,
+            Some(String::from(
+                "sha256:af3c863f96898c6c88cee4daa1a6d6cfb756025e70059f5ea4dbe4d9cc5e0e36"
+            ))
+        );
+        assert_eq!(
+            get_packet_hash(root, "20170818-164847-00000000").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn can_read_named_location() {
+        let entries = read_named_location(Path::new("tests/example"), "another").unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].packet, "20170818-164830-33e0ab01");
+
+        let res = read_named_location(Path::new("tests/example"), "unknown");
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "location 'unknown' does not exist");
+    }
+
+    #[test]
+    fn configured_location_without_entries_is_empty() {
+        let root = get_temp_outpack_root();
+        fs::remove_dir_all(root.join(".outpack/location/another")).unwrap();
+        let entries = read_named_location(&root, "another").unwrap();
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn can_page_entries_by_time() {
+        let entries = read_named_location(Path::new("tests/example"), "another").unwrap();
+
+        let page = page_entries(entries.clone(), 0, None);
+        assert_eq!(page.total, 3);
+        let ids: Vec<&str> = page.entries.iter().map(|e| e.packet.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                "20180220-095832-16a4bbed",
+                "20180818-164043-7cdcde4b",
+                "20170818-164830-33e0ab01"
+            ]
+        );
+
+        let page = page_entries(entries.clone(), 1, Some(1));
+        assert_eq!(page.total, 3);
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].packet, "20180818-164043-7cdcde4b");
+
+        let page = page_entries(entries, 5, Some(2));
+        assert_eq!(page.total, 3);
+        assert!(page.entries.is_empty());
+    }
+
     #[test]
     fn can_mark_known() {
         let root = get_temp_outpack_root();
@@ -179,4 +438,37 @@ mod tests {
         // time known should still be the time it was first added at
         assert_eq!(res.time, time_as_num(now));
     }
+
+    #[test]
+    fn can_prune_entries_without_metadata() {
+        let root = get_temp_outpack_root();
+        let id = "20170818-164847-7574883b";
+        fs::remove_file(root.join(".outpack/metadata").join(id)).unwrap();
+        let before = read_locations(&root).unwrap().len();
+
+        let report = prune(&root).unwrap();
+        assert_eq!(report.pruned, 1);
+        assert_eq!(
+            report.entries,
+            vec![PrunedEntry {
+                packet: String::from(id),
+                location: String::from("local"),
+            }]
+        );
+        let entries = read_locations(&root).unwrap();
+        assert_eq!(entries.len(), before - 1);
+        assert!(!entries.iter().any(|e| e.packet == id));
+
+        let report = prune(&root).unwrap();
+        assert_eq!(report.pruned, 0);
+        assert!(report.entries.is_empty());
+    }
+
+    #[test]
+    fn pruning_keeps_entries_with_metadata() {
+        let root = get_log_outpack_root();
+        let before = read_locations(&root).unwrap();
+        assert_eq!(prune(&root).unwrap().pruned, 0);
+        assert_eq!(read_locations(&root).unwrap(), before);
+    }
 }
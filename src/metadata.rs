@@ -1,39 +1,49 @@
 use crate::location::read_locations;
-use crate::utils::is_packet_str;
-use crate::{location, store};
-use cached::cached_result;
+use crate::metadata_store::{self, MetadataStore};
+use crate::responses::{coded_error, ErrorCode};
+use crate::utils::{is_packet_str, utf8_file_name};
+use crate::{location, query, store};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use std::{fs, io};
 
 use super::config;
 use super::hash;
-use super::utils;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PackitPacket {
     pub id: String,
     pub name: String,
     pub parameters: Option<HashMap<String, serde_json::Value>>,
-    pub time: PacketTime,
+    pub time: RawPacketTime,
     pub custom: Option<serde_json::Value>,
 }
 
 impl PackitPacket {
-    fn from(packet: &Packet) -> PackitPacket {
-        PackitPacket {
-            id: packet.id.to_string(),
-            name: packet.name.to_string(),
-            parameters: packet.parameters.clone(),
-            time: packet.time.clone(),
-            custom: packet.custom.clone(),
-        }
+    /// Read the fields needed from a packet's stored metadata, keeping the times exactly as they
+    /// are written there.
+    fn from_stored(data: &[u8]) -> io::Result<PackitPacket> {
+        Ok(serde_json::from_slice(strip_bom(data))?)
     }
 }
 
+/// A packet's times as the text of the numbers, so that they can be served exactly as they are
+/// stored. Parsing a time and serialising it again gives the same number, but not always the same
+/// text: `1503074938` would become `1503074938.0`, for example, which would change the hash of any
+/// metadata re-emitted from it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RawPacketTime {
+    pub start: Box<RawValue>,
+    pub end: Box<RawValue>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Packet {
     pub id: String,
@@ -43,6 +53,10 @@ pub struct Packet {
     pub files: Vec<PacketFile>,
     pub depends: Vec<PacketDependency>,
     pub time: PacketTime,
+    /// Any fields not modelled above, such as `script` or `schema_version`. These are kept so
+    /// that a packet can be re-serialised without losing information.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
 impl PartialEq for Packet {
@@ -84,131 +98,572 @@ pub struct DependencyFile {
     there: String,
 }
 
-cached_result! {
-    METADATA_CACHE: cached::UnboundCache<PathBuf, Packet> = cached::UnboundCache::new();
-    fn read_metadata(path: PathBuf) -> io::Result<Packet> = {
-        let file = fs::File::open(path)?;
-        let packet: Packet = serde_json::from_reader(file)?;
-        Ok(packet)
+lazy_static! {
+    static ref METADATA_CACHE: Mutex<HashMap<(PathBuf, String), Packet>> =
+        Mutex::new(HashMap::new());
+}
+
+static METADATA_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static METADATA_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// The number of metadata reads which were, and were not, served from the cache.
+pub(crate) fn cache_statistics() -> (u64, u64) {
+    (
+        METADATA_CACHE_HITS.load(Ordering::Relaxed),
+        METADATA_CACHE_MISSES.load(Ordering::Relaxed),
+    )
+}
+
+// Metadata is never modified once written, so parsed packets can be cached indefinitely.
+// Failures are not cached.
+fn read_metadata(root: &Path, store: &dyn MetadataStore, id: &str) -> io::Result<Packet> {
+    let key = (root.to_path_buf(), id.to_string());
+    if let Some(packet) = METADATA_CACHE.lock().unwrap().get(&key) {
+        METADATA_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        return Ok(packet.clone());
     }
+    METADATA_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+    let packet = parse_metadata(&read_packet_data(store, id)?)?;
+    METADATA_CACHE.lock().unwrap().insert(key, packet.clone());
+    Ok(packet)
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// Some editors add a byte order mark when saving files, which serde
+// refuses to parse. We only strip it for parsing; the raw contents
+// are left untouched so that hashes still match.
+fn strip_bom(data: &[u8]) -> &[u8] {
+    data.strip_prefix(UTF8_BOM).unwrap_or(data)
 }
 
-fn get_path(root: &Path, id: &str) -> PathBuf {
-    root.join(".outpack").join("metadata").join(id)
+fn parse_metadata(data: &[u8]) -> io::Result<Packet> {
+    let packet: Packet = serde_json::from_slice(strip_bom(data))?;
+    Ok(packet)
 }
 
-fn get_metadata_file(root_path: &Path, id: &str) -> io::Result<PathBuf> {
-    let path = get_path(root_path, id);
-    if !path.exists() {
-        Err(io::Error::new(
+/// Read a packet's metadata as it is stored, failing if there is none.
+fn read_packet_data(store: &dyn MetadataStore, id: &str) -> io::Result<Vec<u8>> {
+    // Ids usually come straight from a request, and the store turns them into paths.
+    if !is_packet_str(id) {
+        return Err(invalid_id_error(id));
+    }
+    store.read(id)?.ok_or_else(|| {
+        coded_error(
             io::ErrorKind::NotFound,
+            ErrorCode::PacketNotFound,
             format!("packet with id '{}' does not exist", id),
-        ))
-    } else {
-        Ok(path)
+        )
+    })
+}
+
+/// The ids of the packets in a store, sorted, leaving out anything which isn't a valid id. Each id
+/// is listed once, even if a packet's metadata is stored both compressed and uncompressed.
+pub(crate) fn packet_ids(store: &dyn MetadataStore) -> io::Result<Vec<String>> {
+    let mut ids = store.ids()?;
+    ids.retain(|id| is_packet_str(id));
+    ids.sort();
+    ids.dedup();
+    Ok(ids)
+}
+
+/// Which of a packet's times a `TimeRange` applies to.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeField {
+    /// When the packet was added to the repository, according to the first location to list it.
+    #[default]
+    Imported,
+    /// When the packet was created, i.e. its `time.start`.
+    Created,
+}
+
+/// A window of times at which packets were added to the repository, or were created, after
+/// `since` and up to and including `until`. Either bound can be left open.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimeRange {
+    pub since: Option<f64>,
+    pub until: Option<f64>,
+    pub field: TimeField,
+}
+
+impl TimeRange {
+    /// The packets added after the given time.
+    pub fn since(time: f64) -> TimeRange {
+        TimeRange {
+            since: Some(time),
+            ..Default::default()
+        }
+    }
+
+    pub fn contains(&self, time: f64) -> bool {
+        self.since.map_or(true, |since| time > since)
+            && self.until.map_or(true, |until| time <= until)
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+}
+
+/// Keep only the packets within the given range. When filtering on when packets were imported,
+/// this is according to the first location to list each of them, and packets which no location
+/// lists are dropped unless the range is unbounded. When filtering on when they were created, the
+/// metadata is read, though not added to the cache.
+fn retain_in_range(
+    root_path: &Path,
+    store: &dyn MetadataStore,
+    ids: &mut Vec<String>,
+    range: TimeRange,
+) -> io::Result<()> {
+    if range.is_unbounded() {
+        return Ok(());
     }
+    let mut times: HashMap<String, f64> = HashMap::new();
+    match range.field {
+        TimeField::Imported => {
+            for entry in read_locations(root_path)? {
+                times.entry(entry.packet).or_insert(entry.time);
+            }
+        }
+        TimeField::Created => {
+            for id in ids.iter() {
+                let packet = parse_metadata(&read_packet_data(store, id)?)?;
+                times.insert(packet.id, packet.time.start);
+            }
+        }
+    }
+    ids.retain(|id| times.get(id).is_some_and(|&t| range.contains(t)));
+    Ok(())
 }
 
+/// The truncated metadata of the packets within the given range of times, sorted by id.
 pub fn get_packit_metadata_from_date(
     root_path: &Path,
-    from: Option<f64>,
+    range: TimeRange,
 ) -> io::Result<Vec<PackitPacket>> {
-    let packets = get_metadata_from_date(root_path, from)?;
-    Ok(packets.iter().map(PackitPacket::from).collect())
-}
-
-pub fn get_metadata_from_date(root_path: &Path, from: Option<f64>) -> io::Result<Vec<Packet>> {
-    let path = root_path.join(".outpack").join("metadata");
-
-    let packets = fs::read_dir(path)?
-        .filter_map(|e| e.ok())
-        .filter(|e| utils::is_packet(&e.file_name()));
-
-    let mut packets = match from {
-        None => packets
-            .map(|entry| read_metadata(entry.path()))
-            .collect::<io::Result<Vec<Packet>>>()?,
-        Some(time) => {
-            let location_meta = read_locations(root_path)?;
-            packets
-                .filter(|entry| {
-                    location_meta
-                        .iter()
-                        .find(|&e| e.packet == entry.file_name().into_string().unwrap())
-                        .map_or(false, |e| e.time > time)
-                })
-                .map(|entry| read_metadata(entry.path()))
-                .collect::<io::Result<Vec<Packet>>>()?
-        }
-    };
+    iter_packit_metadata_from_date(root_path, range)?.collect()
+}
+
+/// The truncated metadata of the packets matching a query, ordered by id.
+pub fn search_packit_metadata(root_path: &Path, query: &str) -> io::Result<Vec<PackitPacket>> {
+    let mut ids = query::query_ids(root_path, query).map_err(query_error)?;
+    ids.sort();
+    let store = metadata_store::open(root_path)?;
+    ids.iter()
+        .map(|id| PackitPacket::from_stored(&read_packet_data(store.as_ref(), id)?))
+        .collect()
+}
+
+fn query_error(err: query::QueryError) -> io::Error {
+    coded_error(
+        io::ErrorKind::InvalidInput,
+        ErrorCode::InvalidQuery,
+        err.to_string(),
+    )
+}
+
+/// Like `get_packit_metadata_from_date`, but packets are read one at a time as the iterator is
+/// advanced, and are not added to the metadata cache, so that they can be streamed to a client
+/// without holding all of them in memory. Which packets are included, and their order, is decided
+/// before any are read.
+pub fn iter_packit_metadata_from_date(
+    root_path: &Path,
+    range: TimeRange,
+) -> io::Result<impl Iterator<Item = io::Result<PackitPacket>>> {
+    let store = metadata_store::open(root_path)?;
+    let mut ids = packet_ids(store.as_ref())?;
+    retain_in_range(root_path, store.as_ref(), &mut ids, range)?;
+    ids.sort();
+    Ok(ids.into_iter().map(move |id| {
+        let data = read_packet_data(store.as_ref(), &id)?;
+        PackitPacket::from_stored(&data)
+    }))
+}
+
+/// A packet's metadata exactly as it is stored, so that it can be checked against its hash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RawMetadata {
+    pub id: String,
+    /// The hash recorded by the first location to list the packet, if any does.
+    pub hash: Option<String>,
+    pub metadata: String,
+}
+
+/// Iterate over the stored metadata of every packet, ordered by id. As with
+/// `iter_packit_metadata_from_date`, packets are read one at a time as the iterator is advanced.
+pub fn iter_raw_metadata(
+    root_path: &Path,
+) -> io::Result<impl Iterator<Item = io::Result<RawMetadata>>> {
+    let store = metadata_store::open(root_path)?;
+    let ids = packet_ids(store.as_ref())?;
+    let mut hashes: HashMap<String, String> = HashMap::new();
+    for entry in read_locations(root_path)? {
+        hashes.entry(entry.packet).or_insert(entry.hash);
+    }
+    Ok(ids.into_iter().map(move |id| {
+        let data = read_packet_data(store.as_ref(), &id)?;
+        let metadata =
+            String::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(RawMetadata {
+            hash: hashes.remove(&id),
+            id,
+            metadata,
+        })
+    }))
+}
+
+/// The packets within the given range of times, sorted by id.
+pub fn get_metadata_from_date(root_path: &Path, range: TimeRange) -> io::Result<Vec<Packet>> {
+    let store = metadata_store::open(root_path)?;
+    let mut ids = packet_ids(store.as_ref())?;
+    retain_in_range(root_path, store.as_ref(), &mut ids, range)?;
+
+    let mut packets = ids
+        .iter()
+        .map(|id| read_metadata(root_path, store.as_ref(), id))
+        .collect::<io::Result<Vec<Packet>>>()?;
 
     packets.sort_by(|a, b| a.id.cmp(&b.id));
     Ok(packets)
 }
 
+/// Lazily iterate over all the packets in the repository.
+///
+/// Unlike `get_metadata_from_date`, packets are read one at a time as the iterator is advanced,
+/// and are not added to the metadata cache, so this is suitable for processing large
+/// repositories. Packets are yielded in the order the store lists them, not sorted by id. An
+/// error listing the packets or parsing one is yielded in place of that packet, and iteration can
+/// continue past it.
+pub fn iter_packets(root_path: &Path) -> impl Iterator<Item = io::Result<Packet>> {
+    let listed =
+        metadata_store::open(root_path).and_then(|store| Ok((packet_ids(store.as_ref())?, store)));
+    let (packets, error) = match listed {
+        Ok((ids, store)) => (
+            Some(ids.into_iter().map(move |id| {
+                read_packet_data(store.as_ref(), &id).and_then(|data| parse_metadata(&data))
+            })),
+            None,
+        ),
+        Err(e) => (None, Some(Err(e))),
+    };
+    error.into_iter().chain(packets.into_iter().flatten())
+}
+
 pub fn get_metadata_by_id(root_path: &Path, id: &str) -> io::Result<serde_json::Value> {
-    let path = get_metadata_file(root_path, id)?;
-    let file = fs::File::open(path)?;
-    let packet = serde_json::from_reader(file)?;
+    let data = read_packet_data(metadata_store::open(root_path)?.as_ref(), id)?;
+    let packet = serde_json::from_slice(strip_bom(&data))?;
     Ok(packet)
 }
 
+/// Read and parse a packet's metadata, bypassing the metadata cache.
+pub fn get_packet(root_path: &Path, id: &str) -> io::Result<Packet> {
+    read_packet(metadata_store::open(root_path)?.as_ref(), id)
+}
+
+/// As for `get_packet`, but from a store which is already open, so that reading many packets
+/// doesn't read the repository's configuration again for each one.
+pub(crate) fn read_packet(store: &dyn MetadataStore, id: &str) -> io::Result<Packet> {
+    parse_metadata(&read_packet_data(store, id)?)
+}
+
 pub fn get_metadata_text(root_path: &Path, id: &str) -> io::Result<String> {
-    let path = get_metadata_file(root_path, id)?;
-    fs::read_to_string(path)
+    let data = read_packet_data(metadata_store::open(root_path)?.as_ref(), id)?;
+    String::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
+/// The string hashed by `get_ids_digest`, which no longer builds it in full.
+#[cfg(test)]
 fn get_sorted_id_string(mut ids: Vec<String>) -> String {
     ids.sort();
     ids.join("")
 }
 
-pub fn get_ids_digest(root_path: &Path, alg_name: Option<String>) -> io::Result<String> {
+/// Hash the sorted ids of all the packets in the repository, or only of those matching a query,
+/// so that two repositories can cheaply check whether they know of the same packets.
+pub fn get_ids_digest(
+    root_path: &Path,
+    index: &IdIndex,
+    alg_name: Option<String>,
+    query: Option<&str>,
+) -> io::Result<String> {
     let hash_algorithm = match alg_name {
         None => config::read_config(root_path)?.core.hash_algorithm,
         Some(name) => hash::HashAlgorithm::from_str(&name).map_err(hash::hash_error_to_io_error)?,
     };
 
-    let ids = get_ids(root_path, false)?;
-    let id_string = get_sorted_id_string(ids);
-    Ok(hash::hash_data(id_string.as_bytes(), hash_algorithm).to_string())
+    let mut ids = index.ids(false)?;
+    if let Some(query) = query {
+        let matching: HashSet<String> = query::query_ids(root_path, query)
+            .map_err(query_error)?
+            .into_iter()
+            .collect();
+        ids.retain(|id| matching.contains(id));
+    }
+    // The digest is of the sorted ids concatenated together, but the ids are fed to the hasher
+    // one by one, as joining them could need a large allocation for big repositories.
+    ids.sort();
+    Ok(hash::hash_chunks(&ids, hash_algorithm).to_string())
 }
 
+/// List the ids of the packets in the repository, or only of those which are unpacked.
 pub fn get_ids(root_path: &Path, unpacked: bool) -> io::Result<Vec<String>> {
-    let path = root_path.join(".outpack");
-    let path = if unpacked {
-        path.join("location").join("local")
-    } else {
-        path.join("metadata")
-    };
+    if !unpacked {
+        return metadata_store::open(root_path)?.ids();
+    }
+    let path = root_path
+        .join(".outpack")
+        .join("location")
+        .join(config::LOCAL_LOCATION);
     Ok(fs::read_dir(path)?
         .filter_map(|r| r.ok())
-        .map(|e| e.file_name().into_string())
-        .filter_map(|r| r.ok())
+        .filter_map(|e| Some(utf8_file_name(&e.file_name(), &e.path())?.to_string()))
         .collect::<Vec<String>>())
 }
 
-pub fn get_valid_id(id: &String) -> io::Result<String> {
+/// The ids of the packets in a repository, and of those which are unpacked, so that requests don't
+/// each need to list the metadata directory. The ids are listed the first time they are needed,
+/// after which packets added through the server must be recorded with `add`. Clones share the
+/// same index.
+#[derive(Clone)]
+pub struct IdIndex {
+    root: PathBuf,
+    ids: Arc<Mutex<Option<IndexedIds>>>,
+}
+
+struct IndexedIds {
+    all: HashSet<String>,
+    unpacked: HashSet<String>,
+}
+
+impl IdIndex {
+    pub fn new(root: &Path) -> IdIndex {
+        IdIndex {
+            root: root.to_path_buf(),
+            ids: Arc::default(),
+        }
+    }
+
+    /// The ids of all the packets, or only of those which are unpacked, in no particular order.
+    pub fn ids(&self, unpacked: bool) -> io::Result<Vec<String>> {
+        let mut guard = self.ids.lock().unwrap();
+        let ids = match guard.as_mut() {
+            Some(ids) => ids,
+            None => guard.insert(IndexedIds {
+                all: get_ids(&self.root, false)?.into_iter().collect(),
+                unpacked: get_ids(&self.root, true)?.into_iter().collect(),
+            }),
+        };
+        let ids = if unpacked { &ids.unpacked } else { &ids.all };
+        Ok(ids.iter().cloned().collect())
+    }
+
+    /// Record a packet which has been added to the repository, and whether it is unpacked.
+    pub fn add(&self, id: &str, unpacked: bool) {
+        if let Some(ids) = self.ids.lock().unwrap().as_mut() {
+            ids.all.insert(id.to_string());
+            if unpacked {
+                ids.unpacked.insert(id.to_string());
+            }
+        }
+    }
+
+    /// Forget the ids, so that they are listed again when next needed. This is for changes which
+    /// can remove packets, which `add` can't record.
+    pub fn invalidate(&self) {
+        *self.ids.lock().unwrap() = None;
+    }
+}
+
+pub fn get_valid_id(id: &str) -> io::Result<String> {
     let s = id.trim().to_string();
     if is_packet_str(&s) {
         Ok(s)
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("Invalid packet id '{}'", id),
-        ))
+        Err(invalid_id_error(id))
     }
 }
 
-pub fn get_missing_ids(root: &Path, wanted: &[String], unpacked: bool) -> io::Result<Vec<String>> {
-    let known: HashSet<String> = get_ids(root, unpacked)?.into_iter().collect();
-    let wanted: HashSet<String> = wanted
-        .iter()
-        .map(get_valid_id)
-        .collect::<io::Result<HashSet<String>>>()?;
+fn invalid_id_error(id: &str) -> io::Error {
+    coded_error(
+        io::ErrorKind::InvalidInput,
+        ErrorCode::InvalidPacketId,
+        format!("Invalid packet id '{}'", id),
+    )
+}
+
+/// The id of a packet, given its metadata.
+pub fn packet_id(data: &str) -> io::Result<String> {
+    let packet: Packet = serde_json::from_str(data)?;
+    Ok(packet.id)
+}
+
+/// Check whether metadata for a packet is present, without reading it.
+pub fn packet_exists(root: &Path, id: &str) -> io::Result<bool> {
+    let id = get_valid_id(id)?;
+    metadata_store::open(root)?.contains(&id)
+}
+
+/// The files absent from the store for each of a set of packets.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MissingPacketFiles {
+    /// For each known packet, the hashes of its files which are not in the store.
+    pub missing: BTreeMap<String, Vec<String>>,
+    /// Requested packets for which there is no metadata.
+    pub unknown: Vec<String>,
+}
+
+pub fn get_missing_packet_files(root: &Path, ids: &[String]) -> io::Result<MissingPacketFiles> {
+    let mut result = MissingPacketFiles {
+        missing: BTreeMap::new(),
+        unknown: Vec::new(),
+    };
+    let store = metadata_store::open(root)?;
+    let layout = store::layout(root)?;
+    for id in ids {
+        let id = get_valid_id(id)?;
+        if !store.contains(&id)? {
+            result.unknown.push(id);
+            continue;
+        }
+        let packet = read_metadata(root, store.as_ref(), &id)?;
+        let hashes: Vec<String> = packet.files.into_iter().map(|f| f.hash).collect();
+        let missing = store::get_missing_files(root, layout, &hashes)?;
+        result.missing.insert(id, missing);
+    }
+    Ok(result)
+}
+
+fn get_valid_id_set(ids: &[String]) -> io::Result<HashSet<String>> {
+    ids.iter()
+        .map(|id| get_valid_id(id))
+        .collect::<io::Result<HashSet<String>>>()
+}
+
+/// Separate the valid ids from the invalid ones, giving an error for each of the latter, for bulk
+/// requests which tolerate invalid ids. The valid ids are trimmed, as by `get_valid_id`.
+pub fn partition_valid_ids(ids: &[String]) -> (Vec<String>, Vec<io::Error>) {
+    let mut valid = Vec::new();
+    let mut errors = Vec::new();
+    for id in ids {
+        match get_valid_id(id) {
+            Ok(id) => valid.push(id),
+            Err(e) => errors.push(e),
+        }
+    }
+    (valid, errors)
+}
+
+pub fn get_missing_ids(
+    index: &IdIndex,
+    wanted: &[String],
+    unpacked: bool,
+) -> io::Result<Vec<String>> {
+    let known: HashSet<String> = index.ids(unpacked)?.into_iter().collect();
+    let wanted = get_valid_id_set(wanted)?;
     Ok(wanted.difference(&known).cloned().collect::<Vec<String>>())
 }
 
+/// The converse of `get_missing_ids`: the packets in the repository which are not among the
+/// given ids, sorted by id. A client can use this to find which packets it needs to fetch.
+pub fn get_unknown_ids(
+    index: &IdIndex,
+    known: &[String],
+    unpacked: bool,
+) -> io::Result<Vec<String>> {
+    let present: HashSet<String> = index.ids(unpacked)?.into_iter().collect();
+    let known = get_valid_id_set(known)?;
+    let mut ids = present.difference(&known).cloned().collect::<Vec<String>>();
+    ids.sort();
+    Ok(ids)
+}
+
+/// A file belonging to a packet, along with whether it is available in the local file store.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PacketFileStatus {
+    pub path: String,
+    pub hash: String,
+    pub size: usize,
+    pub present: bool,
+}
+
+/// List the files of a packet, reporting for each one whether it can be downloaded from the
+/// file store.
+pub fn get_packet_files(root: &Path, id: &str) -> io::Result<Vec<PacketFileStatus>> {
+    let packet = read_metadata(root, metadata_store::open(root)?.as_ref(), id)?;
+    let layout = store::layout(root)?;
+    packet
+        .files
+        .into_iter()
+        .map(|f| {
+            let present = store::file_exists(root, layout, &f.hash)?;
+            Ok(PacketFileStatus {
+                path: f.path,
+                hash: f.hash,
+                size: f.size,
+                present,
+            })
+        })
+        .collect()
+}
+
+/// Find the hash of the file at the given path within a packet.
+pub fn get_packet_file_hash(root: &Path, id: &str, path: &str) -> io::Result<String> {
+    let packet = read_metadata(root, metadata_store::open(root)?.as_ref(), id)?;
+    packet
+        .files
+        .into_iter()
+        .find(|f| f.path == path)
+        .map(|f| f.hash)
+        .ok_or_else(|| {
+            coded_error(
+                io::ErrorKind::NotFound,
+                ErrorCode::FileNotFound,
+                format!("packet '{}' does not contain file '{}'", id, path),
+            )
+        })
+}
+
+/// A file hash that is referenced by more than one packet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DuplicateFile {
+    pub hash: String,
+    pub count: usize,
+    pub packets: Vec<String>,
+}
+
+/// Find all the file hashes that are referenced by more than one packet.
+///
+/// A packet that references the same hash multiple times only counts once. The result is sorted
+/// by hash, and the packet ids for each hash are sorted too.
+pub fn get_duplicate_files(root: &Path) -> io::Result<Vec<DuplicateFile>> {
+    let mut references: HashMap<String, Vec<String>> = HashMap::new();
+    for packet in get_metadata_from_date(root, TimeRange::default())? {
+        let hashes: HashSet<&String> = packet.files.iter().map(|f| &f.hash).collect();
+        for hash in hashes {
+            references
+                .entry(hash.clone())
+                .or_default()
+                .push(packet.id.clone());
+        }
+    }
+
+    let mut duplicates: Vec<DuplicateFile> = references
+        .into_iter()
+        .filter(|(_, packets)| packets.len() > 1)
+        .map(|(hash, mut packets)| {
+            packets.sort();
+            DuplicateFile {
+                hash,
+                count: packets.len(),
+                packets,
+            }
+        })
+        .collect();
+    duplicates.sort_by(|a, b| a.hash.cmp(&b.hash));
+    Ok(duplicates)
+}
+
 fn check_missing_files(root: &Path, packet: &Packet) -> Result<(), io::Error> {
     let files = packet
         .files
@@ -216,10 +671,11 @@ fn check_missing_files(root: &Path, packet: &Packet) -> Result<(), io::Error> {
         .map(|f| f.hash.clone())
         .collect::<Vec<String>>();
 
-    let missing_files = store::get_missing_files(root, &files)?;
+    let missing_files = store::get_missing_files(root, store::layout(root)?, &files)?;
     if !missing_files.is_empty() {
-        return Err(io::Error::new(
+        return Err(coded_error(
             io::ErrorKind::InvalidInput,
+            ErrorCode::MissingFiles,
             format!(
                 "Can't import metadata for {}, as files missing: \n {}",
                 packet.id,
@@ -237,10 +693,11 @@ fn check_missing_dependencies(root: &Path, packet: &Packet) -> Result<(), io::Er
         .map(|d| d.packet.clone())
         .collect::<Vec<String>>();
 
-    let missing_packets = get_missing_ids(root, &deps, true)?;
+    let missing_packets = get_missing_ids(&IdIndex::new(root), &deps, true)?;
     if !missing_packets.is_empty() {
-        return Err(io::Error::new(
+        return Err(coded_error(
             io::ErrorKind::InvalidInput,
+            ErrorCode::MissingDependencies,
             format!(
                 "Can't import metadata for {}, as dependencies missing: \n {}",
                 packet.id,
@@ -251,12 +708,26 @@ fn check_missing_dependencies(root: &Path, packet: &Packet) -> Result<(), io::Er
     Ok(())
 }
 
+fn conflicting_metadata_error(id: &str) -> io::Error {
+    coded_error(
+        io::ErrorKind::AlreadyExists,
+        ErrorCode::PacketConflict,
+        format!(
+            "packet with id '{}' already exists with different metadata",
+            id
+        ),
+    )
+}
+
 fn add_parsed_metadata(root: &Path, data: &str, packet: &Packet, hash: &str) -> io::Result<()> {
     hash::validate_hash_data(data.as_bytes(), hash).map_err(hash::hash_error_to_io_error)?;
-    let path = get_path(root, &packet.id);
-    if !path.exists() {
-        fs::File::create(&path)?;
-        fs::write(path, data)?;
+    let store = metadata_store::open(root)?;
+    match store.read(&packet.id)? {
+        None => store.add(&packet.id, hash, data)?,
+        Some(existing) if existing != data.as_bytes() => {
+            return Err(conflicting_metadata_error(&packet.id));
+        }
+        Some(_) => {}
     }
     Ok(())
 }
@@ -268,39 +739,264 @@ pub fn add_metadata(root: &Path, data: &str, hash: &hash::Hash) -> io::Result<()
     add_parsed_metadata(root, data, &packet, &hash.to_string())
 }
 
-/// Add a packet to the repository.
+/// Check that a packet could be imported into the local location by `add_packet`, without
+/// writing anything, returning its id. Fails with the same errors that `add_packet` would.
+pub fn check_packet(root: &Path, data: &str, hash: &hash::Hash) -> io::Result<String> {
+    let packet: Packet = serde_json::from_str(data)?;
+    check_missing_files(root, &packet)?;
+    check_missing_dependencies(root, &packet)?;
+    hash::validate_hash_data(data.as_bytes(), &hash.to_string())
+        .map_err(hash::hash_error_to_io_error)?;
+    if let Some(existing) = metadata_store::open(root)?.read(&packet.id)? {
+        if existing != data.as_bytes() {
+            return Err(conflicting_metadata_error(&packet.id));
+        }
+    }
+    Ok(packet.id)
+}
+
+/// Re-register a packet in the local location, with the current time and the hash of its
+/// metadata as stored, replacing any existing entry. This repairs packets whose location entry
+/// has been lost, and which are therefore missing from listings that filter by location.
+///
+/// As for `add_packet`, all of the packet's files must be in the repository.
+pub fn touch_packet(root: &Path, id: &str) -> io::Result<location::LocationEntry> {
+    let id = get_valid_id(id)?;
+    let data = read_packet_data(metadata_store::open(root)?.as_ref(), &id)?;
+    let packet = parse_metadata(&data)?;
+    check_missing_files(root, &packet)?;
+
+    let algorithm = config::read_config(root)?.core.hash_algorithm;
+    let hash = hash::hash_data(&data, algorithm).to_string();
+    let entry = location::replace_packet_entry(
+        &id,
+        config::LOCAL_LOCATION,
+        &hash,
+        SystemTime::now(),
+        root,
+    )?;
+    Ok(entry)
+}
+
+/// Add a packet to the repository, recording that it was obtained from the given location.
 ///
-/// The packet's files and dependencies must already be present in the repository.
-pub fn add_packet(root: &Path, data: &str, hash: &hash::Hash) -> io::Result<()> {
+/// The packet's files and dependencies must already be present in the repository. The location
+/// is either `config::LOCAL_LOCATION` or the name of a location configured in the repository.
+pub fn add_packet(root: &Path, data: &str, hash: &hash::Hash, location: &str) -> io::Result<()> {
     let packet: Packet = serde_json::from_str(data)?;
     let hash_str = hash.to_string();
 
+    if location != config::LOCAL_LOCATION
+        && !config::read_config(root)?
+            .location
+            .iter()
+            .any(|l| l.name == location)
+    {
+        return Err(coded_error(
+            io::ErrorKind::NotFound,
+            ErrorCode::LocationNotFound,
+            format!("location '{}' does not exist", location),
+        ));
+    }
+
     check_missing_files(root, &packet)?;
     check_missing_dependencies(root, &packet)?;
 
     add_parsed_metadata(root, data, &packet, &hash.to_string())?;
 
     let time = SystemTime::now();
-    location::mark_packet_known(&packet.id, "local", &hash_str, time, root)?;
+    location::mark_packet_known(&packet.id, location, &hash_str, time, root)?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::FileStoreLayout;
+    use crate::metadata_store::{get_compressed_path, get_path, DirectoryMetadataStore};
     use crate::store::file_exists;
-    use crate::test_utils::tests::{get_temp_outpack_root, start_packet};
+    use crate::test_utils::tests::{
+        get_empty_outpack_root, get_log_outpack_root, get_temp_outpack_root, start_packet,
+        MemoryStorage,
+    };
     use crate::utils::time_as_num;
+    use flate2::write::GzEncoder;
     use md5::Md5;
     use serde_json::Value;
     use sha2::{Digest, Sha256};
+    use std::io::Write;
+
+    #[test]
+    fn packet_round_trip_preserves_unknown_fields() {
+        let data = serde_json::json!({
+            "schema_version": "0.1.1",
+            "id": "20230427-150828-68772cee",
+            "name": "data",
+            "custom": null,
+            "parameters": null,
+            "files": [],
+            "depends": [],
+            "time": { "start": 1682607306.0, "end": 1682607306.5 },
+            "script": ["orderly.R"]
+        });
+        let packet: Packet = serde_json::from_value(data.clone()).unwrap();
+        assert_eq!(packet.extra["script"], serde_json::json!(["orderly.R"]));
+        assert_eq!(packet.extra["schema_version"], "0.1.1");
+
+        let serialised = serde_json::to_value(&packet).unwrap();
+        assert_eq!(serialised, data);
+    }
+
+    #[test]
+    fn can_check_packet_exists() {
+        let root = Path::new("tests/example");
+        let exists = |id: &str| packet_exists(root, &String::from(id));
+        assert!(exists("20170818-164847-7574883b").unwrap());
+        assert!(!exists("20170818-164043-7cdcde4b").unwrap());
+        assert_eq!(
+            exists("not-an-id").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn can_get_missing_packet_files() {
+        let root = get_empty_outpack_root();
+        let data = b"Testing 123.";
+        let present = hash::hash_data(data, hash::HashAlgorithm::Sha256).to_string();
+        store::put_file(&root, data, &present).unwrap();
+        let absent = hash::hash_data(b"Absent", hash::HashAlgorithm::Sha256).to_string();
+
+        let (id, packet, hash) = start_packet("data")
+            .add_file("present.txt", &present, data.len())
+            .add_file("absent.txt", &absent, 6)
+            .finish();
+        add_metadata(&root, &packet, &hash).unwrap();
+
+        let unknown = String::from("20230427-150828-68772cee");
+        let result = get_missing_packet_files(&root, &[id.clone(), unknown.clone()]).unwrap();
+        assert_eq!(result.missing, BTreeMap::from([(id, vec![absent])]));
+        assert_eq!(result.unknown, vec![unknown]);
+
+        let res = get_missing_packet_files(&root, &[String::from("bad-id")]);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn can_read_metadata_from_storage() {
+        let (id, contents, _) = start_packet("memory").finish();
+        let root = Path::new("memory-storage");
+        let mut storage = MemoryStorage::default();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(contents.as_bytes()).unwrap();
+        storage.add_file(get_compressed_path(root, &id), encoder.finish().unwrap());
+
+        let store = DirectoryMetadataStore::new(&storage, root, false);
+        assert_eq!(read_metadata(root, &store, &id).unwrap().id, id);
+
+        let err = read_metadata(root, &store, "20170818-164847-00000000").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn metadata_read_failures_are_reported() {
+        let (id, contents, _) = start_packet("memory").finish();
+        let root = Path::new("memory-storage");
+        let mut storage = MemoryStorage::default();
+        storage.add_failing_file(get_path(root, &id), io::ErrorKind::PermissionDenied);
+        let err = read_metadata(
+            root,
+            &DirectoryMetadataStore::new(&storage, root, false),
+            &id,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        // A truncated file, as left by an interrupted write, is reported as invalid.
+        storage.add_file(
+            get_path(root, &id),
+            &contents.as_bytes()[..contents.len() / 2],
+        );
+        let err = read_metadata(
+            root,
+            &DirectoryMetadataStore::new(&storage, root, false),
+            &id,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn can_read_metadata_with_bom_and_trailing_newline() {
+        let root = get_temp_outpack_root();
+        let id = "20170818-164847-7574883b";
+        let path = root.join(".outpack").join("metadata").join(id);
+        let original = fs::read(&path).unwrap();
+        let mut contents = UTF8_BOM.to_vec();
+        contents.extend_from_slice(&original);
+        contents.push(b'\n');
+        fs::write(&path, &contents).unwrap();
+
+        let packet = parse_metadata(&contents).unwrap();
+        assert_eq!(packet.id, id);
+
+        let value = get_metadata_by_id(&root, id).unwrap();
+        assert_eq!(value["id"], id);
+
+        let text = get_metadata_text(&root, id).unwrap();
+        assert_eq!(text.as_bytes(), contents.as_slice());
+    }
+
+    #[test]
+    fn can_iterate_over_packets() {
+        let mut ids = iter_packets(Path::new("tests/example"))
+            .map(|p| p.unwrap().id)
+            .collect::<Vec<String>>();
+        ids.sort();
+        let expected = get_metadata_from_date(Path::new("tests/example"), TimeRange::default())
+            .unwrap()
+            .into_iter()
+            .map(|p| p.id)
+            .collect::<Vec<String>>();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn iterating_over_packets_surfaces_parse_errors() {
+        let root = get_temp_outpack_root();
+        let corrupt = root
+            .join(".outpack")
+            .join("metadata")
+            .join("20240101-000000-00000000");
+        fs::write(corrupt, "{ not json").unwrap();
+
+        let results = iter_packets(&root).collect::<Vec<io::Result<Packet>>>();
+        assert_eq!(results.len(), 5);
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 4);
+        let err = results.into_iter().find_map(|r| r.err()).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn iterating_over_missing_metadata_yields_error() {
+        let root = tempfile::TempDir::new().unwrap();
+        let results = iter_packets(root.path()).collect::<Vec<io::Result<Packet>>>();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
 
     #[test]
     fn can_get_packets_from_date() {
-        let all_packets = get_metadata_from_date(Path::new("tests/example"), None).unwrap();
+        let all_packets =
+            get_metadata_from_date(Path::new("tests/example"), TimeRange::default()).unwrap();
         assert_eq!(all_packets.len(), 4);
         let recent_packets =
-            get_metadata_from_date(Path::new("tests/example"), Some(1662480556.)).unwrap();
+            get_metadata_from_date(Path::new("tests/example"), TimeRange::since(1662480556.))
+                .unwrap();
         assert_eq!(recent_packets.len(), 1);
         assert_eq!(
             recent_packets.first().unwrap().id,
@@ -308,7 +1004,8 @@ mod tests {
         );
 
         let recent_packets =
-            get_metadata_from_date(Path::new("tests/example"), Some(1662480555.)).unwrap();
+            get_metadata_from_date(Path::new("tests/example"), TimeRange::since(1662480555.))
+                .unwrap();
         assert_eq!(recent_packets.len(), 4);
     }
 
@@ -336,22 +1033,70 @@ mod tests {
 
     #[test]
     fn can_get_ids_digest_with_config_alg() {
-        let digest = get_ids_digest(Path::new("tests/example"), None).unwrap();
+        let digest = get_ids_digest(
+            Path::new("tests/example"),
+            &IdIndex::new(Path::new("tests/example")),
+            None,
+            None,
+        )
+        .unwrap();
         let dat = "20170818-164830-33e0ab0120170818-164847-7574883b20180220-095832-16a4bbed\
         20180818-164043-7cdcde4b";
         let expected = format!("sha256:{:x}", Sha256::digest(dat));
         assert_eq!(digest, expected);
     }
 
+    #[test]
+    fn ids_digest_is_of_sorted_id_string() {
+        let root = Path::new("tests/example");
+        let id_string = get_sorted_id_string(get_ids(root, false).unwrap());
+        for alg in [hash::HashAlgorithm::Sha256, hash::HashAlgorithm::Md5] {
+            let expected = hash::hash_data(id_string.as_bytes(), alg).to_string();
+            let digest =
+                get_ids_digest(root, &IdIndex::new(root), Some(alg.to_string()), None).unwrap();
+            assert_eq!(digest, expected);
+        }
+    }
+
     #[test]
     fn can_get_ids_digest_with_given_alg() {
-        let digest = get_ids_digest(Path::new("tests/example"), Some(String::from("md5"))).unwrap();
+        let digest = get_ids_digest(
+            Path::new("tests/example"),
+            &IdIndex::new(Path::new("tests/example")),
+            Some(String::from("md5")),
+            None,
+        )
+        .unwrap();
         let dat = "20170818-164830-33e0ab0120170818-164847-7574883b20180220-095832-16a4bbed\
         20180818-164043-7cdcde4b";
         let expected = format!("md5:{:x}", Md5::digest(dat));
         assert_eq!(digest, expected);
     }
 
+    #[test]
+    fn can_get_ids_digest_of_query() {
+        let root = Path::new("tests/example");
+        let digest = get_ids_digest(
+            root,
+            &IdIndex::new(root),
+            None,
+            Some(r#"parameter:disease == "YF""#),
+        )
+        .unwrap();
+        let dat = "20170818-164830-33e0ab0120180220-095832-16a4bbed20180818-164043-7cdcde4b";
+        assert_eq!(digest, format!("sha256:{:x}", Sha256::digest(dat)));
+
+        let digest = get_ids_digest(root, &IdIndex::new(root), None, Some("latest")).unwrap();
+        assert_eq!(
+            digest,
+            format!("sha256:{:x}", Sha256::digest("20180818-164043-7cdcde4b"))
+        );
+
+        let err = get_ids_digest(root, &IdIndex::new(root), None, Some("nonsense")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("Failed to parse query"));
+    }
+
     #[test]
     fn can_get_ids() {
         let ids = get_ids(Path::new("tests/example"), false).unwrap();
@@ -372,7 +1117,7 @@ mod tests {
     #[test]
     fn can_get_missing_ids() {
         let ids = get_missing_ids(
-            Path::new("tests/example"),
+            &IdIndex::new(Path::new("tests/example")),
             &[
                 "20180818-164043-7cdcde4b".to_string(),
                 "20170818-164830-33e0ab02".to_string(),
@@ -385,7 +1130,7 @@ mod tests {
 
         // check whitespace insensitivity
         let ids = get_missing_ids(
-            Path::new("tests/example"),
+            &IdIndex::new(Path::new("tests/example")),
             &[
                 "20180818-164043-7cdcde4b".to_string(),
                 "20170818-164830-33e0ab02".to_string(),
@@ -397,10 +1142,35 @@ mod tests {
         assert!(ids.iter().any(|e| e == "20170818-164830-33e0ab02"));
     }
 
+    #[test]
+    fn can_get_unknown_ids() {
+        let root = Path::new("tests/example");
+        let ids = get_unknown_ids(
+            &IdIndex::new(root),
+            &[
+                "20180818-164043-7cdcde4b".to_string(),
+                " 20170818-164830-33e0ab01".to_string(),
+                "20170818-164830-33e0ab02".to_string(),
+            ],
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            ids,
+            vec!["20170818-164847-7574883b", "20180220-095832-16a4bbed"]
+        );
+
+        let ids = get_unknown_ids(&IdIndex::new(root), &[], true).unwrap();
+        assert_eq!(ids, get_ids(root, true).unwrap());
+
+        let res = get_unknown_ids(&IdIndex::new(root), &["bad-id".to_string()], false);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
     #[test]
     fn can_get_missing_unpacked_ids() {
         let ids = get_missing_ids(
-            Path::new("tests/example"),
+            &IdIndex::new(Path::new("tests/example")),
             &[
                 "20170818-164847-7574883b".to_string(),
                 "20170818-164830-33e0ab02".to_string(),
@@ -415,7 +1185,7 @@ mod tests {
     #[test]
     fn bad_ids_raise_error() {
         let res = get_missing_ids(
-            Path::new("tests/example"),
+            &IdIndex::new(Path::new("tests/example")),
             &[
                 "20180818-164043-7cdcde4b".to_string(),
                 "20170818-164830-33e0ab0".to_string(),
@@ -453,7 +1223,7 @@ mod tests {
                             }"#;
         let hash = hash::hash_data(data.as_bytes(), hash::HashAlgorithm::Sha256);
         let root = get_temp_outpack_root();
-        add_packet(&root, data, &hash).unwrap();
+        add_packet(&root, data, &hash, config::LOCAL_LOCATION).unwrap();
         let packet = get_metadata_by_id(&root, "20230427-150828-68772cee").unwrap();
         let expected: Value = serde_json::from_str(data).unwrap();
         assert_eq!(packet, expected);
@@ -478,11 +1248,420 @@ mod tests {
                             }"#;
         let hash = hash::hash_data(data.as_bytes(), hash::HashAlgorithm::Sha256);
         let root = get_temp_outpack_root();
-        add_packet(&root, data, &hash).unwrap();
+        add_packet(&root, data, &hash, config::LOCAL_LOCATION).unwrap();
         let packet = get_metadata_by_id(&root, "20230427-150828-68772cee").unwrap();
         let expected: Value = serde_json::from_str(data).unwrap();
         assert_eq!(packet, expected);
-        add_packet(&root, data, &hash).unwrap();
+        add_packet(&root, data, &hash, config::LOCAL_LOCATION).unwrap();
+    }
+
+    #[test]
+    fn can_get_packets_from_time_range() {
+        let ids_in = |since: Option<f64>, until: Option<f64>| -> Vec<String> {
+            let range = TimeRange {
+                since,
+                until,
+                ..Default::default()
+            };
+            get_metadata_from_date(Path::new("tests/example"), range)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.id)
+                .collect()
+        };
+
+        assert_eq!(
+            ids_in(Some(1662480555.7), Some(1662480556.)),
+            vec!["20170818-164830-33e0ab01"]
+        );
+        assert_eq!(
+            ids_in(Some(1662480555.7), None),
+            vec!["20170818-164830-33e0ab01", "20170818-164847-7574883b"]
+        );
+        // The upper bound is inclusive.
+        assert_eq!(
+            ids_in(None, Some(1662480555.8897)),
+            vec![
+                "20170818-164830-33e0ab01",
+                "20180220-095832-16a4bbed",
+                "20180818-164043-7cdcde4b"
+            ]
+        );
+        assert!(ids_in(Some(1662480556.2), Some(1662480557.)).is_empty());
+        assert!(ids_in(Some(1662480556.), Some(1662480555.)).is_empty());
+    }
+
+    #[test]
+    fn can_partition_valid_ids() {
+        let ids = [
+            String::from(" 20170818-164830-33e0ab01"),
+            String::from("bad-id"),
+            String::from("20180818-164043-7cdcde4b"),
+            String::from("../etc"),
+        ];
+        let (valid, errors) = partition_valid_ids(&ids);
+        assert_eq!(
+            valid,
+            vec!["20170818-164830-33e0ab01", "20180818-164043-7cdcde4b"]
+        );
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec!["Invalid packet id 'bad-id'", "Invalid packet id '../etc'"]
+        );
+    }
+
+    #[test]
+    fn can_iterate_raw_metadata() {
+        let root = Path::new("tests/example");
+        let packets: Vec<RawMetadata> = iter_raw_metadata(root)
+            .unwrap()
+            .map(|p| p.unwrap())
+            .collect();
+        let ids: Vec<&str> = packets.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                "20170818-164830-33e0ab01",
+                "20170818-164847-7574883b",
+                "20180220-095832-16a4bbed",
+                "20180818-164043-7cdcde4b"
+            ]
+        );
+        for packet in packets {
+            assert_eq!(
+                packet.metadata,
+                get_metadata_text(root, &packet.id).unwrap()
+            );
+            assert_eq!(
+                packet.hash,
+                location::get_packet_hash(root, &packet.id).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn raw_metadata_lists_each_packet_once() {
+        let root = get_temp_outpack_root();
+        let id = "20170818-164830-33e0ab01";
+        let data = get_metadata_text(&root, id).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data.as_bytes()).unwrap();
+        fs::write(get_compressed_path(&root, id), encoder.finish().unwrap()).unwrap();
+        fs::write(root.join(".outpack/metadata/README.txt"), "not a packet").unwrap();
+
+        let ids: Vec<String> = iter_raw_metadata(&root)
+            .unwrap()
+            .map(|p| p.unwrap().id)
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                "20170818-164830-33e0ab01",
+                "20170818-164847-7574883b",
+                "20180220-095832-16a4bbed",
+                "20180818-164043-7cdcde4b"
+            ]
+        );
+    }
+
+    #[test]
+    fn can_get_packets_from_creation_time_range() {
+        let ids_in = |since: Option<f64>, until: Option<f64>| -> Vec<String> {
+            let range = TimeRange {
+                since,
+                until,
+                field: TimeField::Created,
+            };
+            get_metadata_from_date(Path::new("tests/example"), range)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.id)
+                .collect()
+        };
+
+        assert_eq!(
+            ids_in(Some(1510000000.), None),
+            vec!["20180220-095832-16a4bbed"]
+        );
+        assert_eq!(
+            ids_in(None, Some(1503074938.2232)),
+            vec![
+                "20170818-164830-33e0ab01",
+                "20170818-164847-7574883b",
+                "20180818-164043-7cdcde4b"
+            ]
+        );
+        // Every packet was imported long after it was created.
+        assert!(ids_in(Some(1662480555.), None).is_empty());
+    }
+
+    #[test]
+    fn can_iterate_packit_metadata() {
+        let root = Path::new("tests/example");
+        for range in [
+            TimeRange::default(),
+            TimeRange::since(1662480555.),
+            TimeRange {
+                until: Some(1662480555.7),
+                ..Default::default()
+            },
+            TimeRange {
+                since: Some(1510000000.),
+                field: TimeField::Created,
+                ..Default::default()
+            },
+        ] {
+            let expected: Vec<String> = get_packit_metadata_from_date(root, range)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.id)
+                .collect();
+            let ids: Vec<String> = iter_packit_metadata_from_date(root, range)
+                .unwrap()
+                .map(|p| p.unwrap().id)
+                .collect();
+            assert_eq!(ids, expected);
+        }
+    }
+
+    /// The text of a time as written in a packet's JSON.
+    fn time_text(json: &str, field: &str) -> String {
+        let key = format!("\"{}\":", field);
+        let start = json.find(&key).unwrap() + key.len();
+        json[start..]
+            .trim_start()
+            .split(|c: char| c == ',' || c == '}' || c.is_whitespace())
+            .next()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn packit_times_match_stored_text() {
+        let root = get_temp_outpack_root();
+        let path = root.join(".outpack/metadata/20180818-164043-7cdcde4b");
+        let data = fs::read_to_string(&path).unwrap();
+        let start = time_text(&data, "start");
+        let end = time_text(&data, "end");
+        // Neither of these would be written the same way if the parsed numbers were serialised.
+        let data = data
+            .replacen(&start, "1534610443", 1)
+            .replacen(&end, &format!("{}0", end), 1);
+        fs::write(&path, data).unwrap();
+
+        let streamed: Vec<PackitPacket> =
+            iter_packit_metadata_from_date(&root, TimeRange::default())
+                .unwrap()
+                .map(|p| p.unwrap())
+                .collect();
+        let listed = get_packit_metadata_from_date(&root, TimeRange::default()).unwrap();
+        let query = "id == \"20180818-164043-7cdcde4b\"";
+        let searched = search_packit_metadata(&root, query).unwrap();
+        assert_eq!(streamed.len(), 4);
+        assert_eq!(listed.len(), 4);
+        assert_eq!(searched.len(), 1);
+        for packet in streamed.into_iter().chain(listed).chain(searched) {
+            let stored =
+                fs::read_to_string(root.join(".outpack/metadata").join(&packet.id)).unwrap();
+            let line = serde_json::to_string(&packet).unwrap();
+            assert_eq!(time_text(&line, "start"), time_text(&stored, "start"));
+            assert_eq!(time_text(&line, "end"), time_text(&stored, "end"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_non_utf8_names_with_warning() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use tracing_capture::{CaptureLayer, SharedStorage};
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let root = get_temp_outpack_root();
+        let name = OsStr::from_bytes(b"20170818-164830-33e0ab0\xff");
+        fs::write(root.join(".outpack/metadata").join(name), "{}").unwrap();
+
+        let storage = SharedStorage::default();
+        let subscriber = tracing_subscriber::Registry::default().with(CaptureLayer::new(&storage));
+        let ids = tracing::subscriber::with_default(subscriber, || get_ids(&root, false));
+        assert_eq!(ids.unwrap().len(), 4);
+
+        let storage = storage.lock();
+        let warnings: Vec<_> = storage
+            .all_events()
+            .filter(|e| *e.metadata().level() == tracing::Level::WARN)
+            .filter_map(|e| e.message())
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("non-UTF-8"), "{}", warnings[0]);
+    }
+
+    #[test]
+    fn id_index_records_added_packets_without_listing_again() {
+        let root = get_temp_outpack_root();
+        let index = IdIndex::new(&root);
+        assert_eq!(index.ids(false).unwrap().len(), 4);
+        assert_eq!(index.ids(true).unwrap().len(), 1);
+
+        // Metadata written behind the index's back isn't seen, which shows that the directory
+        // isn't listed again.
+        let (unseen, data, _) = start_packet("data").finish();
+        fs::write(get_path(&root, &unseen), data).unwrap();
+
+        let (id, data, hash) = start_packet("data").finish();
+        add_packet(&root, &data, &hash, config::LOCAL_LOCATION).unwrap();
+        index.add(&id, true);
+        let ids = index.ids(false).unwrap();
+        assert_eq!(ids.len(), 5);
+        assert!(ids.contains(&id));
+        assert!(!ids.contains(&unseen));
+        assert!(index.ids(true).unwrap().contains(&id));
+
+        // Clones share the index, and invalidating it lists the directory again.
+        index.clone().invalidate();
+        let ids = index.ids(false).unwrap();
+        assert_eq!(ids.len(), 6);
+        assert!(ids.contains(&unseen));
+    }
+
+    #[test]
+    fn can_store_metadata_compressed() {
+        let root = get_temp_outpack_root();
+        let mut cfg = config::read_config(&root).unwrap();
+        cfg.core.compress_metadata = true;
+        config::write_config(&cfg, &root).unwrap();
+
+        let (id, data, hash) = start_packet("data").finish();
+        let id = id.as_str();
+        add_metadata(&root, &data, &hash).unwrap();
+
+        assert!(get_compressed_path(&root, id).exists());
+        assert!(!get_path(&root, id).exists());
+        assert_eq!(get_metadata_text(&root, id).unwrap(), data);
+        assert_eq!(get_packet(&root, id).unwrap().id, id);
+        assert!(packet_exists(&root, &String::from(id)).unwrap());
+        assert!(get_ids(&root, false).unwrap().contains(&String::from(id)));
+        assert_eq!(iter_packets(&root).filter(|p| p.is_ok()).count(), 5);
+
+        // Adding the same metadata again is fine, and different metadata is still rejected.
+        add_metadata(&root, &data, &hash).unwrap();
+        let other = data.replace(r#""name":"data""#, r#""name":"other""#);
+        let other_hash = hash::hash_data(other.as_bytes(), hash::HashAlgorithm::Sha256);
+        let err = add_metadata(&root, &other, &other_hash).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn cannot_replace_packet_with_different_metadata() {
+        let root = get_temp_outpack_root();
+        let id = "20230427-150828-68772cee";
+        let make_data = |name: &str| {
+            serde_json::json!({
+                "schema_version": "0.0.1",
+                "name": name,
+                "id": id,
+                "time": { "start": 1682608108.4139, "end": 1682608108.4309 },
+                "parameters": null,
+                "files": [],
+                "depends": [],
+                "script": ["orderly.R"]
+            })
+            .to_string()
+        };
+
+        let first = make_data("first");
+        let hash = hash::hash_data(first.as_bytes(), hash::HashAlgorithm::Sha256);
+        add_packet(&root, &first, &hash, config::LOCAL_LOCATION).unwrap();
+
+        let second = make_data("second");
+        let hash = hash::hash_data(second.as_bytes(), hash::HashAlgorithm::Sha256);
+        let err = add_packet(&root, &second, &hash, config::LOCAL_LOCATION).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "packet with id '{}' already exists with different metadata",
+                id
+            )
+        );
+        assert_eq!(get_metadata_text(&root, id).unwrap(), first);
+    }
+
+    #[test]
+    fn can_check_packet_without_importing() {
+        let root = get_empty_outpack_root();
+        let (id, data, hash) = start_packet("data").finish();
+        assert_eq!(check_packet(&root, &data, &hash).unwrap(), id);
+        assert!(!packet_exists(&root, &id).unwrap());
+        assert!(location::read_locations(&root).unwrap().is_empty());
+
+        let wrong = hash::hash_data(b"other", hash::HashAlgorithm::Sha256);
+        let err = check_packet(&root, &data, &wrong).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let file = hash::hash_data(b"absent", hash::HashAlgorithm::Sha256).to_string();
+        let (_, missing, missing_hash) = start_packet("data").add_file("a.txt", file, 6).finish();
+        let err = check_packet(&root, &missing, &missing_hash).unwrap_err();
+        assert_eq!(
+            crate::responses::OutpackError::from(err).code,
+            ErrorCode::MissingFiles
+        );
+
+        let (_, orphan, orphan_hash) = start_packet("data")
+            .add_dependency(&id, Vec::<DependencyFile>::new())
+            .finish();
+        let err = check_packet(&root, &orphan, &orphan_hash).unwrap_err();
+        assert_eq!(
+            crate::responses::OutpackError::from(err).code,
+            ErrorCode::MissingDependencies
+        );
+
+        add_packet(&root, &data, &hash, config::LOCAL_LOCATION).unwrap();
+        assert_eq!(check_packet(&root, &data, &hash).unwrap(), id);
+        let other = data.replace(r#""name":"data""#, r#""name":"other""#);
+        let other_hash = hash::hash_data(other.as_bytes(), hash::HashAlgorithm::Sha256);
+        let err = check_packet(&root, &other, &other_hash).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn can_touch_packet_to_restore_location_entry() {
+        let root = get_empty_outpack_root();
+        let (id, data, hash) = start_packet("data").finish();
+        add_packet(&root, &data, &hash, config::LOCAL_LOCATION).unwrap();
+        let entry_path = root.join(".outpack/location/local").join(&id);
+        let original = location::read_locations(&root).unwrap();
+
+        fs::remove_file(&entry_path).unwrap();
+        assert!(get_packit_metadata_from_date(&root, TimeRange::since(0.))
+            .unwrap()
+            .is_empty());
+
+        let entry = touch_packet(&root, &id).unwrap();
+        assert_eq!(entry.packet, id);
+        assert_eq!(entry.hash, hash.to_string());
+        assert_eq!(entry.location.as_deref(), Some(config::LOCAL_LOCATION));
+        assert!(entry.time >= original[0].time);
+        assert_eq!(
+            get_packit_metadata_from_date(&root, TimeRange::since(0.))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(get_ids(&root, true).unwrap().contains(&id));
+
+        // An existing entry is replaced, rather than kept.
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let touched = touch_packet(&root, &id).unwrap();
+        assert!(touched.time > entry.time);
+        assert_eq!(
+            location::read_locations(&root).unwrap()[0].time,
+            touched.time
+        );
+
+        let err = touch_packet(&root, &String::from("20170818-164847-00000000")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
     }
 
     #[test]
@@ -505,7 +1684,7 @@ mod tests {
         let hash = hash::hash_data(data.as_bytes(), hash::HashAlgorithm::Sha256);
         let root = get_temp_outpack_root();
         let now = SystemTime::now();
-        add_packet(&root, data, &hash).unwrap();
+        add_packet(&root, data, &hash, config::LOCAL_LOCATION).unwrap();
         let path = Path::new(&root)
             .join(".outpack")
             .join("location")
@@ -521,13 +1700,31 @@ mod tests {
         assert!(entry.time >= time_as_num(now));
     }
 
+    #[test]
+    fn can_add_packet_from_another_location() {
+        let root = get_temp_outpack_root();
+        let (id, data, hash) = start_packet("remote").finish();
+        add_packet(&root, &data, &hash, "another").unwrap();
+
+        let entries = location::read_named_location(&root, "another").unwrap();
+        let entry = entries.iter().find(|e| e.packet == id).unwrap();
+        assert_eq!(entry.hash, hash.to_string());
+        assert_eq!(entry.location.as_deref(), Some("another"));
+        assert!(!get_ids(&root, true).unwrap().contains(&id));
+
+        let (_, data, hash) = start_packet("remote").finish();
+        let err = add_packet(&root, &data, &hash, "unknown").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "location 'unknown' does not exist");
+    }
+
     #[test]
     fn can_add_metadata_with_missing_files() {
         let root = get_temp_outpack_root();
 
         let file_hash = "sha256:c7b512b2d14a7caae8968830760cb95980a98e18ca2c2991b87c71529e223164";
 
-        assert!(!file_exists(&root, file_hash).unwrap());
+        assert!(!file_exists(&root, FileStoreLayout::default(), file_hash).unwrap());
 
         let (_, metadata, hash) = start_packet("data")
             .add_file("data.csv", file_hash, 51)
@@ -542,19 +1739,88 @@ mod tests {
 
         let file_hash = "sha256:c7b512b2d14a7caae8968830760cb95980a98e18ca2c2991b87c71529e223164";
 
-        assert!(!file_exists(&root, file_hash).unwrap());
+        assert!(!file_exists(&root, FileStoreLayout::default(), file_hash).unwrap());
 
         let (_, metadata, hash) = start_packet("data")
             .add_file("data.csv", file_hash, 51)
             .finish();
 
-        let res = add_packet(&root, &metadata, &hash);
+        let res = add_packet(&root, &metadata, &hash, config::LOCAL_LOCATION);
         assert_regex!(
             res.unwrap_err().to_string(),
             "Can't import metadata for .*, as files missing:"
         );
     }
 
+    #[test]
+    fn can_get_duplicate_files() {
+        let root = get_empty_outpack_root();
+        let shared = "sha256:c7b512b2d14a7caae8968830760cb95980a98e18ca2c2991b87c71529e223164";
+        let unique = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+        let (id1, metadata, hash) = start_packet("a")
+            .add_file("data.csv", shared, 51)
+            .add_file("other.csv", unique, 51)
+            .finish();
+        add_metadata(&root, &metadata, &hash).unwrap();
+
+        let (id2, metadata, hash) = start_packet("b").add_file("input.csv", shared, 51).finish();
+        add_metadata(&root, &metadata, &hash).unwrap();
+
+        let duplicates = get_duplicate_files(&root).unwrap();
+        let mut expected_ids = vec![id1, id2];
+        expected_ids.sort();
+        assert_eq!(
+            duplicates,
+            vec![DuplicateFile {
+                hash: shared.to_string(),
+                count: 2,
+                packets: expected_ids,
+            }]
+        );
+    }
+
+    #[test]
+    fn can_get_packet_files() {
+        let files =
+            get_packet_files(Path::new("tests/example"), "20170818-164847-7574883b").unwrap();
+        assert_eq!(files.len(), 21);
+        assert!(files.iter().all(|f| !f.present));
+        assert_eq!(files[0].path, "orderly.yml");
+        assert_eq!(
+            files[0].hash,
+            "sha256:8916131d0bebabc5ab098ae3a34b03389768e1ef15acba74ea265deddeba579f"
+        );
+
+        let err =
+            get_packet_files(Path::new("tests/example"), "20170818-164847-00000000").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn can_get_packet_file_hash() {
+        let root = Path::new("tests/example");
+        let id = "20170818-164847-7574883b";
+        assert_eq!(
+            get_packet_file_hash(root, id, "script.R").unwrap(),
+            "sha256:d58d95afe19f8cd8835e29565717c9dfc7e472f1cfaaab38b7337f484c73752e"
+        );
+
+        let err = get_packet_file_hash(root, id, "missing.R").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "packet '20170818-164847-7574883b' does not contain file 'missing.R'"
+        );
+    }
+
+    #[test]
+    fn example_packets_share_all_files() {
+        let duplicates = get_duplicate_files(Path::new("tests/example")).unwrap();
+        assert_eq!(duplicates.len(), 21);
+        assert!(duplicates.iter().all(|d| d.count == 4));
+    }
+
     #[test]
     fn cannot_add_packet_with_missing_dependencies() {
         let (dependency_id, _, _) = start_packet("upstream").finish();
@@ -564,10 +1830,88 @@ mod tests {
 
         let root = get_temp_outpack_root();
 
-        let res = add_packet(&root, &metadata, &hash);
+        let res = add_packet(&root, &metadata, &hash, config::LOCAL_LOCATION);
         assert_regex!(
             res.unwrap_err().to_string(),
             "Can't import metadata for .*, as dependencies missing:"
         );
     }
+
+    #[test]
+    fn log_backend_serves_the_same_packets() {
+        let example = Path::new("tests/example");
+        let root = get_log_outpack_root();
+        assert!(!root
+            .join(".outpack/metadata/20170818-164847-7574883b")
+            .exists());
+
+        let mut ids = get_ids(&root, false).unwrap();
+        ids.sort();
+        let mut expected = get_ids(example, false).unwrap();
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        let ids_of = |packets: Vec<Packet>| packets.into_iter().map(|p| p.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids_of(get_metadata_from_date(&root, TimeRange::default()).unwrap()),
+            ids_of(get_metadata_from_date(example, TimeRange::default()).unwrap())
+        );
+        assert_eq!(
+            ids_of(get_metadata_from_date(&root, TimeRange::since(1662480556.)).unwrap()),
+            ids_of(get_metadata_from_date(example, TimeRange::since(1662480556.)).unwrap())
+        );
+        assert_eq!(iter_packets(&root).count(), 4);
+        assert_eq!(
+            get_ids_digest(&root, &IdIndex::new(&root), None, None).unwrap(),
+            get_ids_digest(example, &IdIndex::new(example), None, None).unwrap()
+        );
+
+        let id = "20170818-164847-7574883b";
+        assert!(packet_exists(&root, &String::from(id)).unwrap());
+        assert_eq!(
+            get_metadata_text(&root, id).unwrap(),
+            get_metadata_text(example, id).unwrap()
+        );
+        assert_eq!(get_packet(&root, id).unwrap().id, id);
+        assert_eq!(get_metadata_by_id(&root, id).unwrap()["id"], id);
+        assert_eq!(
+            get_packet_files(&root, id).unwrap(),
+            get_packet_files(example, id).unwrap()
+        );
+
+        let missing = String::from("20170818-164847-00000000");
+        assert!(!packet_exists(&root, &missing).unwrap());
+        let err = get_packet(&root, &missing).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn log_backend_can_add_packets() {
+        let root = get_empty_outpack_root();
+        let mut config = config::read_config(&root).unwrap();
+        config.core.metadata_backend = config::MetadataBackend::Log;
+        config::write_config(&config, &root).unwrap();
+
+        let (id, data, hash) = start_packet("data").finish();
+        assert_eq!(check_packet(&root, &data, &hash).unwrap(), id);
+        add_packet(&root, &data, &hash, config::LOCAL_LOCATION).unwrap();
+        add_packet(&root, &data, &hash, config::LOCAL_LOCATION).unwrap();
+        assert!(root.join(".outpack/metadata.log").exists());
+        assert!(fs::read_dir(root.join(".outpack/metadata"))
+            .unwrap()
+            .next()
+            .is_none());
+
+        assert_eq!(get_ids(&root, false).unwrap(), vec![id.clone()]);
+        assert_eq!(get_metadata_text(&root, &id).unwrap(), data);
+        assert_eq!(touch_packet(&root, &id).unwrap().hash, hash.to_string());
+
+        let mut packet: Packet = serde_json::from_str(&data).unwrap();
+        packet.name = String::from("other");
+        let other = serde_json::to_string(&packet).unwrap();
+        let other_hash = hash::hash_data(other.as_bytes(), hash::HashAlgorithm::Sha256);
+        let err = add_packet(&root, &other, &other_hash, config::LOCAL_LOCATION).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(get_metadata_text(&root, &id).unwrap(), data);
+    }
 }
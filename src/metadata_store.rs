@@ -0,0 +1,442 @@
+//! Where packet metadata is kept.
+//!
+//! By default each packet's metadata is a file in `.outpack/metadata`, named by its id. Listing
+//! packets then means listing that directory, which gets slower as the repository grows, so a
+//! repository can instead be configured to append metadata to a single log file, indexed in
+//! memory. Either way, the functions in `metadata` go through the `MetadataStore` trait.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, MetadataBackend};
+use crate::storage::{FileSystem, Storage};
+use crate::utils::utf8_file_name;
+
+pub trait MetadataStore: Send + Sync {
+    /// The ids of the packets with metadata, in no particular order.
+    fn ids(&self) -> io::Result<Vec<String>>;
+
+    /// A packet's metadata, exactly as it was added, or `None` if there is none.
+    fn read(&self, id: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Whether there is metadata for a packet, without reading it.
+    fn contains(&self, id: &str) -> io::Result<bool>;
+
+    /// Add a packet's metadata, with its hash. The caller must check it isn't already stored.
+    fn add(&self, id: &str, hash: &str, data: &str) -> io::Result<()>;
+}
+
+/// Open the metadata store of a repository, as chosen by its configuration.
+pub fn open(root: &Path) -> io::Result<Box<dyn MetadataStore>> {
+    let config = config::read_config(root)?;
+    Ok(match config.core.metadata_backend {
+        MetadataBackend::Directory => Box::new(DirectoryMetadataStore::new(
+            &FileSystem,
+            root,
+            config.core.compress_metadata,
+        )),
+        MetadataBackend::Log => Box::new(LogMetadataStore::new(root)),
+    })
+}
+
+/// Metadata may be stored gzip-compressed, in which case the file name has this suffix after the
+/// packet id.
+pub(crate) const COMPRESSED_SUFFIX: &str = ".json.gz";
+
+/// The most that compressed metadata may decompress to. Packet metadata is far smaller than this,
+/// so the limit only stops a crafted file from exhausting memory.
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+pub(crate) fn get_path(root: &Path, id: &str) -> PathBuf {
+    root.join(".outpack").join("metadata").join(id)
+}
+
+pub(crate) fn get_compressed_path(root: &Path, id: &str) -> PathBuf {
+    root.join(".outpack")
+        .join("metadata")
+        .join(format!("{}{}", id, COMPRESSED_SUFFIX))
+}
+
+/// Metadata kept as one file per packet in `.outpack/metadata`, named by its id.
+pub struct DirectoryMetadataStore<'a> {
+    storage: &'a dyn Storage,
+    root: PathBuf,
+    compress: bool,
+}
+
+impl<'a> DirectoryMetadataStore<'a> {
+    /// Metadata is read through the given storage. New metadata is written gzip-compressed if
+    /// `compress` is set, but either form can be read.
+    pub fn new(storage: &'a dyn Storage, root: &Path, compress: bool) -> Self {
+        DirectoryMetadataStore {
+            storage,
+            root: root.to_path_buf(),
+            compress,
+        }
+    }
+
+    fn find(&self, id: &str) -> Option<PathBuf> {
+        [
+            get_path(&self.root, id),
+            get_compressed_path(&self.root, id),
+        ]
+        .into_iter()
+        .find(|path| self.storage.exists(path))
+    }
+
+    /// Read the contents of a metadata file, decompressing them if needed.
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let data = self.storage.read(path)?;
+        if path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.ends_with(COMPRESSED_SUFFIX))
+        {
+            let mut contents = Vec::new();
+            GzDecoder::new(data.as_slice())
+                .take(MAX_DECOMPRESSED_SIZE + 1)
+                .read_to_end(&mut contents)?;
+            if contents.len() as u64 > MAX_DECOMPRESSED_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Metadata in {} decompresses to more than {} bytes",
+                        path.display(),
+                        MAX_DECOMPRESSED_SIZE
+                    ),
+                ));
+            }
+            Ok(contents)
+        } else {
+            Ok(data)
+        }
+    }
+}
+
+impl MetadataStore for DirectoryMetadataStore<'_> {
+    fn ids(&self) -> io::Result<Vec<String>> {
+        let path = self.root.join(".outpack").join("metadata");
+        Ok(fs::read_dir(path)?
+            .filter_map(|r| r.ok())
+            .filter_map(|e| {
+                let name = utf8_file_name(&e.file_name(), &e.path())?.to_string();
+                Some(match name.strip_suffix(COMPRESSED_SUFFIX) {
+                    Some(id) => id.to_string(),
+                    None => name,
+                })
+            })
+            .collect())
+    }
+
+    fn read(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        self.find(id).map(|path| self.read_file(&path)).transpose()
+    }
+
+    fn contains(&self, id: &str) -> io::Result<bool> {
+        Ok(self.find(id).is_some())
+    }
+
+    fn add(&self, id: &str, _hash: &str, data: &str) -> io::Result<()> {
+        if self.compress {
+            let file = File::create(get_compressed_path(&self.root, id))?;
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            encoder.write_all(data.as_bytes())?;
+            encoder.finish()?;
+            Ok(())
+        } else {
+            fs::write(get_path(&self.root, id), data)
+        }
+    }
+}
+
+/// A line of the metadata log.
+#[derive(Serialize, Deserialize)]
+struct LogRecord {
+    id: String,
+    hash: String,
+    json: String,
+}
+
+/// Only the id of a record is needed to index it.
+#[derive(Deserialize)]
+struct LogRecordId {
+    id: String,
+}
+
+/// Where each packet's record is in a metadata log.
+#[derive(Default)]
+struct LogIndex {
+    /// How many bytes of the log have been indexed. Anything after that has been appended since.
+    indexed: u64,
+    /// The offset and length of each packet's record.
+    records: HashMap<String, (u64, usize)>,
+}
+
+impl LogIndex {
+    /// Index any records appended to the log since it was last read.
+    fn update(&mut self, path: &Path) -> io::Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                *self = LogIndex::default();
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        if len < self.indexed {
+            // The log has been replaced, so start again.
+            *self = LogIndex::default();
+        }
+        if len == self.indexed {
+            return Ok(());
+        }
+
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(self.indexed))?;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            // A record without its newline is still being written, so is left for next time.
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            match serde_json::from_slice::<LogRecordId>(&line) {
+                // Metadata is never changed once added, so only the first record for a packet
+                // counts.
+                Ok(record) => {
+                    self.records.entry(record.id).or_insert((self.indexed, n));
+                }
+                Err(e) => tracing::warn!(
+                    "Skipping invalid record at offset {} of {}: {}",
+                    self.indexed,
+                    path.display(),
+                    e
+                ),
+            }
+            self.indexed += n as u64;
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// The index of each metadata log, keyed by its path. These are kept for as long as the
+    /// process runs, and brought up to date whenever a packet isn't found in them.
+    static ref LOG_INDEXES: Mutex<HashMap<PathBuf, LogIndex>> = Mutex::new(HashMap::new());
+}
+
+/// Metadata appended as one JSON record per line, holding a packet's `id`, `hash` and metadata as
+/// `json`, to `.outpack/metadata.log`.
+pub struct LogMetadataStore {
+    path: PathBuf,
+}
+
+impl LogMetadataStore {
+    pub fn new(root: &Path) -> Self {
+        LogMetadataStore {
+            path: root.join(".outpack").join("metadata.log"),
+        }
+    }
+
+    /// Find where a packet's record is, indexing any new records if it isn't known yet.
+    fn find(&self, id: &str) -> io::Result<Option<(u64, usize)>> {
+        let mut indexes = LOG_INDEXES.lock().unwrap();
+        let index = indexes.entry(self.path.clone()).or_default();
+        if let Some(location) = index.records.get(id) {
+            return Ok(Some(*location));
+        }
+        index.update(&self.path)?;
+        Ok(index.records.get(id).copied())
+    }
+}
+
+impl MetadataStore for LogMetadataStore {
+    fn ids(&self) -> io::Result<Vec<String>> {
+        let mut indexes = LOG_INDEXES.lock().unwrap();
+        let index = indexes.entry(self.path.clone()).or_default();
+        index.update(&self.path)?;
+        Ok(index.records.keys().cloned().collect())
+    }
+
+    fn read(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        let Some((offset, len)) = self.find(id)? else {
+            return Ok(None);
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut line = vec![0; len];
+        file.read_exact(&mut line)?;
+        let record: LogRecord = serde_json::from_slice(&line)?;
+        Ok(Some(record.json.into_bytes()))
+    }
+
+    fn contains(&self, id: &str) -> io::Result<bool> {
+        Ok(self.find(id)?.is_some())
+    }
+
+    fn add(&self, id: &str, hash: &str, data: &str) -> io::Result<()> {
+        let record = LogRecord {
+            id: id.to_string(),
+            hash: hash.to_string(),
+            json: data.to_string(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        // The lock is held while appending, so that the index is updated before anyone else can
+        // look for the packet.
+        let mut indexes = LOG_INDEXES.lock().unwrap();
+        let index = indexes.entry(self.path.clone()).or_default();
+        index.update(&self.path)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        // A record left incomplete by an interrupted write is ended, so that it isn't merged with
+        // this one.
+        if file.metadata()?.len() > index.indexed {
+            line.insert(0, b'\n');
+        }
+        // Written with a single call, so that concurrent writers can't interleave their records.
+        file.write_all(&line)?;
+        index.update(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tests::{get_empty_outpack_root, start_packet};
+
+    /// Check the behaviour every store must have, starting from an empty one.
+    fn check_store(store: &dyn MetadataStore) {
+        let (id, data, hash) = start_packet("store").finish();
+        let (other, other_data, other_hash) = start_packet("store").finish();
+        assert!(store.ids().unwrap().is_empty());
+        assert!(!store.contains(&id).unwrap());
+        assert_eq!(store.read(&id).unwrap(), None);
+
+        store.add(&id, &hash.to_string(), &data).unwrap();
+        store
+            .add(&other, &other_hash.to_string(), &other_data)
+            .unwrap();
+        assert!(store.contains(&id).unwrap());
+        assert_eq!(store.read(&id).unwrap(), Some(data.into_bytes()));
+        assert_eq!(store.read(&other).unwrap(), Some(other_data.into_bytes()));
+
+        let mut ids = store.ids().unwrap();
+        ids.sort();
+        let mut expected = vec![id, other];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn directory_store_can_add_and_read_metadata() {
+        let root = get_empty_outpack_root();
+        check_store(&DirectoryMetadataStore::new(&FileSystem, &root, false));
+
+        let root = get_empty_outpack_root();
+        check_store(&DirectoryMetadataStore::new(&FileSystem, &root, true));
+    }
+
+    #[test]
+    fn compressed_metadata_is_smaller_and_readable_by_other_tools() {
+        let root = get_empty_outpack_root();
+        let store = DirectoryMetadataStore::new(&FileSystem, &root, true);
+        let data =
+            fs::read_to_string("tests/example/.outpack/metadata/20180818-164043-7cdcde4b").unwrap();
+        let id = "20180818-164043-7cdcde4b";
+        store.add(id, "", &data).unwrap();
+
+        let compressed = fs::read(get_compressed_path(&root, id)).unwrap();
+        assert!(compressed.len() * 3 < data.len());
+        let mut contents = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, data);
+    }
+
+    #[test]
+    fn rejects_metadata_which_decompresses_too_far() {
+        let root = get_empty_outpack_root();
+        let id = "20180818-164043-7cdcde4b";
+        let path = get_compressed_path(&root, id);
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::best());
+        let zeros = vec![0; 1024 * 1024];
+        for _ in 0..=MAX_DECOMPRESSED_SIZE / zeros.len() as u64 {
+            encoder.write_all(&zeros).unwrap();
+        }
+        encoder.finish().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < 1024 * 1024);
+
+        let store = DirectoryMetadataStore::new(&FileSystem, &root, false);
+        let err = store.read(id).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("decompresses to more than"));
+
+        fs::write(&path, "not gzip data").unwrap();
+        assert!(store.read(id).is_err());
+    }
+
+    #[test]
+    fn log_store_can_add_and_read_metadata() {
+        let root = get_empty_outpack_root();
+        check_store(&LogMetadataStore::new(&root));
+    }
+
+    #[test]
+    fn log_store_sees_records_appended_by_others() {
+        let root = get_empty_outpack_root();
+        let store = LogMetadataStore::new(&root);
+        let (id, data, hash) = start_packet("log").finish();
+        store.add(&id, &hash.to_string(), &data).unwrap();
+
+        // Another process appends a record, then starts writing one more.
+        let (other, other_data, other_hash) = start_packet("log").finish();
+        let record = LogRecord {
+            id: other.clone(),
+            hash: other_hash.to_string(),
+            json: other_data.clone(),
+        };
+        let mut file = OpenOptions::new().append(true).open(&store.path).unwrap();
+        writeln!(file, "{}", serde_json::to_string(&record).unwrap()).unwrap();
+        write!(file, r#"{{"id":"20240101-000000-00000000","#).unwrap();
+
+        assert_eq!(store.read(&other).unwrap(), Some(other_data.into_bytes()));
+        assert_eq!(store.ids().unwrap().len(), 2);
+
+        // The incomplete record is skipped rather than corrupting the next one.
+        let (last, last_data, last_hash) = start_packet("log").finish();
+        store
+            .add(&last, &last_hash.to_string(), &last_data)
+            .unwrap();
+        assert_eq!(store.read(&last).unwrap(), Some(last_data.into_bytes()));
+        assert_eq!(store.ids().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn log_store_skips_invalid_records() {
+        let root = get_empty_outpack_root();
+        let store = LogMetadataStore::new(&root);
+        fs::write(&store.path, "not a record\n").unwrap();
+
+        let (id, data, hash) = start_packet("log").finish();
+        store.add(&id, &hash.to_string(), &data).unwrap();
+        assert_eq!(store.ids().unwrap(), vec![id.clone()]);
+        assert_eq!(store.read(&id).unwrap(), Some(data.into_bytes()));
+    }
+}
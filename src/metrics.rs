@@ -1,21 +1,53 @@
 use crate::metadata;
+use crate::metadata::IdIndex;
 use crate::store;
+use axum::body::{Body, HttpBody};
 use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, HeaderMap};
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use futures::future::{BoxFuture, FutureExt};
+use futures::TryStreamExt;
 use prometheus::{
-    core::Collector, core::Desc, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
-    IntGaugeVec, Opts, Registry,
+    core::Collector, core::Desc, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
 };
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The namespace used for the server's own metrics, unless configured otherwise.
+pub const DEFAULT_NAMESPACE: &str = "outpack_server";
+
+/// The namespace used for HTTP request metrics, unless configured otherwise.
+pub const DEFAULT_HTTP_NAMESPACE: &str = "http";
+
+/// The histogram buckets used for HTTP request durations, in seconds, unless configured otherwise.
+pub const DEFAULT_DURATION_BUCKETS: &[f64] = prometheus::DEFAULT_BUCKETS;
+
+/// Check that a namespace, given on the command line, can be used as a metric name prefix.
+pub fn parse_namespace(namespace: &str) -> Result<String, String> {
+    let mut chars = namespace.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if valid_start && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(namespace.to_owned())
+    } else {
+        Err(format!("'{}' is not a valid metrics namespace", namespace))
+    }
+}
 
 /// A prometheus collector with metrics for the state of the repository.
 ///
-/// The metrics are collected lazily whenever the metrics endpoint is called.
+/// The metrics are collected lazily whenever the metrics endpoint is called. For large
+/// repositories, where this is slow, they can instead be refreshed periodically using
+/// `refresh_in_background`.
 pub struct RepositoryMetrics {
     root: PathBuf,
+    ids: IdIndex,
     metadata_total: IntGauge,
     packets_total: IntGauge,
     files_total: IntGauge,
@@ -24,12 +56,23 @@ pub struct RepositoryMetrics {
 }
 
 impl RepositoryMetrics {
-    pub fn register(registry: &Registry, root: &Path) -> prometheus::Result<()> {
-        registry.register(Box::new(RepositoryMetrics::new(root)))
+    /// Create and register repository metrics, which are refreshed every `refresh_interval` if
+    /// one is given, and otherwise whenever they are collected.
+    pub fn register(
+        registry: &Registry,
+        root: &Path,
+        ids: IdIndex,
+        namespace: &str,
+        refresh_interval: Option<Duration>,
+    ) -> prometheus::Result<()> {
+        let metrics = RepositoryMetrics::new(root, ids, namespace);
+        match refresh_interval {
+            None => registry.register(Box::new(metrics)),
+            Some(interval) => registry.register(Box::new(metrics.refresh_in_background(interval))),
+        }
     }
 
-    pub fn new(root: impl Into<PathBuf>) -> RepositoryMetrics {
-        let namespace = "outpack_server";
+    pub fn new(root: impl Into<PathBuf>, ids: IdIndex, namespace: &str) -> RepositoryMetrics {
         let make_opts = |name: &str, help: &str| Opts::new(name, help).namespace(namespace);
 
         let metadata_total = IntGauge::with_opts(make_opts(
@@ -63,6 +106,7 @@ impl RepositoryMetrics {
         descs.extend(file_size_bytes_total.desc().into_iter().cloned());
         RepositoryMetrics {
             root: root.into(),
+            ids,
             metadata_total,
             packets_total,
             files_total,
@@ -72,15 +116,13 @@ impl RepositoryMetrics {
     }
 
     fn update(&self) -> anyhow::Result<()> {
-        self.metadata_total
-            .set(metadata::get_ids(&self.root, false)?.len() as i64);
+        self.metadata_total.set(self.ids.ids(false)?.len() as i64);
 
-        self.packets_total
-            .set(metadata::get_ids(&self.root, true)?.len() as i64);
+        self.packets_total.set(self.ids.ids(true)?.len() as i64);
 
         let mut files_count = 0;
         let mut files_size = 0;
-        for f in store::enumerate_files(&self.root) {
+        for f in store::enumerate_files(&self.root, store::layout(&self.root)?)? {
             files_count += 1;
             files_size += f.metadata()?.len();
         }
@@ -91,22 +133,81 @@ impl RepositoryMetrics {
     }
 }
 
+impl RepositoryMetrics {
+    /// Refresh the metrics on a background thread every `interval`, rather than whenever they are
+    /// collected, so that collecting them returns immediately.
+    ///
+    /// The most recent values are reported until they are next refreshed, and nothing is reported
+    /// until the first refresh has finished. The thread exits once the returned collector is
+    /// dropped.
+    pub fn refresh_in_background(self, interval: Duration) -> BackgroundRepositoryMetrics {
+        let metrics = Arc::new(self);
+        let refreshed = Arc::new(AtomicBool::new(false));
+
+        let weak = Arc::downgrade(&metrics);
+        let flag = refreshed.clone();
+        thread::Builder::new()
+            .name(String::from("outpack-metrics"))
+            .spawn(move || {
+                while let Some(metrics) = weak.upgrade() {
+                    match metrics.update() {
+                        Ok(()) => flag.store(true, Ordering::Release),
+                        Err(e) => {
+                            tracing::error!("error while refreshing repository metrics: {}", e)
+                        }
+                    }
+                    drop(metrics);
+                    thread::sleep(interval);
+                }
+            })
+            .expect("metrics thread started");
+
+        BackgroundRepositoryMetrics { metrics, refreshed }
+    }
+
+    fn families(&self) -> Vec<prometheus::proto::MetricFamily> {
+        let mut metrics = Vec::new();
+        metrics.extend(self.metadata_total.collect());
+        metrics.extend(self.packets_total.collect());
+        metrics.extend(self.files_total.collect());
+        metrics.extend(self.file_size_bytes_total.collect());
+        metrics
+    }
+}
+
 impl Collector for RepositoryMetrics {
     fn desc(&self) -> Vec<&prometheus::core::Desc> {
         self.descs.iter().collect()
     }
 
     fn collect(&self) -> Vec<prometheus::proto::MetricFamily> {
-        let mut metrics = Vec::new();
         if let Err(e) = self.update() {
             tracing::error!("error while collecting repository metrics: {}", e);
+            Vec::new()
         } else {
-            metrics.extend(self.metadata_total.collect());
-            metrics.extend(self.packets_total.collect());
-            metrics.extend(self.files_total.collect());
-            metrics.extend(self.file_size_bytes_total.collect());
+            self.families()
+        }
+    }
+}
+
+/// Repository metrics which are refreshed in the background, created by
+/// `RepositoryMetrics::refresh_in_background`.
+pub struct BackgroundRepositoryMetrics {
+    metrics: Arc<RepositoryMetrics>,
+    refreshed: Arc<AtomicBool>,
+}
+
+impl Collector for BackgroundRepositoryMetrics {
+    fn desc(&self) -> Vec<&prometheus::core::Desc> {
+        self.metrics.desc()
+    }
+
+    fn collect(&self) -> Vec<prometheus::proto::MetricFamily> {
+        if self.refreshed.load(Ordering::Acquire) {
+            self.metrics.families()
+        } else {
+            Vec::new()
         }
-        metrics
     }
 }
 
@@ -115,6 +216,8 @@ pub struct HttpMetrics {
     requests_total: IntCounterVec,
     requests_duration_seconds: HistogramVec,
     requests_in_flight: IntGaugeVec,
+    request_size_bytes: HistogramVec,
+    response_size_bytes: HistogramVec,
 }
 
 // The type returned by `HttpMetrics::layer()`. Unfortunately it is a might of a mouthful.
@@ -128,18 +231,36 @@ impl HttpMetrics {
     /// Create and register HTTP metrics.
     ///
     /// The returned object should be used to add a layer to axum router, using the `layer` method.
-    pub fn register(registry: &Registry) -> prometheus::Result<HttpMetrics> {
-        let metrics = HttpMetrics::new();
+    pub fn register(
+        registry: &Registry,
+        namespace: &str,
+        duration_buckets: &[f64],
+    ) -> prometheus::Result<HttpMetrics> {
+        let metrics = HttpMetrics::new(namespace, duration_buckets)?;
         registry.register(Box::new(metrics.requests_total.clone()))?;
         registry.register(Box::new(metrics.requests_duration_seconds.clone()))?;
         registry.register(Box::new(metrics.requests_in_flight.clone()))?;
+        registry.register(Box::new(metrics.request_size_bytes.clone()))?;
+        registry.register(Box::new(metrics.response_size_bytes.clone()))?;
         Ok(metrics)
     }
 
-    pub fn new() -> HttpMetrics {
-        HttpMetrics {
+    /// Create HTTP metrics, recording request durations in the given histogram buckets.
+    ///
+    /// The bucket boundaries, in seconds, must be in increasing order.
+    pub fn new(namespace: &str, duration_buckets: &[f64]) -> prometheus::Result<HttpMetrics> {
+        // The histogram only checks its buckets when the first set of labels is used, which would
+        // be too late to report a misconfiguration.
+        if let Some(w) = duration_buckets.windows(2).find(|w| w[0] >= w[1]) {
+            return Err(prometheus::Error::Msg(format!(
+                "histogram buckets must be in increasing order: {} >= {}",
+                w[0], w[1]
+            )));
+        }
+
+        Ok(HttpMetrics {
             requests_total: IntCounterVec::new(
-                Opts::new("requests_total", "Total number of HTTP requests").namespace("http"),
+                Opts::new("requests_total", "Total number of HTTP requests").namespace(namespace),
                 &["endpoint", "method", "status"],
             )
             .unwrap(),
@@ -149,21 +270,37 @@ impl HttpMetrics {
                     "requests_duration_seconds",
                     "HTTP request duration in seconds for all requests",
                 )
-                .namespace("http"),
+                .namespace(namespace)
+                .buckets(duration_buckets.to_vec()),
                 &["endpoint", "method", "status"],
-            )
-            .unwrap(),
+            )?,
 
             requests_in_flight: IntGaugeVec::new(
                 Opts::new(
                     "requests_in_flight",
                     "Number of HTTP requests currently in-flight",
                 )
-                .namespace("http"),
+                .namespace(namespace),
                 &["endpoint", "method"],
             )
             .unwrap(),
-        }
+
+            request_size_bytes: HistogramVec::new(
+                HistogramOpts::new("request_size_bytes", "HTTP request body size in bytes")
+                    .namespace(namespace)
+                    .buckets(size_buckets()),
+                &["endpoint", "method"],
+            )
+            .unwrap(),
+
+            response_size_bytes: HistogramVec::new(
+                HistogramOpts::new("response_size_bytes", "HTTP response body size in bytes")
+                    .namespace(namespace)
+                    .buckets(size_buckets()),
+                &["endpoint", "method"],
+            )
+            .unwrap(),
+        })
     }
 
     /// Create a `Layer` that can be added to an Axum router to record request metrics.
@@ -192,6 +329,12 @@ impl HttpMetrics {
             .with_label_values(&[path.as_str(), method.as_ref()])
             .inc();
 
+        let request_size = self
+            .request_size_bytes
+            .with_label_values(&[path.as_str(), method.as_ref()]);
+        let (parts, body) = req.into_parts();
+        let body = observe_body_size(&request_size, &parts.headers, body);
+        let req = Request::from_parts(parts, body);
         let response = next.run(req).await;
 
         self.requests_in_flight
@@ -209,7 +352,58 @@ impl HttpMetrics {
             .with_label_values(&[path.as_str(), method.as_ref(), &status])
             .observe(duration);
 
-        response
+        let response_size = self
+            .response_size_bytes
+            .with_label_values(&[path.as_str(), method.as_ref()]);
+        let (parts, body) = response.into_parts();
+        let body = observe_body_size(&response_size, &parts.headers, body);
+        Response::from_parts(parts, body)
+    }
+}
+
+/// Histogram buckets for request and response sizes, from 64 bytes up to 16MiB.
+fn size_buckets() -> Vec<f64> {
+    prometheus::exponential_buckets(64.0, 4.0, 10).unwrap()
+}
+
+/// Record the size of a body in a histogram.
+///
+/// When the size is known upfront, from the `Content-Length` header or the body itself, it is
+/// recorded straight away. Otherwise the bytes are counted as they are read, and the total is
+/// recorded once the body is dropped, whether or not it was read to the end.
+fn observe_body_size(histogram: &Histogram, headers: &HeaderMap, body: Body) -> Body {
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    if let Some(size) = content_length.or_else(|| body.size_hint().exact()) {
+        histogram.observe(size as f64);
+        return body;
+    }
+
+    let mut counter = ByteCounter {
+        histogram: histogram.clone(),
+        bytes: 0,
+    };
+    Body::from_stream(
+        body.into_data_stream()
+            .inspect_ok(move |chunk| counter.add(chunk.len())),
+    )
+}
+
+struct ByteCounter {
+    histogram: Histogram,
+    bytes: u64,
+}
+
+impl ByteCounter {
+    fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for ByteCounter {
+    fn drop(&mut self) {
+        self.histogram.observe(self.bytes as f64);
     }
 }
 
@@ -226,9 +420,127 @@ pub fn register_process_metrics(_registry: &Registry) -> prometheus::Result<()>
     Ok(())
 }
 
-pub fn register_build_info_metrics(registry: &Registry) -> prometheus::Result<()> {
-    let opts =
-        Opts::new("build_info", "Build information about the server").namespace("outpack_server");
+/// A prometheus collector reporting how effective the packet metadata cache is.
+///
+/// The cache is shared by the whole process, so the counts are read from it whenever the metrics
+/// endpoint is called, rather than being recorded by the collector itself.
+pub struct CacheMetrics {
+    metadata_cache_hits_total: IntCounter,
+    metadata_cache_misses_total: IntCounter,
+    descs: Vec<Desc>,
+}
+
+impl CacheMetrics {
+    pub fn register(registry: &Registry, namespace: &str) -> prometheus::Result<()> {
+        registry.register(Box::new(CacheMetrics::new(namespace)))
+    }
+
+    pub fn new(namespace: &str) -> CacheMetrics {
+        let make_opts = |name: &str, help: &str| Opts::new(name, help).namespace(namespace);
+
+        let metadata_cache_hits_total = IntCounter::with_opts(make_opts(
+            "metadata_cache_hits_total",
+            "Number of packet metadata reads served from the cache",
+        ))
+        .unwrap();
+
+        let metadata_cache_misses_total = IntCounter::with_opts(make_opts(
+            "metadata_cache_misses_total",
+            "Number of packet metadata reads which had to parse the file",
+        ))
+        .unwrap();
+
+        let mut descs = Vec::new();
+        descs.extend(metadata_cache_hits_total.desc().into_iter().cloned());
+        descs.extend(metadata_cache_misses_total.desc().into_iter().cloned());
+        CacheMetrics {
+            metadata_cache_hits_total,
+            metadata_cache_misses_total,
+            descs,
+        }
+    }
+
+    fn update(&self) {
+        let (hits, misses) = metadata::cache_statistics();
+        let catch_up = |counter: &IntCounter, value: u64| {
+            counter.inc_by(value.saturating_sub(counter.get()));
+        };
+        catch_up(&self.metadata_cache_hits_total, hits);
+        catch_up(&self.metadata_cache_misses_total, misses);
+    }
+}
+
+impl Collector for CacheMetrics {
+    fn desc(&self) -> Vec<&prometheus::core::Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<prometheus::proto::MetricFamily> {
+        self.update();
+        let mut metrics = Vec::new();
+        metrics.extend(self.metadata_cache_hits_total.collect());
+        metrics.extend(self.metadata_cache_misses_total.collect());
+        metrics
+    }
+}
+
+/// Counts of the git operations run by the server, and how long fetches take, since those go
+/// over the network to the remote.
+#[derive(Clone)]
+pub struct GitMetrics {
+    operations_total: IntCounterVec,
+    fetch_duration_seconds: Histogram,
+}
+
+impl GitMetrics {
+    pub fn register(registry: &Registry, namespace: &str) -> prometheus::Result<GitMetrics> {
+        let metrics = GitMetrics::new(namespace);
+        registry.register(Box::new(metrics.operations_total.clone()))?;
+        registry.register(Box::new(metrics.fetch_duration_seconds.clone()))?;
+        Ok(metrics)
+    }
+
+    pub fn new(namespace: &str) -> GitMetrics {
+        GitMetrics {
+            operations_total: IntCounterVec::new(
+                Opts::new("git_operations_total", "Total number of git operations")
+                    .namespace(namespace),
+                &["operation", "result"],
+            )
+            .unwrap(),
+
+            fetch_duration_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "git_fetch_duration_seconds",
+                    "Time taken to fetch from the git remote, in seconds",
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Count an operation, such as "fetch" or "pull", as a success or an error.
+    pub fn record<T, E>(&self, operation: &str, result: &Result<T, E>) {
+        let outcome = if result.is_ok() { "success" } else { "error" };
+        self.operations_total
+            .with_label_values(&[operation, outcome])
+            .inc();
+    }
+
+    /// Run and record a fetch, timing how long it takes whether or not it succeeds.
+    pub fn fetch<T, E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let result = self.fetch_duration_seconds.observe_closure_duration(f);
+        self.record("fetch", &result);
+        result
+    }
+}
+
+/// Register a constant `build_info` gauge, labelled with the crate version, git
+/// revision and rustc version the server was built with, to correlate other metrics with a
+/// particular deployment.
+pub fn register_build_info_metrics(registry: &Registry, namespace: &str) -> prometheus::Result<()> {
+    let opts = Opts::new("build_info", "Build information about the server").namespace(namespace);
     let labels = ["version", "revision", "rustc"];
     let values = [
         env!("CARGO_PKG_VERSION"),
@@ -244,10 +556,70 @@ pub fn register_build_info_metrics(registry: &Registry) -> prometheus::Result<()
 }
 
 /// Render the metrics from a `prometheus::Registry` into an HTTP response.
-pub fn render(registry: Registry) -> impl IntoResponse {
+/// The groups of metrics which can be requested separately from the metrics endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsGroup {
+    /// Metrics about the server itself, such as HTTP requests, the process and its caches, which
+    /// are cheap to collect.
+    Http,
+    /// Metrics about the contents of the repository, which may require scanning it.
+    Repository,
+}
+
+impl MetricsGroup {
+    pub const ALL: &'static [MetricsGroup] = &[MetricsGroup::Http, MetricsGroup::Repository];
+}
+
+impl std::str::FromStr for MetricsGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(MetricsGroup::Http),
+            "repository" => Ok(MetricsGroup::Repository),
+            _ => Err(format!(
+                "unknown metrics collector '{}', expected 'http' or 'repository'",
+                s
+            )),
+        }
+    }
+}
+
+/// A registry for each group of metrics, so that one group can be gathered without collecting
+/// the others.
+#[derive(Clone)]
+pub struct MetricsRegistries {
+    pub http: Registry,
+    pub repository: Registry,
+}
+
+impl MetricsRegistries {
+    pub fn new() -> MetricsRegistries {
+        MetricsRegistries {
+            http: Registry::new(),
+            repository: Registry::new(),
+        }
+    }
+
+    fn get(&self, group: MetricsGroup) -> &Registry {
+        match group {
+            MetricsGroup::Http => &self.http,
+            MetricsGroup::Repository => &self.repository,
+        }
+    }
+}
+
+pub fn render(registries: &MetricsRegistries, groups: &[MetricsGroup]) -> impl IntoResponse {
+    // Each group is only gathered once, however many times it is asked for.
+    let mut metrics: Vec<_> = MetricsGroup::ALL
+        .iter()
+        .filter(|group| groups.contains(group))
+        .flat_map(|group| registries.get(*group).gather())
+        .collect();
+    metrics.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
     let mut buffer = vec![];
     let encoder = prometheus::TextEncoder::new();
-    let metrics = registry.gather();
     encoder.encode(&metrics, &mut buffer).unwrap();
 
     let headers = [(axum::http::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)];
@@ -257,6 +629,7 @@ pub fn render(registry: Registry) -> impl IntoResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config;
     use crate::hash::hash_data;
     use crate::hash::HashAlgorithm;
     use crate::metadata::{add_metadata, add_packet};
@@ -266,14 +639,25 @@ mod tests {
     use axum::body::Body;
     use axum::http::StatusCode;
     use axum::Router;
-    use std::sync::Arc;
     use tokio::sync::Barrier;
     use tower::Service;
 
+    #[test]
+    fn can_parse_namespace() {
+        assert_eq!(parse_namespace("outpack_2").unwrap(), "outpack_2");
+        assert_eq!(parse_namespace("_private").unwrap(), "_private");
+        assert_eq!(
+            parse_namespace("").unwrap_err(),
+            "'' is not a valid metrics namespace"
+        );
+        assert!(parse_namespace("2outpack").is_err());
+        assert!(parse_namespace("outpack-server").is_err());
+    }
+
     #[test]
     fn repository_collector_empty_repo() {
         let root = get_empty_outpack_root();
-        let collector = RepositoryMetrics::new(root);
+        let collector = RepositoryMetrics::new(&root, IdIndex::new(&root), DEFAULT_NAMESPACE);
 
         assert_eq!(collector.metadata_total.get(), 0);
         assert_eq!(collector.packets_total.get(), 0);
@@ -281,10 +665,46 @@ mod tests {
         assert_eq!(collector.file_size_bytes_total.get(), 0);
     }
 
+    #[test]
+    fn repository_metrics_can_be_refreshed_in_background() {
+        let root = get_empty_outpack_root();
+        let data = b"Testing 123";
+        put_file(
+            &root,
+            data,
+            &hash_data(data, HashAlgorithm::Sha256).to_string(),
+        )
+        .unwrap();
+
+        let collector = RepositoryMetrics::new(&root, IdIndex::new(&root), DEFAULT_NAMESPACE)
+            .refresh_in_background(Duration::from_secs(3600));
+        let start = Instant::now();
+        while !collector.refreshed.load(Ordering::Acquire) {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "metrics not refreshed"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(collector.collect().len(), 4);
+        assert_eq!(collector.metrics.files_total.get(), 1);
+
+        // Collecting reports the values from the last refresh, without looking at the store.
+        let data = b"More data";
+        put_file(
+            &root,
+            data,
+            &hash_data(data, HashAlgorithm::Sha256).to_string(),
+        )
+        .unwrap();
+        collector.collect();
+        assert_eq!(collector.metrics.files_total.get(), 1);
+    }
+
     #[tokio::test]
     async fn repository_collector_files() {
         let root = get_empty_outpack_root();
-        let collector = RepositoryMetrics::new(&root);
+        let collector = RepositoryMetrics::new(&root, IdIndex::new(&root), DEFAULT_NAMESPACE);
 
         let data1 = b"Testing 123";
         let hash1 = hash_data(data1, HashAlgorithm::Sha256).to_string();
@@ -305,7 +725,7 @@ mod tests {
     #[test]
     fn repository_collector_packets() {
         let root = get_empty_outpack_root();
-        let collector = RepositoryMetrics::new(&root);
+        let collector = RepositoryMetrics::new(&root, IdIndex::new(&root), DEFAULT_NAMESPACE);
 
         // Create two different packets.
         // One of them is actually added to the repository.
@@ -313,7 +733,7 @@ mod tests {
         let (_, packet1, hash1) = start_packet("hello").finish();
         let (_, packet2, hash2) = start_packet("hello").finish();
 
-        add_packet(&root, &packet1, &hash1).unwrap();
+        add_packet(&root, &packet1, &hash1, config::LOCAL_LOCATION).unwrap();
         add_metadata(&root, &packet2, &hash2).unwrap();
 
         collector.update().unwrap();
@@ -324,7 +744,7 @@ mod tests {
     #[tokio::test]
     async fn http_metrics() {
         use axum::routing::{get, post};
-        let metrics = HttpMetrics::new();
+        let metrics = HttpMetrics::new(DEFAULT_HTTP_NAMESPACE, DEFAULT_DURATION_BUCKETS).unwrap();
 
         let mut router = Router::<()>::new()
             .route("/", get(()))
@@ -357,6 +777,47 @@ mod tests {
         assert_eq!(get_metric(&["/match/:id", "GET", "200"]), 2);
     }
 
+    #[tokio::test]
+    async fn http_size_metrics() {
+        use axum::routing::post;
+        let metrics = HttpMetrics::new(DEFAULT_HTTP_NAMESPACE, DEFAULT_DURATION_BUCKETS).unwrap();
+
+        // Echo the request body back as a stream, so that neither body has a known length.
+        let echo = |request: Request| async move {
+            Body::from_stream(request.into_body().into_data_stream())
+        };
+        let mut router = Router::<()>::new()
+            .route("/echo", post(echo))
+            .layer(metrics.layer());
+
+        let chunks: Vec<Result<&str, std::io::Error>> = vec![Ok("Hello, "), Ok("World!")];
+        let request = Request::post("/echo")
+            .body(Body::from_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        let response = router.call(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "Hello, World!");
+
+        let request_size = metrics
+            .request_size_bytes
+            .with_label_values(&["/echo", "POST"]);
+        let response_size = metrics
+            .response_size_bytes
+            .with_label_values(&["/echo", "POST"]);
+        assert_eq!(request_size.get_sample_count(), 1);
+        assert_eq!(request_size.get_sample_sum(), 13.);
+        assert_eq!(response_size.get_sample_count(), 1);
+        assert_eq!(response_size.get_sample_sum(), 13.);
+    }
+
+    #[test]
+    fn http_metrics_require_increasing_buckets() {
+        assert!(HttpMetrics::new(DEFAULT_HTTP_NAMESPACE, &[1.0, 10.0, 300.0]).is_ok());
+        assert!(HttpMetrics::new(DEFAULT_HTTP_NAMESPACE, &[10.0, 1.0]).is_err());
+    }
+
     #[tokio::test]
     async fn http_in_flight_metric() {
         // Testing the in-flight metric needs a bit of coordination, since we need to read the
@@ -369,7 +830,7 @@ mod tests {
 
         use axum::routing::get;
         let request_count = 4;
-        let metrics = HttpMetrics::new();
+        let metrics = HttpMetrics::new(DEFAULT_HTTP_NAMESPACE, DEFAULT_DURATION_BUCKETS).unwrap();
         let barriers = Arc::new((
             Barrier::new(request_count + 1),
             Barrier::new(request_count + 1),
@@ -1,5 +1,6 @@
 mod query_eval;
 mod query_format;
+mod query_json;
 mod query_parse;
 mod query_types;
 
@@ -8,40 +9,117 @@ mod test_utils_query;
 #[cfg(feature = "python")]
 mod python;
 
-use crate::index::get_packet_index;
+use crate::index::{get_packet_index, Index};
+use crate::metadata::Packet;
 use crate::query::query_eval::eval_query;
 use crate::query::query_format::format_query_result;
+pub use crate::query::query_json::query_to_json;
 pub use crate::query::query_parse::parse_query;
 use crate::query::query_parse::Rule;
+use pest::error::{InputLocation, LineColLocation};
 use std::path::Path;
 
 use thiserror::Error;
 
+fn get_index(root: &Path) -> Result<Index, QueryError> {
+    get_packet_index(root).map_err(|e| {
+        QueryError::EvalError(format!(
+            "Could not build outpack index from root at {}: {:?}",
+            root.display(),
+            e
+        ))
+    })
+}
+
 pub fn run_query(root: &Path, query: &str) -> Result<String, QueryError> {
-    let index = match get_packet_index(root) {
-        Ok(index) => index,
-        Err(e) => {
-            return Err(QueryError::EvalError(format!(
-                "Could not build outpack index from root at {}: {:?}",
-                root.display(),
-                e
-            )))
-        }
-    };
+    let index = get_index(root)?;
     let parsed = parse_query(query)?;
     let result = eval_query(&index, parsed);
     format_query_result(result)
 }
 
+/// The ids of the packets matching a query, in no particular order.
+pub fn query_ids(root: &Path, query: &str) -> Result<Vec<String>, QueryError> {
+    let index = get_index(root)?;
+    let parsed = parse_query(query)?;
+    let packets = eval_query(&index, parsed)?;
+    Ok(packets
+        .into_iter()
+        .map(|packet| packet.id.clone())
+        .collect())
+}
+
+/// The packets matching a query, in no particular order.
+pub fn query_packets(root: &Path, query: &str) -> Result<Vec<Packet>, QueryError> {
+    let index = get_index(root)?;
+    let parsed = parse_query(query)?;
+    let packets = eval_query(&index, parsed)?;
+    Ok(packets.into_iter().cloned().collect())
+}
+
 // pest's error type is quite large, which would consume a lot of stack space and require moving
 // data around, even in the happy path when an Ok is returned. We want to keep this as small as
 // possible so Box the large error body to force it onto the heap. The heap memory allocation cost
 // is only incurred when an actual error is returned.
 // See https://rust-lang.github.io/rust-clippy/master/index.html#result_large_err
-#[derive(Error, Debug, Clone)]
-#[error(transparent)]
+#[derive(Debug, Clone)]
 pub struct ParseError(Box<pest::error::Error<Rule>>);
 
+impl ParseError {
+    /// The byte offset into the query at which the error was found.
+    pub fn offset(&self) -> usize {
+        match self.0.location {
+            InputLocation::Pos(pos) => pos,
+            InputLocation::Span((start, _)) => start,
+        }
+    }
+
+    /// The line and column, both counted from 1, at which the error was found.
+    pub fn line_col(&self) -> (usize, usize) {
+        match self.0.line_col {
+            LineColLocation::Pos(pos) => pos,
+            LineColLocation::Span(start, _) => start,
+        }
+    }
+
+    /// What was wrong, without its position.
+    pub fn message(&self) -> String {
+        self.0.variant.message().into_owned()
+    }
+
+    /// How many characters of the line to underline: the whole span if it is on one line, or
+    /// else just the first.
+    fn width(&self) -> usize {
+        match self.0.line_col {
+            LineColLocation::Span((start_line, start_col), (end_line, end_col))
+                if start_line == end_line && end_col > start_col =>
+            {
+                end_col - start_col
+            }
+            _ => 1,
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Shows the message and its position, followed by the line of the query with the offending
+/// token underlined, e.g.
+///
+/// ```text
+/// expected lookup or literal at line 1, column 9
+/// name ==
+///         ^
+/// ```
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (line, column) = self.line_col();
+        writeln!(f, "{} at line {}, column {}", self.message(), line, column)?;
+        writeln!(f, "{}", self.0.line().trim_end_matches(['\r', '\n']))?;
+        write!(f, "{}{}", " ".repeat(column - 1), "^".repeat(self.width()))
+    }
+}
+
 #[derive(Error, Debug, Clone)]
 pub enum QueryError {
     #[error("Failed to parse query\n{0}")]
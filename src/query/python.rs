@@ -39,6 +39,8 @@ struct Classes {
     Negation: PyObject,
     Brackets: PyObject,
     Test: PyObject,
+    HasFile: PyObject,
+    Has: PyObject,
     BooleanExpr: PyObject,
 
     Literal: PyObject,
@@ -47,6 +49,8 @@ struct Classes {
     LookupParameter: PyObject,
     LookupId: PyObject,
     LookupName: PyObject,
+    LookupTime: PyObject,
+    LookupCustom: PyObject,
 }
 
 lazy_static! {
@@ -67,6 +71,8 @@ lazy_static! {
                 Brackets: make_dataclass("Brackets", &["inner"]),
                 Negation: make_dataclass("Negation", &["inner"]),
                 Test: make_dataclass("Test", &["operator", "lhs", "rhs"]),
+                HasFile: make_dataclass("HasFile", &["field", "value"]),
+                Has: make_dataclass("Has", &["lookup"]),
                 BooleanExpr: make_dataclass("BooleanExpr", &["operator", "lhs", "rhs"]),
 
                 Literal: make_dataclass("Literal", &["value"]),
@@ -75,6 +81,8 @@ lazy_static! {
                 LookupParameter: make_dataclass("LookupParameter", &["name"]),
                 LookupId: make_dataclass("LookupId", &[]),
                 LookupName: make_dataclass("LookupName", &[]),
+                LookupTime: make_dataclass("LookupTime", &["field"]),
+                LookupCustom: make_dataclass("LookupCustom", &["path"]),
             }
         })
     };
@@ -110,6 +118,8 @@ fn outpack_query_parser(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add("Brackets", &CLASSES.Brackets)?;
     m.add("Negation", &CLASSES.Negation)?;
     m.add("Test", &CLASSES.Test)?;
+    m.add("HasFile", &CLASSES.HasFile)?;
+    m.add("Has", &CLASSES.Has)?;
     m.add("BooleanExpr", &CLASSES.BooleanExpr)?;
 
     m.add("Literal", &CLASSES.Literal)?;
@@ -118,6 +128,8 @@ fn outpack_query_parser(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add("LookupParameter", &CLASSES.LookupParameter)?;
     m.add("LookupId", &CLASSES.LookupId)?;
     m.add("LookupName", &CLASSES.LookupName)?;
+    m.add("LookupTime", &CLASSES.LookupTime)?;
+    m.add("LookupCustom", &CLASSES.LookupCustom)?;
 
     // PyO3's `#[pyclass]` does a decent job of generating idiomatic code for enums that don't have
     // any data. We can just use these rather than eg. calling the Python `enum` package.
@@ -126,9 +138,20 @@ fn outpack_query_parser(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     Ok(())
 }
 
+/// Parse errors are raised as a `ValueError`, with the position of the error in the query as
+/// its `offset` (in bytes), `line` and `column` attributes.
 impl From<ParseError> for PyErr {
     fn from(err: ParseError) -> PyErr {
-        PyValueError::new_err(err.to_string())
+        let exception = PyValueError::new_err(err.to_string());
+        Python::with_gil(|py| {
+            let value = exception.value(py);
+            let (line, column) = err.line_col();
+            value
+                .setattr("offset", err.offset())
+                .and_then(|()| value.setattr("line", line))
+                .and_then(|()| value.setattr("column", column))
+                .map_or_else(|e| e, |()| exception)
+        })
     }
 }
 
@@ -149,6 +172,10 @@ fn convert_query(py: Python, query: QueryNode) -> PyResult<PyObject> {
             ),
         ),
 
+        QueryNode::HasFile(field, value) => CLASSES.HasFile.call1(py, (field.as_str(), value)),
+
+        QueryNode::Has(lookup) => CLASSES.Has.call1(py, (convert_lookup(py, lookup)?,)),
+
         QueryNode::BooleanExpr(operator, lhs, rhs) => CLASSES.BooleanExpr.call1(
             py,
             (
@@ -160,23 +187,30 @@ fn convert_query(py: Python, query: QueryNode) -> PyResult<PyObject> {
     }
 }
 
+fn convert_lookup(py: Python, lookup: Lookup) -> PyResult<PyObject> {
+    match lookup {
+        Lookup::Packet(PacketLookup::Name) => CLASSES.LookupName.call0(py),
+        Lookup::Packet(PacketLookup::Id) => CLASSES.LookupId.call0(py),
+        Lookup::Packet(PacketLookup::Parameter(name)) => CLASSES.LookupParameter.call1(py, (name,)),
+        Lookup::Packet(PacketLookup::Time(field)) => {
+            CLASSES.LookupTime.call1(py, (field.as_str(),))
+        }
+        Lookup::Packet(PacketLookup::Custom(path)) => CLASSES.LookupCustom.call1(py, (path,)),
+        Lookup::This(name) => CLASSES.LookupThis.call1(py, (name,)),
+        Lookup::Environment(name) => CLASSES.LookupEnvironment.call1(py, (name,)),
+    }
+}
+
 fn convert_test_value(py: Python, test_value: TestValue) -> PyResult<PyObject> {
     match test_value {
-        TestValue::Lookup(Lookup::Packet(PacketLookup::Name)) => CLASSES.LookupName.call0(py),
-        TestValue::Lookup(Lookup::Packet(PacketLookup::Id)) => CLASSES.LookupId.call0(py),
-        TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter(name))) => {
-            CLASSES.LookupParameter.call1(py, (name,))
-        }
-        TestValue::Lookup(Lookup::This(name)) => CLASSES.LookupThis.call1(py, (name,)),
-        TestValue::Lookup(Lookup::Environment(name)) => {
-            CLASSES.LookupEnvironment.call1(py, (name,))
-        }
+        TestValue::Lookup(lookup) => convert_lookup(py, lookup),
 
         TestValue::Literal(literal) => {
             let value = match literal {
                 Literal::Bool(b) => b.to_object(py),
                 Literal::String(s) => s.to_object(py),
                 Literal::Number(n) => n.to_object(py),
+                Literal::Null => py.None(),
             };
             CLASSES.Literal.call1(py, (value,))
         }
@@ -12,6 +12,8 @@ pub fn eval_query<'a>(index: &'a Index, query: QueryNode) -> Result<Vec<&'a Pack
         QueryNode::Latest(inner) => eval_latest(index, inner),
         QueryNode::Single(inner) => eval_single(index, *inner),
         QueryNode::Test(test, lhs, rhs) => eval_test(index, test, lhs, rhs),
+        QueryNode::HasFile(field, value) => Ok(eval_has_file(index, field, value)),
+        QueryNode::Has(lookup) => eval_has(index, lookup),
         QueryNode::Negation(inner) => eval_negation(index, *inner),
         QueryNode::Brackets(inner) => eval_brackets(index, *inner),
         QueryNode::BooleanExpr(op, lhs, rhs) => eval_boolean_op(index, op, *lhs, *rhs),
@@ -22,8 +24,8 @@ fn eval_latest<'a>(
     index: &'a Index,
     inner: Option<Box<QueryNode>>,
 ) -> Result<Vec<&'a Packet>, QueryError> {
-    if inner.is_some() {
-        let latest = eval_query(index, *inner.unwrap())?;
+    if let Some(inner) = inner {
+        let latest = eval_query(index, *inner)?;
         let last = latest.last();
         match last {
             Some(packet) => Ok(vec![*packet]),
@@ -69,6 +71,7 @@ fn eval_test<'a>(
     lhs: TestValue,
     rhs: TestValue,
 ) -> Result<Vec<&'a Packet>, QueryError> {
+    check_test_is_meaningful(&test, &lhs, &rhs)?;
     index
         .packets
         .iter()
@@ -80,6 +83,57 @@ fn eval_test<'a>(
         .collect()
 }
 
+fn eval_has_file<'a>(index: &'a Index, field: FileField, value: &str) -> Vec<&'a Packet> {
+    index
+        .packets
+        .iter()
+        .filter(|packet| {
+            packet.files.iter().any(|file| match field {
+                FileField::Hash => file.hash == value,
+                FileField::Path => file.path == value,
+            })
+        })
+        .collect()
+}
+
+fn eval_has<'a>(index: &'a Index, lookup: Lookup) -> Result<Vec<&'a Packet>, QueryError> {
+    let Lookup::Packet(lookup) = lookup else {
+        return Err(QueryError::EvalError(format!(
+            "{} is not supported in has() in this context",
+            lookup
+        )));
+    };
+    Ok(index
+        .packets
+        .iter()
+        .filter(|packet| packet.has_value(&lookup))
+        .collect())
+}
+
+/// Packet ids and names are only ever matched exactly, so ordered comparisons against them are
+/// rejected up front rather than silently comparing strings.
+fn check_test_is_meaningful(
+    test: &TestOperator,
+    lhs: &TestValue,
+    rhs: &TestValue,
+) -> Result<(), QueryError> {
+    if matches!(test, TestOperator::Equal | TestOperator::NotEqual) {
+        return Ok(());
+    }
+    for value in [lhs, rhs] {
+        let field = match value {
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Id)) => "id",
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Name)) => "name",
+            _ => continue,
+        };
+        return Err(QueryError::EvalError(format!(
+            "Packet {} can only be compared using == or !=",
+            field
+        )));
+    }
+    Ok(())
+}
+
 fn lookup_filter(
     packet: &Packet,
     test: &TestOperator,
@@ -130,21 +184,59 @@ fn evaluate_lookup<'a>(
 }
 
 impl Packet {
-    pub fn lookup_value(&self, lookup: &PacketLookup) -> Option<Literal> {
+    pub fn lookup_value(&self, lookup: &PacketLookup) -> Option<Literal<'_>> {
         match lookup {
             PacketLookup::Id => Some(Literal::String(&self.id)),
             PacketLookup::Name => Some(Literal::String(&self.name)),
             PacketLookup::Parameter(param_name) => self.get_parameter(param_name),
+            PacketLookup::Time(TimeLookup::Start) => Some(Literal::Number(self.time.start)),
+            PacketLookup::Time(TimeLookup::End) => Some(Literal::Number(self.time.end)),
+            PacketLookup::Custom(path) => self.get_custom(path),
+        }
+    }
+
+    /// Whether a lookup has any value for the packet, even one which no test can match, such as a
+    /// null parameter or an object in the custom metadata.
+    pub fn has_value(&self, lookup: &PacketLookup) -> bool {
+        match lookup {
+            PacketLookup::Id | PacketLookup::Name | PacketLookup::Time(_) => true,
+            PacketLookup::Parameter(param_name) => self
+                .parameters
+                .as_ref()
+                .is_some_and(|params| params.contains_key(*param_name)),
+            PacketLookup::Custom(path) => self.custom.as_ref().is_some_and(|custom| {
+                path.split('.')
+                    .try_fold(custom, |value, key| value.get(key))
+                    .is_some()
+            }),
+        }
+    }
+
+    /// Look up a value in the packet's custom metadata by a dotted path. A path which doesn't
+    /// exist, or which leads to an object, array or null, has no value, so never matches.
+    pub fn get_custom(&self, path: &str) -> Option<Literal<'_>> {
+        let value = path
+            .split('.')
+            .try_fold(self.custom.as_ref()?, |value, key| value.get(key))?;
+        match value {
+            JsonValue::Number(number) => Some(Literal::Number(number.as_f64()?)),
+            JsonValue::Bool(bool) => Some(Literal::Bool(*bool)),
+            JsonValue::String(string) => Some(Literal::String(string)),
+            _ => None,
         }
     }
 
-    pub fn get_parameter(&self, param_name: &str) -> Option<Literal> {
+    /// Look up a parameter of the packet. A parameter which is present with a null value is
+    /// distinct from one which is absent: the former is `Literal::Null`, and so only equals a
+    /// `null` literal, while the latter has no value and never matches.
+    pub fn get_parameter(&self, param_name: &str) -> Option<Literal<'_>> {
         if let Some(params) = &self.parameters {
             match params.get(param_name)? {
                 JsonValue::Number(number) => Some(Literal::Number(number.as_f64()?)),
                 JsonValue::Bool(bool) => Some(Literal::Bool(*bool)),
                 JsonValue::String(string) => Some(Literal::String(string)),
-                _ => None, // Parameters must be number, bool or string
+                JsonValue::Null => Some(Literal::Null),
+                _ => None, // Parameters must be number, bool, string or null
             }
         } else {
             None
@@ -170,8 +262,9 @@ fn eval_boolean_op<'a>(
 
 #[cfg(test)]
 mod tests {
-    use crate::metadata::get_metadata_from_date;
-    use crate::test_utils::tests::assert_packet_ids_eq;
+    use crate::metadata::{get_metadata_from_date, TimeRange};
+    use crate::query::parse_query;
+    use crate::test_utils::tests::{assert_packet_ids_eq, get_empty_outpack_root, start_packet};
 
     use super::*;
     use std::path::Path;
@@ -257,7 +350,8 @@ mod tests {
 
     #[test]
     fn can_get_parameter_as_literal() {
-        let packets = get_metadata_from_date(Path::new("tests/example"), None).unwrap();
+        let packets =
+            get_metadata_from_date(Path::new("tests/example"), TimeRange::default()).unwrap();
         assert_eq!(packets.len(), 4);
 
         let matching_packets: Vec<Packet> = packets
@@ -281,7 +375,8 @@ mod tests {
 
     #[test]
     fn can_test_lookup_filter() {
-        let packets = get_metadata_from_date(Path::new("tests/example"), None).unwrap();
+        let packets =
+            get_metadata_from_date(Path::new("tests/example"), TimeRange::default()).unwrap();
         assert_eq!(packets.len(), 4);
 
         let matching_packets: Vec<Packet> = packets
@@ -372,6 +467,81 @@ mod tests {
         );
     }
 
+    #[test]
+    fn null_parameter_is_distinct_from_absent_parameter() {
+        let packets =
+            get_metadata_from_date(Path::new("tests/example"), TimeRange::default()).unwrap();
+        let mut packet = packets
+            .into_iter()
+            .find(|e| e.id == "20180220-095832-16a4bbed")
+            .unwrap();
+        packet
+            .parameters
+            .as_mut()
+            .unwrap()
+            .insert("nothing".to_string(), JsonValue::Null);
+
+        assert_eq!(packet.get_parameter("nothing"), Some(Literal::Null));
+        assert_eq!(packet.get_parameter("missing"), None);
+
+        let nothing = TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("nothing")));
+        let missing = TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("missing")));
+        let disease = TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease")));
+        let null = TestValue::Literal(Literal::Null);
+
+        let eval = |test, lhs, rhs| lookup_filter(&packet, &test, lhs, rhs).unwrap();
+        assert!(eval(TestOperator::Equal, &nothing, &null));
+        assert!(!eval(TestOperator::NotEqual, &nothing, &null));
+        assert!(!eval(TestOperator::Equal, &missing, &null));
+        assert!(!eval(TestOperator::NotEqual, &missing, &null));
+        assert!(!eval(TestOperator::Equal, &disease, &null));
+        assert!(eval(TestOperator::NotEqual, &disease, &null));
+        assert!(!eval(
+            TestOperator::Equal,
+            &nothing,
+            &TestValue::Literal(Literal::Bool(false))
+        ));
+        assert!(!eval(TestOperator::LessThan, &nothing, &null));
+        assert!(!eval(TestOperator::GreaterThanOrEqual, &nothing, &null));
+    }
+
+    #[test]
+    fn has_distinguishes_null_parameters_from_absent_ones() {
+        let mut index = crate::index::get_packet_index(Path::new("tests/example")).unwrap();
+        let packet = index
+            .packets
+            .iter_mut()
+            .find(|e| e.id == "20180220-095832-16a4bbed")
+            .unwrap();
+        packet
+            .parameters
+            .as_mut()
+            .unwrap()
+            .insert("nothing".to_string(), JsonValue::Null);
+
+        let has = |lookup| eval_query(&index, QueryNode::Has(Lookup::Packet(lookup))).unwrap();
+        assert_packet_ids_eq(
+            has(PacketLookup::Parameter("nothing")),
+            vec!["20180220-095832-16a4bbed"],
+        );
+        assert_packet_ids_eq(has(PacketLookup::Parameter("missing")), vec![]);
+        assert_packet_ids_eq(
+            has(PacketLookup::Parameter("disease")),
+            vec![
+                "20170818-164830-33e0ab01",
+                "20180220-095832-16a4bbed",
+                "20180818-164043-7cdcde4b",
+            ],
+        );
+        assert_eq!(has(PacketLookup::Name).len(), 4);
+        // The path leads to an array, which can't be tested but is present.
+        assert_eq!(has(PacketLookup::Custom("orderly.artefacts")).len(), 4);
+        assert_packet_ids_eq(has(PacketLookup::Custom("orderly.missing")), vec![]);
+
+        let res = eval_query(&index, QueryNode::Has(Lookup::This("x")));
+        assert!(matches!(res, Err(QueryError::EvalError(_))));
+    }
+
     #[test]
     fn can_use_different_test_types() {
         let index = crate::index::get_packet_index(Path::new("tests/example")).unwrap();
@@ -492,6 +662,138 @@ mod tests {
         assert_eq!(res.len(), 0);
     }
 
+    #[test]
+    fn can_test_id_and_name_for_inequality() {
+        let index = crate::index::get_packet_index(Path::new("tests/example")).unwrap();
+
+        let res = eval_query(
+            &index,
+            parse_query(r#"id != "20180818-164043-7cdcde4b""#).unwrap(),
+        );
+        let mut ids: Vec<&str> = res.unwrap().iter().map(|p| p.id.as_str()).collect();
+        ids.sort();
+        assert_eq!(
+            ids,
+            vec![
+                "20170818-164830-33e0ab01",
+                "20170818-164847-7574883b",
+                "20180220-095832-16a4bbed"
+            ]
+        );
+
+        let res = eval_query(
+            &index,
+            parse_query(r#"name != "modup-201707-queries1""#).unwrap(),
+        );
+        assert!(res
+            .unwrap()
+            .iter()
+            .all(|p| p.name != "modup-201707-queries1"));
+
+        let latest = index.packets.last().unwrap();
+        let query = format!(r#"name != "{}" && latest()"#, latest.name);
+        let res = eval_query(&index, parse_query(&query).unwrap()).unwrap();
+        assert_eq!(res.len(), 0);
+
+        let res = eval_query(&index, parse_query(r#"name != "x" && latest()"#).unwrap()).unwrap();
+        assert_packet_ids_eq(res, vec![latest.id.as_str()]);
+
+        let res = eval_query(
+            &index,
+            parse_query(r#"name != "x" || id == "20170818-164830-33e0ab01""#).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res.len(), index.packets.len());
+    }
+
+    #[test]
+    fn can_compare_two_packet_fields() {
+        let index = crate::index::get_packet_index(Path::new("tests/example")).unwrap();
+        let eval = |query: &str| {
+            let mut ids: Vec<String> = eval_query(&index, parse_query(query).unwrap())
+                .unwrap()
+                .iter()
+                .map(|p| p.id.clone())
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(
+            eval("time.start == time.end"),
+            vec![
+                "20170818-164830-33e0ab01",
+                "20170818-164847-7574883b",
+                "20180818-164043-7cdcde4b"
+            ]
+        );
+        assert_eq!(
+            eval("time.start < time.end"),
+            vec!["20180220-095832-16a4bbed"]
+        );
+        assert_eq!(
+            eval("time.end > time.start && parameter:size >= parameter:size").len(),
+            1
+        );
+
+        // A field which is missing on either side never matches.
+        assert_eq!(eval("parameter:disease == parameter:missing").len(), 0);
+        assert_eq!(eval("parameter:disease != parameter:missing").len(), 0);
+        assert_eq!(eval("parameter:size > time.start").len(), 0);
+        assert_eq!(eval("time.start > parameter:size").len(), 1);
+    }
+
+    #[test]
+    fn can_select_packets_by_file() {
+        let index = crate::index::get_packet_index(Path::new("tests/example")).unwrap();
+        let count = |query: &str| {
+            eval_query(&index, parse_query(query).unwrap())
+                .unwrap()
+                .len()
+        };
+
+        // Every packet in the example repository has the same files.
+        let hash = "sha256:8916131d0bebabc5ab098ae3a34b03389768e1ef15acba74ea265deddeba579f";
+        assert_eq!(count(&format!("hasfile({:?})", hash)), 4);
+        assert_eq!(count(&format!("hasfile(hash == {:?})", hash)), 4);
+        assert_eq!(count(r#"hasfile("sha256:0000")"#), 0);
+        assert_eq!(count(r#"hasfile(path == "orderly.yml")"#), 4);
+        assert_eq!(count(r#"hasfile(path == "data.csv")"#), 0);
+        // Paths and hashes aren't interchangeable.
+        assert_eq!(count(r#"hasfile("orderly.yml")"#), 0);
+
+        let res = eval_query(
+            &index,
+            parse_query(r#"hasfile(path == "report.pdf") && name == "modup-201707-params1""#)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+    }
+
+    #[test]
+    fn ordered_comparisons_on_id_and_name_fail() {
+        let index = crate::index::get_packet_index(Path::new("tests/example")).unwrap();
+        for query in [
+            r#"id < "x""#,
+            r#"id >= "20180818-164043-7cdcde4b""#,
+            r#""x" > name"#,
+            r#"name <= "x" && latest()"#,
+        ] {
+            let e = eval_query(&index, parse_query(query).unwrap()).unwrap_err();
+            assert!(matches!(e, QueryError::EvalError(..)));
+            assert!(e
+                .to_string()
+                .contains("can only be compared using == or !="));
+        }
+
+        // Even when there are no packets to compare against.
+        let root = get_empty_outpack_root();
+        let index = crate::index::get_packet_index(&root).unwrap();
+        let e = eval_query(&index, parse_query(r#"id < "x""#).unwrap()).unwrap_err();
+        assert!(matches!(e, QueryError::EvalError(..)));
+    }
+
     #[test]
     fn query_does_no_type_coersion() {
         let index = crate::index::get_packet_index(Path::new("tests/example")).unwrap();
@@ -626,6 +928,31 @@ mod tests {
             .contains("Query found 3 packets, but expected exactly one"));
     }
 
+    #[test]
+    fn latest_is_empty_but_single_fails_on_empty_repo() {
+        let root = get_empty_outpack_root();
+        let index = crate::index::get_packet_index(&root).unwrap();
+
+        let res = eval_query(&index, QueryNode::Latest(None)).unwrap();
+        assert_eq!(res.len(), 0);
+
+        let inner_query = QueryNode::Test(
+            TestOperator::Equal,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
+            TestValue::Literal(Literal::String("data")),
+        );
+        let query = QueryNode::Latest(Some(Box::new(inner_query)));
+        let res = eval_query(&index, query).unwrap();
+        assert_eq!(res.len(), 0);
+
+        let query = QueryNode::Single(Box::new(QueryNode::Latest(None)));
+        let e = eval_query(&index, query).unwrap_err();
+        assert!(matches!(e, QueryError::EvalError(..)));
+        assert!(e
+            .to_string()
+            .contains("Query found 0 packets, but expected exactly one"));
+    }
+
     #[test]
     fn query_with_this_fails() {
         let index = crate::index::get_packet_index(Path::new("tests/example")).unwrap();
@@ -651,4 +978,91 @@ mod tests {
         let e = eval_query(&index, query).unwrap_err();
         assert!(matches!(e, QueryError::EvalError(..)));
     }
+
+    /// Build an index with one packet for each combination of the boolean
+    /// parameters `a`, `b` and `c`.
+    fn truth_table_index() -> Index {
+        let mut packets = Vec::new();
+        for i in 0..8 {
+            let (_, json, _) = start_packet("truth")
+                .parameter("a", i & 4 != 0)
+                .parameter("b", i & 2 != 0)
+                .parameter("c", i & 1 != 0)
+                .finish();
+            packets.push(serde_json::from_str::<Packet>(&json).unwrap());
+        }
+        Index { packets }
+    }
+
+    fn get_bool_parameter(packet: &Packet, name: &str) -> bool {
+        packet.parameters.as_ref().unwrap()[name].as_bool().unwrap()
+    }
+
+    /// Evaluate the query against the truth table index, and check it
+    /// selects exactly those packets for which `expected` holds.
+    fn assert_query_matches(query: &str, expected: impl Fn(bool, bool, bool) -> bool) {
+        let index = truth_table_index();
+        let mut res: Vec<&str> = eval_query(&index, parse_query(query).unwrap())
+            .unwrap()
+            .iter()
+            .map(|p| p.id.as_str())
+            .collect();
+        res.sort();
+
+        let mut wanted: Vec<&str> = index
+            .packets
+            .iter()
+            .filter(|p| {
+                expected(
+                    get_bool_parameter(p, "a"),
+                    get_bool_parameter(p, "b"),
+                    get_bool_parameter(p, "c"),
+                )
+            })
+            .map(|p| p.id.as_str())
+            .collect();
+        wanted.sort();
+
+        assert_eq!(res, wanted, "unexpected result for query '{}'", query);
+    }
+
+    const A: &str = "parameter:a == true";
+    const B: &str = "parameter:b == true";
+    const C: &str = "parameter:c == true";
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_query_matches(&format!("{A} || {B} && {C}"), |a, b, c| a || (b && c));
+        assert_query_matches(&format!("{A} && {B} || {C}"), |a, b, c| (a && b) || c);
+        assert_query_matches(&format!("({A} || {B}) && {C}"), |a, b, c| (a || b) && c);
+        assert_query_matches(&format!("{A} && ({B} || {C})"), |a, b, c| a && (b || c));
+    }
+
+    #[test]
+    fn negation_binds_tighter_than_binary_operators() {
+        assert_query_matches(&format!("!{A} && {B}"), |a, b, _| !a && b);
+        assert_query_matches(&format!("!{A} || {B}"), |a, b, _| !a || b);
+        assert_query_matches(&format!("{A} && !{B}"), |a, b, _| a && !b);
+        assert_query_matches(&format!("!({A} && {B})"), |a, b, _| !(a && b));
+        assert_query_matches(&format!("!({A} || {B}) && {C}"), |a, b, c| !(a || b) && c);
+        assert_query_matches(&format!("{A} || !{B} && {C}"), |a, b, c| a || (!b && c));
+    }
+
+    #[test]
+    fn negation_can_be_repeated() {
+        assert_query_matches(&format!("!!{A}"), |a, _, _| a);
+        assert_query_matches(&format!("!!{A} && !{B}"), |a, b, _| a && !b);
+    }
+
+    #[test]
+    fn boolean_operators_chain() {
+        assert_query_matches(&format!("{A} && {B} && {C}"), |a, b, c| a && b && c);
+        assert_query_matches(&format!("{A} || {B} || {C}"), |a, b, c| a || b || c);
+        assert_query_matches(&format!("{A} && !{B} || !{A} && {C}"), |a, b, c| {
+            (a && !b) || (!a && c)
+        });
+        assert_query_matches(&format!("{A} && {B} || {C} && !{A}"), |a, b, c| {
+            (a && b) || (c && !a)
+        });
+    }
 }
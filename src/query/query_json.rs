@@ -0,0 +1,207 @@
+//! Conversion of a parsed query into a JSON representation.
+//!
+//! The shape of the JSON mirrors the classes exposed by the Python bindings: each node is an
+//! object whose `type` field is the name of the corresponding Python class, and whose remaining
+//! fields match the dataclass fields. Operators are serialised using the same names as the
+//! Python `TestOperator` and `BooleanOperator` enums.
+
+use serde_json::{json, Value};
+
+use crate::query::query_types::{Literal, Lookup, PacketLookup, QueryNode, TestValue};
+
+pub fn query_to_json(query: &QueryNode) -> Value {
+    match query {
+        QueryNode::Latest(None) => json!({ "type": "Latest", "inner": null }),
+        QueryNode::Latest(Some(inner)) => {
+            json!({ "type": "Latest", "inner": query_to_json(inner) })
+        }
+        QueryNode::Single(inner) => json!({ "type": "Single", "inner": query_to_json(inner) }),
+        QueryNode::Negation(inner) => json!({ "type": "Negation", "inner": query_to_json(inner) }),
+        QueryNode::Brackets(inner) => json!({ "type": "Brackets", "inner": query_to_json(inner) }),
+        QueryNode::Test(operator, lhs, rhs) => json!({
+            "type": "Test",
+            "operator": operator,
+            "lhs": test_value_to_json(lhs),
+            "rhs": test_value_to_json(rhs),
+        }),
+        QueryNode::HasFile(field, value) => json!({
+            "type": "HasFile",
+            "field": field.as_str(),
+            "value": value,
+        }),
+        QueryNode::Has(lookup) => json!({
+            "type": "Has",
+            "lookup": lookup_to_json(lookup),
+        }),
+        QueryNode::BooleanExpr(operator, lhs, rhs) => json!({
+            "type": "BooleanExpr",
+            "operator": operator,
+            "lhs": query_to_json(lhs),
+            "rhs": query_to_json(rhs),
+        }),
+    }
+}
+
+fn lookup_to_json(lookup: &Lookup) -> Value {
+    match lookup {
+        Lookup::Packet(PacketLookup::Name) => json!({ "type": "LookupName" }),
+        Lookup::Packet(PacketLookup::Id) => json!({ "type": "LookupId" }),
+        Lookup::Packet(PacketLookup::Parameter(name)) => {
+            json!({ "type": "LookupParameter", "name": name })
+        }
+        Lookup::Packet(PacketLookup::Time(field)) => {
+            json!({ "type": "LookupTime", "field": field.as_str() })
+        }
+        Lookup::Packet(PacketLookup::Custom(path)) => {
+            json!({ "type": "LookupCustom", "path": path })
+        }
+        Lookup::This(name) => json!({ "type": "LookupThis", "name": name }),
+        Lookup::Environment(name) => json!({ "type": "LookupEnvironment", "name": name }),
+    }
+}
+
+fn test_value_to_json(test_value: &TestValue) -> Value {
+    match test_value {
+        TestValue::Lookup(lookup) => lookup_to_json(lookup),
+        TestValue::Literal(literal) => {
+            let value = match literal {
+                Literal::Bool(b) => json!(b),
+                Literal::String(s) => json!(s),
+                Literal::Number(n) => json!(n),
+                Literal::Null => Value::Null,
+            };
+            json!({ "type": "Literal", "value": value })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parse_query;
+
+    #[test]
+    fn can_convert_test_to_json() {
+        let res = query_to_json(&parse_query("name == 'foo'").unwrap());
+        assert_eq!(
+            res,
+            json!({
+                "type": "Test",
+                "operator": "Equal",
+                "lhs": { "type": "LookupName" },
+                "rhs": { "type": "Literal", "value": "foo" }
+            })
+        );
+
+        let res = query_to_json(&parse_query("parameter:x >= 2").unwrap());
+        assert_eq!(
+            res,
+            json!({
+                "type": "Test",
+                "operator": "GreaterThanOrEqual",
+                "lhs": { "type": "LookupParameter", "name": "x" },
+                "rhs": { "type": "Literal", "value": 2.0 }
+            })
+        );
+    }
+
+    #[test]
+    fn can_convert_null_literal_to_json() {
+        let res = query_to_json(&parse_query("parameter:x == null").unwrap());
+        assert_eq!(
+            res,
+            json!({
+                "type": "Test",
+                "operator": "Equal",
+                "lhs": { "type": "LookupParameter", "name": "x" },
+                "rhs": { "type": "Literal", "value": null }
+            })
+        );
+    }
+
+    #[test]
+    fn can_convert_hasfile_to_json() {
+        let res = query_to_json(&parse_query(r#"hasfile(path == "data.csv")"#).unwrap());
+        assert_eq!(
+            res,
+            json!({ "type": "HasFile", "field": "path", "value": "data.csv" })
+        );
+    }
+
+    #[test]
+    fn can_convert_has_to_json() {
+        let res = query_to_json(&parse_query("has(parameter:disease)").unwrap());
+        assert_eq!(
+            res,
+            json!({
+                "type": "Has",
+                "lookup": { "type": "LookupParameter", "name": "disease" }
+            })
+        );
+    }
+
+    #[test]
+    fn can_convert_time_lookup_to_json() {
+        let res = query_to_json(&parse_query("time.end < 1600000000").unwrap());
+        assert_eq!(
+            res,
+            json!({
+                "type": "Test",
+                "operator": "LessThan",
+                "lhs": { "type": "LookupTime", "field": "end" },
+                "rhs": { "type": "Literal", "value": 1600000000.0 }
+            })
+        );
+    }
+
+    #[test]
+    fn can_convert_custom_lookup_to_json() {
+        let res = query_to_json(&parse_query(r#"custom:orderly.displayname == "Foo""#).unwrap());
+        assert_eq!(
+            res,
+            json!({
+                "type": "Test",
+                "operator": "Equal",
+                "lhs": { "type": "LookupCustom", "path": "orderly.displayname" },
+                "rhs": { "type": "Literal", "value": "Foo" }
+            })
+        );
+    }
+
+    #[test]
+    fn can_convert_nested_query_to_json() {
+        let res = query_to_json(&parse_query("latest").unwrap());
+        assert_eq!(res, json!({ "type": "Latest", "inner": null }));
+
+        let res =
+            query_to_json(&parse_query(r#"single(!(id == "123" || this:x == true))"#).unwrap());
+        assert_eq!(
+            res,
+            json!({
+                "type": "Single",
+                "inner": {
+                    "type": "Negation",
+                    "inner": {
+                        "type": "Brackets",
+                        "inner": {
+                            "type": "BooleanExpr",
+                            "operator": "Or",
+                            "lhs": {
+                                "type": "Test",
+                                "operator": "Equal",
+                                "lhs": { "type": "LookupId" },
+                                "rhs": { "type": "Literal", "value": "123" }
+                            },
+                            "rhs": {
+                                "type": "Test",
+                                "operator": "Equal",
+                                "lhs": { "type": "LookupThis", "name": "x" },
+                                "rhs": { "type": "Literal", "value": true }
+                            }
+                        }
+                    }
+                }
+            })
+        );
+    }
+}
@@ -10,7 +10,7 @@ use crate::query::ParseError;
 #[grammar = "query/query.pest"]
 struct QueryParser;
 
-pub fn parse_query(query: &str) -> Result<QueryNode, ParseError> {
+pub fn parse_query(query: &str) -> Result<QueryNode<'_>, ParseError> {
     let pairs = QueryParser::parse(Rule::query, query)?;
     let node = parse_toplevel(get_first_inner_pair(pairs.peek().unwrap()))?;
     Ok(node)
@@ -123,6 +123,26 @@ fn parse_expr(query: Pair<Rule>) -> Result<QueryNode, ParseError> {
             };
             Ok(node)
         }
+        Rule::hasFile => {
+            let arg = get_first_inner_pair(query);
+            Ok(match arg.as_rule() {
+                Rule::string => QueryNode::HasFile(FileField::Hash, get_string_inner(arg)),
+                Rule::fileTest => {
+                    let mut test = arg.into_inner();
+                    let field = match test.next().unwrap().as_str() {
+                        "hash" => FileField::Hash,
+                        "path" => FileField::Path,
+                        _ => unreachable!(),
+                    };
+                    QueryNode::HasFile(field, get_string_inner(test.next().unwrap()))
+                }
+                _ => unreachable!(),
+            })
+        }
+        Rule::has => {
+            let lookup = get_first_inner_pair(query);
+            Ok(QueryNode::Has(parse_lookup(get_first_inner_pair(lookup))))
+        }
         Rule::brackets => {
             let expr = query.into_inner();
             let inner = parse_body(expr.peek().unwrap().into_inner())?;
@@ -154,6 +174,12 @@ fn parse_lookup_packet(lookup: pest::iterators::Pair<Rule>) -> PacketLookup {
         Rule::lookupPacketId => PacketLookup::Id,
         Rule::lookupPacketName => PacketLookup::Name,
         Rule::lookupPacketParam => PacketLookup::Parameter(get_string_inner(lookup)),
+        Rule::lookupPacketCustom => PacketLookup::Custom(get_string_inner(lookup)),
+        Rule::lookupPacketTime => match lookup.into_inner().peek().map(|p| p.as_rule()) {
+            None | Some(Rule::timeStart) => PacketLookup::Time(TimeLookup::Start),
+            Some(Rule::timeEnd) => PacketLookup::Time(TimeLookup::End),
+            _ => unreachable!(),
+        },
         _ => unreachable!(),
     }
 }
@@ -162,6 +188,7 @@ fn parse_literal(literal: Pair<Rule>) -> Literal {
     match literal.as_rule() {
         Rule::string => Literal::String(get_string_inner(literal)),
         Rule::boolean => Literal::Bool(literal.as_str().to_lowercase().parse().unwrap()),
+        Rule::null => Literal::Null,
         Rule::number => Literal::Number(literal.as_str().parse().unwrap()),
         _ => unreachable!(),
     }
@@ -177,7 +204,7 @@ fn unknown_infix_error(operator: Pair<Rule>) -> ParseError {
     .into()
 }
 
-fn get_string_inner(rule: Pair<Rule>) -> &str {
+fn get_string_inner(rule: Pair<'_, Rule>) -> &str {
     get_first_inner_pair(rule).as_str()
 }
 
@@ -327,6 +354,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn query_can_parse_time_lookups() {
+        let res = parse_query("time >= 1503074938").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Test(
+                TestOperator::GreaterThanOrEqual,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Time(TimeLookup::Start))),
+                TestValue::Literal(Literal::Number(_))
+            )
+        );
+        let res = parse_query("time.start < 1600000000").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Test(
+                TestOperator::LessThan,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Time(TimeLookup::Start))),
+                TestValue::Literal(Literal::Number(_))
+            )
+        );
+        let res = parse_query("time.end > 1519120600").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Test(
+                TestOperator::GreaterThan,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Time(TimeLookup::End))),
+                TestValue::Literal(Literal::Number(_))
+            )
+        );
+
+        assert!(parse_query("time.middle > 1").is_err());
+        assert!(parse_query("time . end > 1").is_err());
+    }
+
+    #[test]
+    fn query_can_parse_custom_lookups() {
+        let res = parse_query(r#"custom:orderly.displayname == "Foo""#).unwrap();
+        assert_node!(
+            res,
+            QueryNode::Test(
+                TestOperator::Equal,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Custom("orderly.displayname"))),
+                TestValue::Literal(Literal::String("Foo"))
+            )
+        );
+        let res = parse_query("custom:level < 3").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Test(
+                TestOperator::LessThan,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Custom("level"))),
+                TestValue::Literal(Literal::Number(_))
+            )
+        );
+
+        assert!(parse_query("custom: == 1").is_err());
+        assert!(parse_query("custom:orderly. == 1").is_err());
+        assert!(parse_query("custom:orderly . displayname == 1").is_err());
+    }
+
     #[test]
     fn query_can_parse_parameters() {
         let res = parse_query(r#"parameter:x == "foo""#).unwrap();
@@ -419,6 +506,18 @@ mod tests {
                 TestValue::Literal(Literal::Bool(false))
             )
         );
+        for null in ["null", "NULL", "Null"] {
+            let query = format!("parameter:x == {}", null);
+            let res = parse_query(&query).unwrap();
+            assert_node!(
+                res,
+                QueryNode::Test(
+                    TestOperator::Equal,
+                    TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("x"))),
+                    TestValue::Literal(Literal::Null)
+                )
+            );
+        }
         let e = parse_query("parameter:x == T").unwrap_err();
         assert!(e.to_string().contains("expected lookup or literal"));
 
@@ -606,6 +705,13 @@ mod tests {
                 )
             )
         );
+
+        let res = parse_query("!!latest()").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Negation,
+            (QueryNode::Negation, (QueryNode::Latest(None)))
+        );
     }
 
     #[test]
@@ -766,6 +872,74 @@ mod tests {
         assert!(e.to_string().contains("expected body"));
     }
 
+    #[test]
+    fn parse_errors_report_their_position() {
+        let e = parse_query("name == ").unwrap_err();
+        assert_eq!(e.offset(), 8);
+        assert_eq!(e.line_col(), (1, 9));
+        assert_eq!(
+            e.to_string(),
+            "expected lookup or literal at line 1, column 9\nname == \n        ^"
+        );
+
+        // Errors about a span of the query underline all of it.
+        let e = parse_query(r#"name =! "123""#).unwrap_err();
+        assert_eq!(e.offset(), 5);
+        assert_eq!(e.line_col(), (1, 6));
+        assert!(e.to_string().ends_with("name =! \"123\"\n     ^^"));
+
+        let e = parse_query("latest() &&\n  name ==").unwrap_err();
+        assert_eq!(e.line_col(), (2, 10));
+        assert!(e.to_string().ends_with("\n  name ==\n         ^"));
+    }
+
+    #[test]
+    fn query_can_parse_has() {
+        let res = parse_query("has(parameter:disease)").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Has(Lookup::Packet(PacketLookup::Parameter("disease")))
+        );
+        let res = parse_query("has( custom:orderly.displayname )").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Has(Lookup::Packet(PacketLookup::Custom("orderly.displayname")))
+        );
+        let res = parse_query("!has(name)").unwrap();
+        assert_node!(res, QueryNode::Negation(_));
+
+        assert!(parse_query("has()").is_err());
+        assert!(parse_query("has(1)").is_err());
+        assert!(parse_query("has(parameter:x == 1)").is_err());
+    }
+
+    #[test]
+    fn query_can_parse_hasfile() {
+        let res = parse_query(r#"hasfile("sha256:abc")"#).unwrap();
+        assert_node!(res, QueryNode::HasFile(FileField::Hash, "sha256:abc"));
+        let res = parse_query(r#"hasfile(hash == "sha256:abc")"#).unwrap();
+        assert_node!(res, QueryNode::HasFile(FileField::Hash, "sha256:abc"));
+        let res = parse_query("hasfile(path == 'data.csv')").unwrap();
+        assert_node!(res, QueryNode::HasFile(FileField::Path, "data.csv"));
+
+        let res = parse_query(r#"name == "x" && !hasfile(path == "data.csv")"#).unwrap();
+        assert_node!(
+            res,
+            QueryNode::BooleanExpr,
+            BooleanOperator::And,
+            (QueryNode::Test(
+                TestOperator::Equal,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
+                TestValue::Literal(Literal::String("x"))
+            )),
+            (QueryNode::Negation(_))
+        );
+
+        assert!(parse_query("hasfile()").is_err());
+        assert!(parse_query(r#"hasfile(size == "1")"#).is_err());
+        assert!(parse_query(r#"hasfile(path != "data.csv")"#).is_err());
+    }
+
     #[test]
     fn query_can_parse_infix_in_any_order() {
         let res = parse_query(r#"parameter:x == "foo""#).unwrap();
@@ -826,4 +1000,62 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn display_round_trips_through_parser() {
+        let queries = [
+            "latest",
+            "latest()",
+            r#""20180818-164043-7cdcde4b""#,
+            r#"name == "data""#,
+            "name == 'say \"hi\"'",
+            r#"id != "123" && latest()"#,
+            "parameter:x >= -1.5 || parameter:y < 1e3",
+            "parameter:x == NULL && parameter:y != null",
+            "parameter:flag == TRUE",
+            "time > 1503074938 && time.end <= 1600000000.25",
+            r#"custom:orderly.displayname == "Foo""#,
+            "this:x == environment:y",
+            r#"hasfile("sha256:abc") || hasfile(path == "data.csv")"#,
+            "has(parameter:x) && !has(custom:orderly.displayname) || has(this:y)",
+            r#"latest(name == "x" && parameter:a > 1)"#,
+            r#"single(!(name == "x" || name == "y") && !latest(parameter:a == 2))"#,
+            r#"(name == "a" || name == "b") && (name == "c" || (name == "d"))"#,
+            r#"name == "a" || name == "b" && name == "c" || !!(id == "d")"#,
+        ];
+        for query in queries {
+            let parsed = parse_query(query).unwrap();
+            let displayed = parsed.to_string();
+            let reparsed = parse_query(&displayed).unwrap_or_else(|e| {
+                panic!("'{}' from '{}' failed to parse: {}", displayed, query, e)
+            });
+            assert_eq!(
+                format!("{:?}", reparsed),
+                format!("{:?}", parsed),
+                "{}",
+                query
+            );
+            assert_eq!(reparsed.to_string(), displayed);
+        }
+    }
+
+    #[test]
+    fn display_is_canonical() {
+        let display = |query| parse_query(query).unwrap().to_string();
+        assert_eq!(display("latest"), "latest()");
+        assert_eq!(display(r#""123""#), r#"id == "123""#);
+        assert_eq!(
+            display("time>1 &&parameter:x=='a'"),
+            r#"time.start > 1 && parameter:x == "a""#
+        );
+        assert_eq!(display("parameter:x == True"), "parameter:x == true");
+        assert_eq!(
+            display("hasfile( hash=='sha256:abc' )"),
+            r#"hasfile("sha256:abc")"#
+        );
+        assert_eq!(
+            display(r#"latest(single((name == "a")))"#),
+            r#"latest(single((name == "a")))"#
+        );
+    }
 }
@@ -1,10 +1,47 @@
+use serde::Serialize;
 use std::cmp::Ordering;
+use std::fmt;
 
 #[derive(Debug, PartialEq)]
 pub enum PacketLookup<'a> {
     Name,
     Id,
     Parameter(&'a str),
+    Time(TimeLookup),
+    /// A dotted path into the packet's `custom` metadata, e.g. `orderly.displayname`.
+    Custom(&'a str),
+}
+
+/// Which of a packet's timestamps to look up. A bare `time` in a query refers to the start.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TimeLookup {
+    Start,
+    End,
+}
+
+impl TimeLookup {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeLookup::Start => "start",
+            TimeLookup::End => "end",
+        }
+    }
+}
+
+/// Which property of a packet's files is matched by `hasfile(...)`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FileField {
+    Hash,
+    Path,
+}
+
+impl FileField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileField::Hash => "hash",
+            FileField::Path => "path",
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -19,6 +56,7 @@ pub enum Literal<'a> {
     Bool(bool),
     String(&'a str),
     Number(f64),
+    Null,
 }
 
 #[derive(Debug, PartialEq)]
@@ -36,7 +74,7 @@ impl PartialOrd for Literal<'_> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub enum TestOperator {
     Equal,
     NotEqual,
@@ -46,7 +84,7 @@ pub enum TestOperator {
     GreaterThanOrEqual,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub enum BooleanOperator {
     And,
     Or,
@@ -59,9 +97,132 @@ pub enum QueryNode<'a> {
     Negation(Box<QueryNode<'a>>),
     Brackets(Box<QueryNode<'a>>),
     Test(TestOperator, TestValue<'a>, TestValue<'a>),
+    /// Packets with at least one file whose hash or path is the given string.
+    HasFile(FileField, &'a str),
+    /// Packets for which the lookup has a value, of any kind, including null.
+    Has(Lookup<'a>),
     BooleanExpr(BooleanOperator, Box<QueryNode<'a>>, Box<QueryNode<'a>>),
 }
 
+// The `Display` implementations below render a query in a canonical form, which parses back into
+// the same tree. Brackets are kept as they appear in the tree, and only added where the tree
+// couldn't otherwise be expressed.
+
+impl fmt::Display for Literal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Literal::Bool(value) => write!(f, "{}", value),
+            Literal::Number(value) => write!(f, "{}", value),
+            Literal::Null => write!(f, "null"),
+            // Strings can't contain escapes, so quote them with whichever quote they don't contain.
+            Literal::String(value) if value.contains('"') => write!(f, "'{}'", value),
+            Literal::String(value) => write!(f, "\"{}\"", value),
+        }
+    }
+}
+
+impl fmt::Display for Lookup<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lookup::Packet(PacketLookup::Name) => write!(f, "name"),
+            Lookup::Packet(PacketLookup::Id) => write!(f, "id"),
+            Lookup::Packet(PacketLookup::Parameter(name)) => write!(f, "parameter:{}", name),
+            Lookup::Packet(PacketLookup::Time(field)) => write!(f, "time.{}", field.as_str()),
+            Lookup::Packet(PacketLookup::Custom(path)) => write!(f, "custom:{}", path),
+            Lookup::This(name) => write!(f, "this:{}", name),
+            Lookup::Environment(name) => write!(f, "environment:{}", name),
+        }
+    }
+}
+
+impl fmt::Display for TestValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TestValue::Lookup(lookup) => write!(f, "{}", lookup),
+            TestValue::Literal(literal) => write!(f, "{}", literal),
+        }
+    }
+}
+
+impl fmt::Display for TestOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TestOperator::Equal => "==",
+            TestOperator::NotEqual => "!=",
+            TestOperator::LessThan => "<",
+            TestOperator::LessThanOrEqual => "<=",
+            TestOperator::GreaterThan => ">",
+            TestOperator::GreaterThanOrEqual => ">=",
+        })
+    }
+}
+
+impl fmt::Display for BooleanOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BooleanOperator::And => "&&",
+            BooleanOperator::Or => "||",
+        })
+    }
+}
+
+impl BooleanOperator {
+    fn precedence(&self) -> u8 {
+        match self {
+            BooleanOperator::Or => 1,
+            BooleanOperator::And => 2,
+        }
+    }
+}
+
+impl QueryNode<'_> {
+    /// Write the node, wrapped in brackets if it is a boolean expression binding less tightly
+    /// than `precedence`.
+    fn fmt_operand(&self, f: &mut fmt::Formatter, precedence: u8) -> fmt::Result {
+        match self {
+            QueryNode::BooleanExpr(op, _, _) if op.precedence() < precedence => {
+                write!(f, "({})", self)
+            }
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for QueryNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryNode::Latest(None) => write!(f, "latest()"),
+            QueryNode::Latest(Some(inner)) => write!(f, "latest({})", inner),
+            QueryNode::Single(inner) => write!(f, "single({})", inner),
+            QueryNode::Negation(inner) => {
+                write!(f, "!")?;
+                inner.fmt_operand(f, u8::MAX)
+            }
+            QueryNode::Brackets(inner) => write!(f, "({})", inner),
+            QueryNode::Test(op, lhs, rhs) => write!(f, "{} {} {}", lhs, op, rhs),
+            QueryNode::HasFile(FileField::Hash, hash) => {
+                write!(f, "hasfile({})", Literal::String(hash))
+            }
+            QueryNode::HasFile(field, value) => {
+                write!(
+                    f,
+                    "hasfile({} == {})",
+                    field.as_str(),
+                    Literal::String(value)
+                )
+            }
+            QueryNode::Has(lookup) => write!(f, "has({})", lookup),
+            QueryNode::BooleanExpr(op, lhs, rhs) => {
+                // Operators are left-associative, so a right operand of the same precedence
+                // needs brackets too.
+                lhs.fmt_operand(f, op.precedence())?;
+                write!(f, " {} ", op)?;
+                rhs.fmt_operand(f, op.precedence() + 1)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,6 +236,7 @@ mod tests {
         let lit_bool2 = Literal::Bool(false);
         let lit_str1 = Literal::String("test");
         let lit_str2 = Literal::String("test2");
+        let lit_null = Literal::Null;
 
         assert_eq!(lit_num1, lit_num2);
         assert_ne!(lit_num2, lit_num3);
@@ -82,6 +244,8 @@ mod tests {
         assert_ne!(lit_bool1, lit_bool2);
         assert_ne!(lit_bool2, lit_str1);
         assert_ne!(lit_str1, lit_str2);
+        assert_eq!(lit_null, Literal::Null);
+        assert_ne!(lit_null, lit_bool2);
 
         assert!(lit_num1 < lit_num3);
         assert_eq!(lit_num3.partial_cmp(&lit_num1), Some(Ordering::Greater));
@@ -91,5 +255,28 @@ mod tests {
         // Is undefined on non-number variants
         assert!(lit_bool1.partial_cmp(&lit_bool2).is_none());
         assert!(lit_bool2.partial_cmp(&lit_bool1).is_none());
+        assert!(lit_null.partial_cmp(&Literal::Null).is_none());
+    }
+
+    #[test]
+    fn display_adds_brackets_where_needed() {
+        let test = |name| {
+            Box::new(QueryNode::Test(
+                TestOperator::Equal,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
+                TestValue::Literal(Literal::String(name)),
+            ))
+        };
+        let or = QueryNode::BooleanExpr(BooleanOperator::Or, test("a"), test("b"));
+        let and = QueryNode::BooleanExpr(BooleanOperator::And, test("c"), Box::new(or));
+        assert_eq!(
+            and.to_string(),
+            r#"name == "c" && (name == "a" || name == "b")"#
+        );
+        let negation = QueryNode::Negation(Box::new(and));
+        assert_eq!(
+            negation.to_string(),
+            r#"!(name == "c" && (name == "a" || name == "b"))"#
+        );
     }
 }
@@ -0,0 +1,138 @@
+//! A limit on how often the repository can be written to.
+//!
+//! A client stuck in a loop importing packets can otherwise keep the server busy hashing and
+//! writing files indefinitely. The limit is a single token bucket shared by all clients: each write
+//! request takes a token, tokens are replenished at a fixed rate, and the bucket holds at most one
+//! second's worth, so short bursts are allowed. Requests arriving when the bucket is empty are
+//! rejected with a `429`, and a `Retry-After` header saying when a token will next be available.
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{Request, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::responses::{ErrorCode, OutpackError};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// Allow this many requests per second, which must be at least one.
+    pub fn new(per_second: u32) -> RateLimiter {
+        assert!(per_second > 0, "rate limit must allow at least one request");
+        let per_second = f64::from(per_second);
+        RateLimiter {
+            per_second,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: per_second,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    /// Take a token from the bucket, or return how long it will be until one is available.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.per_second);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+    // Retry-After only allows whole seconds, so round up to avoid clients retrying too early.
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let error = OutpackError {
+        error: String::from("TOO_MANY_REQUESTS"),
+        detail: format!("Too many write requests, try again in {} seconds", seconds),
+        code: ErrorCode::TooManyRequests,
+        kind: Some(ErrorKind::Other),
+        status: StatusCode::TOO_MANY_REQUESTS,
+    };
+    ([(RETRY_AFTER, seconds.to_string())], error).into_response()
+}
+
+/// Middleware rejecting requests once the limit is exceeded. Without a limiter, all requests are
+/// let through.
+pub async fn limit_writes(
+    State(limiter): State<Option<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limiter.map(|l| l.try_acquire()) {
+        Some(Err(wait)) => too_many_requests(wait),
+        _ => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_bursts_up_to_the_rate() {
+        let limiter = RateLimiter::new(3);
+        let now = Instant::now();
+        assert!(limiter.try_acquire_at(now).is_ok());
+        assert!(limiter.try_acquire_at(now).is_ok());
+        assert!(limiter.try_acquire_at(now).is_ok());
+
+        let wait = limiter.try_acquire_at(now).unwrap_err();
+        assert!(wait > Duration::from_millis(300) && wait <= Duration::from_millis(334));
+    }
+
+    #[test]
+    fn replenishes_tokens_over_time() {
+        let limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        assert!(limiter.try_acquire_at(now).is_ok());
+        assert!(limiter.try_acquire_at(now).is_ok());
+        assert!(limiter.try_acquire_at(now).is_err());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.try_acquire_at(later).is_ok());
+        assert!(limiter.try_acquire_at(later).is_err());
+
+        // Idle time doesn't build up more than a second's worth of tokens.
+        let much_later = now + Duration::from_secs(60);
+        assert!(limiter.try_acquire_at(much_later).is_ok());
+        assert!(limiter.try_acquire_at(much_later).is_ok());
+        assert!(limiter.try_acquire_at(much_later).is_err());
+    }
+
+    #[test]
+    fn rounds_retry_after_up_to_whole_seconds() {
+        let response = too_many_requests(Duration::from_millis(200));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+
+        let response = too_many_requests(Duration::from_secs(2));
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+    }
+}
@@ -1,57 +1,191 @@
-use std::io;
 use std::io::ErrorKind;
+use std::{fmt, io};
 
 use axum::extract::rejection::JsonRejection;
-use axum::http::StatusCode;
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use serde::{Deserialize, Serialize};
 
 use crate::hash;
 
-pub struct OutpackSuccess<T>(T);
+pub struct OutpackSuccess<T>(T, Vec<OutpackError>);
 
 impl<T> From<T> for OutpackSuccess<T> {
     fn from(inner: T) -> Self {
-        Self(inner)
+        Self(inner, Vec::new())
     }
 }
 
+impl<T> OutpackSuccess<T> {
+    /// A successful response which nonetheless reports errors for some of the items requested,
+    /// so that a bulk request isn't failed entirely by one bad item.
+    pub fn with_errors(inner: T, errors: Vec<OutpackError>) -> Self {
+        Self(inner, errors)
+    }
+}
+
+/// A stable, machine-readable category for an error, which clients can match on rather than
+/// parsing the human-readable message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    PacketNotFound,
+    FileNotFound,
+    LocationNotFound,
+    UploadNotFound,
+    RouteNotFound,
+    InvalidPacketId,
+    InvalidHash,
+    HashMismatch,
+    InvalidMetadata,
+    InvalidArchive,
+    InvalidChunkOffset,
+    InvalidRequest,
+    InvalidQuery,
+    RequestTooLarge,
+    TooManyRequests,
+    MissingFiles,
+    MissingDependencies,
+    PacketConflict,
+    PreconditionFailed,
+    GitError,
+    Timeout,
+
+    // Used for errors which have not been given a more specific code.
+    NotFound,
+    InvalidInput,
+    AlreadyExists,
+    InternalError,
+}
+
+impl ErrorCode {
+    fn for_kind(kind: ErrorKind) -> ErrorCode {
+        match kind {
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::InvalidInput | ErrorKind::UnexpectedEof => ErrorCode::InvalidInput,
+            ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            ErrorKind::TimedOut => ErrorCode::Timeout,
+            _ => ErrorCode::InternalError,
+        }
+    }
+}
+
+/// The payload of an `io::Error` created with `coded_error`, which lets the code survive being
+/// passed around as a plain `io::Error` until it is converted into an `OutpackError`.
+#[derive(Debug)]
+struct CodedError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+pub fn coded_error(kind: ErrorKind, code: ErrorCode, message: impl Into<String>) -> io::Error {
+    io::Error::new(
+        kind,
+        CodedError {
+            code,
+            message: message.into(),
+        },
+    )
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OutpackError {
     pub error: String,
     pub detail: String,
+    pub code: ErrorCode,
 
     #[serde(skip_serializing, skip_deserializing)]
     pub kind: Option<ErrorKind>,
+
+    #[serde(skip_serializing, skip_deserializing, default = "default_status")]
+    pub status: StatusCode,
+}
+
+fn default_status() -> StatusCode {
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// The status code used for errors that only carry an `io::ErrorKind`.
+pub fn status_for_kind(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        ErrorKind::UnexpectedEof => StatusCode::BAD_REQUEST,
+        ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        ErrorKind::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The status code for a git error, distinguishing problems with the request (such as an invalid
+/// or unknown reference) from failures of the server or its remote.
+fn status_for_git_error(e: &git2::Error) -> StatusCode {
+    match e.code() {
+        git2::ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        git2::ErrorCode::InvalidSpec | git2::ErrorCode::Ambiguous | git2::ErrorCode::Invalid => {
+            StatusCode::BAD_REQUEST
+        }
+        git2::ErrorCode::Exists | git2::ErrorCode::NotFastForward => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
 
 impl From<io::Error> for OutpackError {
     fn from(e: io::Error) -> Self {
+        let code = e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<CodedError>())
+            .map_or_else(|| ErrorCode::for_kind(e.kind()), |inner| inner.code);
         OutpackError {
             error: e.kind().to_string(),
             detail: e.to_string(),
+            code,
             kind: Some(e.kind()),
+            status: status_for_kind(e.kind()),
         }
     }
 }
 
 impl From<hash::HashError> for OutpackError {
     fn from(e: hash::HashError) -> Self {
+        let code = e.code();
         OutpackError {
             // later this can be sorted out better; for now keep old
             // behaviour
             error: std::io::ErrorKind::InvalidInput.to_string(),
             detail: e.explanation,
+            code,
             kind: Some(std::io::ErrorKind::InvalidInput),
+            status: StatusCode::BAD_REQUEST,
         }
     }
 }
 
 impl From<JsonRejection> for OutpackError {
     fn from(e: JsonRejection) -> Self {
+        // Bodies over the size limit are rejected before being parsed, and reported as such so
+        // that clients know to split up their request.
+        let (code, status) = if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            (ErrorCode::RequestTooLarge, StatusCode::PAYLOAD_TOO_LARGE)
+        } else {
+            (ErrorCode::InvalidRequest, StatusCode::BAD_REQUEST)
+        };
         OutpackError {
             error: e.to_string(),
             detail: e.body_text(),
+            code,
             kind: Some(std::io::ErrorKind::InvalidInput),
+            status,
         }
     }
 }
@@ -61,7 +195,9 @@ impl From<git2::Error> for OutpackError {
         OutpackError {
             error: e.message().to_string(),
             detail: format!("{:?}", e.code()),
+            code: ErrorCode::GitError,
             kind: Some(std::io::ErrorKind::Other),
+            status: status_for_git_error(&e),
         }
     }
 }
@@ -80,27 +216,93 @@ pub struct FailResponse {
     pub errors: Option<Vec<OutpackError>>,
 }
 
-impl<T: Serialize> axum::response::IntoResponse for OutpackSuccess<T> {
-    fn into_response(self) -> axum::http::Response<axum::body::Body> {
-        axum::Json(SuccessResponse {
+/// The header a client can send, with a value of `true`, to receive unwrapped success responses.
+pub const RAW_HEADER: &str = "x-outpack-raw";
+
+/// How JSON responses to the current request should be written.
+#[derive(Clone, Copy)]
+struct JsonFormat {
+    pretty: bool,
+    envelope: bool,
+}
+
+tokio::task_local! {
+    static FORMAT: JsonFormat;
+}
+
+fn has_query_param(request: &Request, param: &str) -> bool {
+    request
+        .uri()
+        .query()
+        .is_some_and(|query| query.split('&').any(|p| p == param))
+}
+
+/// Middleware choosing how JSON responses are written, based on the request:
+///
+/// - a `pretty=true` query parameter pretty-prints them, which is easier to read when debugging
+///   with `curl`.
+/// - an `envelope=false` query parameter, or an `X-Outpack-Raw: true` header, returns the `data`
+///   of a success response on its own, without the surrounding `status` and `errors`. Errors are
+///   always enveloped, so that clients can tell them apart.
+///
+/// Responses are built without access to the request, so the choice is passed down to them as a
+/// task-local value for the duration of the request.
+pub async fn json_format(request: Request, next: Next) -> Response {
+    let raw = has_query_param(&request, "envelope=false")
+        || request
+            .headers()
+            .get(RAW_HEADER)
+            .is_some_and(|value| value == "true");
+    let format = JsonFormat {
+        pretty: has_query_param(&request, "pretty=true"),
+        envelope: !raw,
+    };
+    FORMAT.scope(format, next.run(request)).await
+}
+
+fn current_format() -> JsonFormat {
+    FORMAT.try_with(|format| *format).unwrap_or(JsonFormat {
+        pretty: false,
+        envelope: true,
+    })
+}
+
+fn json_response<T: Serialize>(value: T) -> Response {
+    if !current_format().pretty {
+        return axum::Json(value).into_response();
+    }
+    match serde_json::to_vec_pretty(&value) {
+        Ok(body) => (
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(mime::APPLICATION_JSON.as_ref()),
+            )],
+            body,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+impl<T: Serialize> IntoResponse for OutpackSuccess<T> {
+    fn into_response(self) -> Response {
+        // Like errors, a response with errors for some items keeps its envelope, so that they
+        // aren't lost.
+        if !current_format().envelope && self.1.is_empty() {
+            return json_response(self.0);
+        }
+        json_response(SuccessResponse {
             status: String::from("success"),
             data: self.0,
-            errors: None,
+            errors: (!self.1.is_empty()).then_some(self.1),
         })
-        .into_response()
     }
 }
 
-impl axum::response::IntoResponse for OutpackError {
-    fn into_response(self) -> axum::http::Response<axum::body::Body> {
-        let status = match self.kind {
-            Some(ErrorKind::NotFound) => StatusCode::NOT_FOUND,
-            Some(ErrorKind::InvalidInput) => StatusCode::BAD_REQUEST,
-            Some(ErrorKind::UnexpectedEof) => StatusCode::BAD_REQUEST,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        };
-
-        let body = axum::Json(FailResponse {
+impl IntoResponse for OutpackError {
+    fn into_response(self) -> Response {
+        let status = self.status;
+        let body = json_response(FailResponse {
             status: "failure".to_owned(),
             data: None,
             errors: Some(vec![self]),
@@ -109,3 +311,60 @@ impl axum::response::IntoResponse for OutpackError {
         (status, body).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_use_status_for_kind() {
+        let err = OutpackError::from(io::Error::new(ErrorKind::NotFound, "missing"));
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        let err = OutpackError::from(io::Error::new(ErrorKind::PermissionDenied, "denied"));
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn io_errors_carry_their_code() {
+        let err = coded_error(ErrorKind::NotFound, ErrorCode::PacketNotFound, "missing");
+        assert_eq!(err.to_string(), "missing");
+        let err = OutpackError::from(err);
+        assert_eq!(err.code, ErrorCode::PacketNotFound);
+        assert_eq!(err.detail, "missing");
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+
+        let err = OutpackError::from(io::Error::new(ErrorKind::InvalidInput, "bad"));
+        assert_eq!(err.code, ErrorCode::InvalidInput);
+        assert_eq!(
+            serde_json::to_value(&err).unwrap()["code"],
+            serde_json::json!("INVALID_INPUT")
+        );
+    }
+
+    #[test]
+    fn git_user_errors_are_bad_requests() {
+        let err = git2::Error::new(
+            git2::ErrorCode::InvalidSpec,
+            git2::ErrorClass::Reference,
+            "invalid reference",
+        );
+        assert_eq!(OutpackError::from(err).status, StatusCode::BAD_REQUEST);
+
+        let err = git2::Error::new(
+            git2::ErrorCode::NotFound,
+            git2::ErrorClass::Reference,
+            "no such reference",
+        );
+        assert_eq!(OutpackError::from(err).status, StatusCode::NOT_FOUND);
+
+        let err = git2::Error::new(
+            git2::ErrorCode::GenericError,
+            git2::ErrorClass::Net,
+            "connection refused",
+        );
+        assert_eq!(
+            OutpackError::from(err).status,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
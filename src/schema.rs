@@ -0,0 +1,103 @@
+use std::io;
+use std::sync::Arc;
+
+use jsonschema::{Draft, JSONSchema, SchemaResolver, SchemaResolverError};
+use lazy_static::lazy_static;
+use serde_json::Value;
+use url::Url;
+
+use crate::responses::{coded_error, ErrorCode};
+
+// The schemas are compiled into the binary, so that validation doesn't depend on where the
+// server is run from.
+const METADATA_SCHEMA: &str = include_str!("../schema/outpack/metadata.json");
+
+struct BundledSchemaResolver;
+
+impl SchemaResolver for BundledSchemaResolver {
+    fn resolve(
+        &self,
+        _root_schema: &Value,
+        _url: &Url,
+        original_reference: &str,
+    ) -> Result<Arc<Value>, SchemaResolverError> {
+        let contents = match original_reference {
+            "packet-id.json" => include_str!("../schema/outpack/packet-id.json"),
+            "hash.json" => include_str!("../schema/outpack/hash.json"),
+            "git.json" => include_str!("../schema/outpack/git.json"),
+            _ => anyhow::bail!("Unknown schema reference '{}'", original_reference),
+        };
+        Ok(Arc::new(serde_json::from_str(contents)?))
+    }
+}
+
+lazy_static! {
+    static ref METADATA_VALIDATOR: JSONSchema = {
+        let schema: Value = serde_json::from_str(METADATA_SCHEMA).expect("Schema is valid json");
+        JSONSchema::options()
+            .with_draft(Draft::Draft7)
+            .with_resolver(BundledSchemaResolver)
+            .compile(&schema)
+            .expect("Metadata schema is valid")
+    };
+}
+
+/// Check a packet's metadata against the outpack metadata schema.
+///
+/// All the validation errors are listed in the returned error.
+pub fn validate_metadata(data: &str) -> io::Result<()> {
+    let instance: Value = serde_json::from_str(data)?;
+    if let Err(errors) = METADATA_VALIDATOR.validate(&instance) {
+        let errors: Vec<String> = errors
+            .map(|e| format!("{} (at '{}')", e, e.instance_path))
+            .collect();
+        return Err(coded_error(
+            io::ErrorKind::InvalidInput,
+            ErrorCode::InvalidMetadata,
+            format!("Metadata does not match schema: {}", errors.join("; ")),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_metadata() {
+        let data = r#"{
+            "schema_version": "0.1.1",
+            "id": "20230427-150828-68772cee",
+            "name": "data",
+            "parameters": null,
+            "time": { "start": 1682607306.0, "end": 1682607306.5 },
+            "files": [],
+            "depends": [],
+            "custom": null,
+            "git": null,
+            "script": ["orderly.R"]
+        }"#;
+        validate_metadata(data).unwrap();
+    }
+
+    #[test]
+    fn lists_all_validation_errors() {
+        let data = r#"{
+            "schema_version": "0.1.1",
+            "id": "not-an-id",
+            "name": "data",
+            "parameters": null,
+            "time": { "start": 1682607306.0, "end": 1682607306.5 },
+            "files": [],
+            "depends": [],
+            "custom": null
+        }"#;
+        let err = validate_metadata(data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let message = err.to_string();
+        assert!(message.starts_with("Metadata does not match schema: "));
+        assert!(message.contains("\"not-an-id\""));
+        assert!(message.contains("\"git\" is a required property"));
+    }
+}
@@ -0,0 +1,432 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::extract::Request;
+use axum::Router;
+use http_body::{Frame, SizeHint};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time::{Instant, Sleep};
+use tower::ServiceExt;
+
+/// How long a connection may be idle before it is closed, unless configured otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a client may take to send a request's headers, unless configured otherwise.
+pub const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many connections may be open at once, unless configured otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Limits on the connections accepted by the server, which stop idle or slow clients from using
+/// up its file descriptors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionSettings {
+    /// Close connections which have been idle for this long, or never if `None`. This is what
+    /// closes HTTP/1.1 keep-alive connections between requests.
+    pub idle_timeout: Option<Duration>,
+    /// Close connections which take longer than this to send the headers of a request.
+    pub header_read_timeout: Duration,
+    /// Stop accepting new connections while this many are open. Further clients wait in the
+    /// listener's backlog until one is closed.
+    pub max_connections: usize,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings {
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            header_read_timeout: DEFAULT_HEADER_READ_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.idle_timeout {
+            Some(timeout) => write!(f, "idle timeout {}s", timeout.as_secs_f64())?,
+            None => write!(f, "no idle timeout")?,
+        }
+        write!(
+            f,
+            ", header read timeout {}s, at most {} connections",
+            self.header_read_timeout.as_secs_f64(),
+            self.max_connections
+        )
+    }
+}
+
+/// Serve the application, with the given limits on its connections.
+///
+/// This is equivalent to `axum::serve`, except for the limits. For the idle timeout, every
+/// connection is wrapped in an `IdleTimeout`. A connection counts as idle when no data has been
+/// read from or written to it, and no request is being processed on it. A request is being
+/// processed until the whole of its response body has been sent.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    settings: ConnectionSettings,
+) -> io::Result<()> {
+    let connections = Arc::new(Semaphore::new(settings.max_connections));
+    loop {
+        let permit = connections
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Errors such as running out of file descriptors would otherwise be retried in a
+                // busy loop, so wait a while first, as `axum::serve` does.
+                tracing::error!("failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let app = app.clone();
+        let service = {
+            let active = active.clone();
+            hyper::service::service_fn(move |request: Request<Incoming>| {
+                let guard = ActiveRequest::new(active.clone());
+                let response = app.clone().oneshot(request);
+                async move {
+                    let response = response.await?;
+                    Ok::<_, std::convert::Infallible>(response.map(|inner| {
+                        Body::new(GuardedBody {
+                            inner,
+                            _guard: guard,
+                        })
+                    }))
+                }
+            })
+        };
+
+        let mut builder = Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .header_read_timeout(settings.header_read_timeout);
+
+        tokio::spawn(async move {
+            // Errors here only occur when the client goes away, or is too slow, and there is
+            // nothing we can do about them.
+            let _ = match settings.idle_timeout {
+                Some(timeout) => {
+                    let io = TokioIo::new(IdleTimeout::new(stream, timeout, active));
+                    builder.serve_connection_with_upgrades(io, service).await
+                }
+                None => {
+                    let io = TokioIo::new(stream);
+                    builder.serve_connection_with_upgrades(io, service).await
+                }
+            };
+            drop(permit);
+        });
+    }
+}
+
+/// Counts a request as in progress on its connection for as long as it is alive.
+struct ActiveRequest(Arc<AtomicUsize>);
+
+impl ActiveRequest {
+    fn new(active: Arc<AtomicUsize>) -> ActiveRequest {
+        active.fetch_add(1, Ordering::SeqCst);
+        ActiveRequest(active)
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A response body which keeps its request counted as in progress until it has been sent, or the
+/// connection has gone away.
+struct GuardedBody {
+    inner: Body,
+    _guard: ActiveRequest,
+}
+
+impl http_body::Body for GuardedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A wrapper around a connection that reports end-of-file once it has been idle for too long.
+///
+/// Reporting end-of-file makes hyper close the connection cleanly, exactly as if the client had
+/// gone away.
+struct IdleTimeout<T> {
+    inner: T,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    active: Arc<AtomicUsize>,
+}
+
+impl<T> IdleTimeout<T> {
+    fn new(inner: T, timeout: Duration, active: Arc<AtomicUsize>) -> IdleTimeout<T> {
+        IdleTimeout {
+            inner,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            active,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sleep.as_mut().reset(Instant::now() + self.timeout);
+    }
+
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        while self.sleep.as_mut().poll(cx).is_ready() {
+            if self.active.load(Ordering::SeqCst) == 0 {
+                return true;
+            }
+            // A request is still being processed, which doesn't count as idle.
+            self.reset();
+        }
+        false
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.reset();
+                Poll::Ready(result)
+            }
+            Poll::Pending if this.poll_expired(cx) => {
+                tracing::debug!("closing idle connection");
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if result.is_ready() {
+            this.reset();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{api, ServerOptions};
+    use futures::StreamExt;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn start_server(settings: ConnectionSettings) -> std::net::SocketAddr {
+        let app = api(Path::new("tests/example"), ServerOptions::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, app, settings));
+        addr
+    }
+
+    fn with_idle_timeout(timeout: Duration) -> ConnectionSettings {
+        ConnectionSettings {
+            idle_timeout: Some(timeout),
+            ..Default::default()
+        }
+    }
+
+    /// Wait for the server to close the connection, returning how long that took.
+    async fn wait_for_close(stream: &mut TcpStream) -> Duration {
+        let start = std::time::Instant::now();
+        let mut buf = vec![0; 4096];
+        loop {
+            let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+                .await
+                .expect("connection was not closed");
+            if matches!(n, Ok(0) | Err(_)) {
+                return start.elapsed();
+            }
+        }
+    }
+
+    /// Send a request on the connection and read the complete response.
+    async fn send_request(stream: &mut TcpStream) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 4096];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_closed() {
+        let addr = start_server(with_idle_timeout(Duration::from_millis(200))).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let response = send_request(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        assert!(wait_for_close(&mut stream).await >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn active_connection_is_kept_open() {
+        let addr = start_server(with_idle_timeout(Duration::from_millis(500))).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        // Each request resets the timer, so the connection outlives the timeout.
+        for _ in 0..4 {
+            let response = send_request(&mut stream).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_is_kept_open_while_body_is_streamed() {
+        // The body pauses for longer than the idle timeout part way through.
+        let app = Router::new().route(
+            "/",
+            axum::routing::get(|| async {
+                let chunks =
+                    futures::stream::iter(["first\n", "second\n"]).then(|chunk| async move {
+                        if chunk.starts_with("second") {
+                            tokio::time::sleep(Duration::from_millis(600)).await;
+                        }
+                        Ok::<_, io::Error>(chunk)
+                    });
+                Body::from_stream(chunks)
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let settings = with_idle_timeout(Duration::from_millis(200));
+        tokio::spawn(serve(listener, app, settings));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+            .await
+            .expect("response was not completed")
+            .unwrap();
+        assert!(response.contains("first\n"), "{}", response);
+        assert!(response.contains("second\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn slow_headers_are_cut_off() {
+        let addr = start_server(ConnectionSettings {
+            idle_timeout: None,
+            header_read_timeout: Duration::from_millis(200),
+            ..Default::default()
+        })
+        .await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+        assert!(wait_for_close(&mut stream).await >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_limit_wait() {
+        let addr = start_server(ConnectionSettings {
+            max_connections: 1,
+            ..Default::default()
+        })
+        .await;
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let response = send_request(&mut first).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        // The connection is accepted by the OS, but not served until the first is closed.
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(300), send_request(&mut second));
+        assert!(waiting.await.is_err());
+
+        drop(first);
+        let response = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut buf = vec![0; 4096];
+            let n = second.read(&mut buf).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        })
+        .await
+        .expect("second connection was not served");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+
+    #[test]
+    fn can_display_settings() {
+        assert_eq!(
+            ConnectionSettings::default().to_string(),
+            "idle timeout 120s, header read timeout 30s, at most 1024 connections"
+        );
+        let settings = ConnectionSettings {
+            idle_timeout: None,
+            header_read_timeout: Duration::from_millis(1500),
+            max_connections: 8,
+        };
+        assert_eq!(
+            settings.to_string(),
+            "no idle timeout, header read timeout 1.5s, at most 8 connections"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Read access to the files making up an outpack repository.
+//!
+//! Code reading the repository goes through the `Storage` trait rather than `std::fs`, so that
+//! tests can substitute an in-memory repository, and inject failures such as permission errors
+//! which are awkward to provoke on a real filesystem.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub trait Storage: Send + Sync {
+    /// Read the entire contents of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Whether a file exists. Any error accessing it is treated as the file not existing.
+    fn exists(&self, path: &Path) -> bool;
+}
+
+/// The real filesystem, as used by the server and the CLI.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSystem;
+
+impl Storage for FileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_system_reads_files() {
+        let path = Path::new("tests/example/.outpack/config.json");
+        assert!(FileSystem.exists(path));
+        assert_eq!(FileSystem.read(path).unwrap(), fs::read(path).unwrap());
+
+        let path = Path::new("tests/example/.outpack/missing.json");
+        assert!(!FileSystem.exists(path));
+        assert_eq!(
+            FileSystem.read(path).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
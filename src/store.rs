@@ -3,75 +3,298 @@ use std::{fs, io};
 use tempfile::tempdir_in;
 use walkdir::{DirEntry, WalkDir};
 
-use crate::hash;
+use crate::config::FileStoreLayout;
+use crate::responses::{coded_error, ErrorCode};
 use crate::upload::Upload;
+use crate::utils::utf8_file_name;
+use crate::{config, hash, metadata};
 
-pub fn file_path(root: &Path, hash: &str) -> io::Result<PathBuf> {
-    let parsed: hash::Hash = hash.parse().map_err(hash::hash_error_to_io_error)?;
-    Ok(root
+/// The layout of the repository's file store, from its configuration. This is read once by
+/// callers and passed to the functions below, rather than being read again for every file.
+pub fn layout(root: &Path) -> io::Result<FileStoreLayout> {
+    Ok(config::read_config(root)?.core.file_store_layout)
+}
+
+fn parse_hash(hash: &str) -> io::Result<hash::Hash> {
+    hash.parse().map_err(hash::hash_error_to_io_error)
+}
+
+/// Where a file is stored under the given layout.
+fn layout_path(root: &Path, layout: FileStoreLayout, hash: &hash::Hash) -> io::Result<PathBuf> {
+    // Parsing only accepts hex digits, but the fields of a hash are public, so check again before
+    // the value becomes part of a path.
+    if !hash.value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(coded_error(
+            io::ErrorKind::InvalidInput,
+            ErrorCode::InvalidHash,
+            format!("Invalid hash format '{}'", hash),
+        ));
+    }
+    let split = layout.depth * layout.width;
+    if hash.value.len() <= split {
+        return Err(coded_error(
+            io::ErrorKind::InvalidInput,
+            ErrorCode::InvalidHash,
+            format!("Hash '{}' is too short for the file store", hash),
+        ));
+    }
+    let mut path = root
         .join(".outpack")
         .join("files")
-        .join(parsed.algorithm.to_string())
-        .join(&parsed.value[..2])
-        .join(&parsed.value[2..]))
+        .join(hash.algorithm.to_string());
+    for i in 0..layout.depth {
+        path.push(&hash.value[i * layout.width..(i + 1) * layout.width]);
+    }
+    path.push(&hash.value[split..]);
+    Ok(path)
 }
 
-pub fn file_exists(root: &Path, hash: &str) -> io::Result<bool> {
-    let path = file_path(root, hash)?;
+pub fn file_path(root: &Path, layout: FileStoreLayout, hash: &str) -> io::Result<PathBuf> {
+    let parsed = parse_hash(hash)?;
+    layout_path(root, layout, &parsed)
+}
+
+pub fn file_exists(root: &Path, layout: FileStoreLayout, hash: &str) -> io::Result<bool> {
+    let path = file_path(root, layout, hash)?;
     Ok(std::fs::metadata(path).is_ok())
 }
 
-pub fn get_missing_files(root: &Path, wanted: &[String]) -> io::Result<Vec<String>> {
+/// The hashes whose presence in the store is as given, failing if any hash is invalid.
+fn filter_files(
+    root: &Path,
+    layout: FileStoreLayout,
+    wanted: &[String],
+    present: bool,
+) -> io::Result<Vec<String>> {
+    let exists = |h: &str| -> io::Result<bool> { file_exists(root, layout, h) };
     wanted
         .iter()
-        .filter_map(|h| match file_exists(root, h) {
-            Ok(false) => Some(Ok(h.clone())),
-            Ok(true) => None,
+        .filter_map(|h| match exists(h) {
+            Ok(exists) if exists == present => Some(Ok(h.clone())),
+            Ok(_) => None,
             Err(e) => Some(Err(e)),
         })
         .collect()
 }
 
+pub fn get_missing_files(
+    root: &Path,
+    layout: FileStoreLayout,
+    wanted: &[String],
+) -> io::Result<Vec<String>> {
+    filter_files(root, layout, wanted, false)
+}
+
+pub fn get_present_files(
+    root: &Path,
+    layout: FileStoreLayout,
+    wanted: &[String],
+) -> io::Result<Vec<String>> {
+    filter_files(root, layout, wanted, true)
+}
+
+/// The directory used for temporary files, unless configured otherwise.
+///
+/// This is kept outside of `.outpack/files`, so that incomplete uploads are never mistaken for
+/// files in the store.
+pub fn default_temp_dir(root: &Path) -> PathBuf {
+    root.join(".outpack").join(".tmp")
+}
+
+/// Add a file to the store, staging it in the default temporary directory and reading the layout
+/// from the repository's configuration.
 pub fn put_file(root: &Path, file: impl Into<Upload>, hash: &str) -> io::Result<()> {
-    let temp_dir = tempdir_in(root)?;
+    put_file_via(root, layout(root)?, &default_temp_dir(root), file, hash)
+}
+
+/// Add a file to the store, staging it in the given temporary directory first.
+///
+/// The temporary directory must be on the same filesystem as the store, so that the file can be
+/// moved into place once its hash has been validated.
+pub fn put_file_via(
+    root: &Path,
+    layout: FileStoreLayout,
+    temp_dir: &Path,
+    file: impl Into<Upload>,
+    hash: &str,
+) -> io::Result<()> {
+    fs::create_dir_all(temp_dir)?;
+    let temp_dir = tempdir_in(temp_dir)?;
     let temp_path = temp_dir.path().join("data");
 
     file.into().persist(&temp_path)?;
 
     hash::validate_hash_file(&temp_path, hash).map_err(hash::hash_error_to_io_error)?;
 
-    let path = file_path(root, hash)?;
-    if !file_exists(root, hash)? {
+    let path = file_path(root, layout, hash)?;
+    if fs::metadata(&path).is_err() {
         fs::create_dir_all(path.parent().unwrap())?;
         fs::rename(temp_path, path)?;
     }
     Ok(())
 }
 
-pub fn enumerate_files(root: &Path) -> impl Iterator<Item = DirEntry> {
+/// Make a stored file available at `dest`, for example within a packet's archive directory.
+///
+/// The file is hard linked where the filesystem allows it, so that no extra space is used, and
+/// copied otherwise. An existing file at `dest` is never overwritten.
+pub fn link_file(root: &Path, layout: FileStoreLayout, hash: &str, dest: &Path) -> io::Result<()> {
+    let source = file_path(root, layout, hash)?;
+    if !source.exists() {
+        return Err(coded_error(
+            io::ErrorKind::NotFound,
+            ErrorCode::FileNotFound,
+            format!("hash '{}' not found", hash),
+        ));
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::hard_link(&source, dest) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+        Err(_) => fs::copy(&source, dest).map(|_| ()),
+    }
+}
+
+/// Every file in the store, in no particular order.
+pub fn enumerate_files(
+    root: &Path,
+    layout: FileStoreLayout,
+) -> io::Result<impl Iterator<Item = DirEntry>> {
     let directory = root.join(".outpack").join("files");
 
-    WalkDir::new(directory)
+    // Stored files always live at `<algorithm>/<shard>.../<rest>`, with as many shard directories
+    // as the layout's depth. Anything shallower is an in-progress upload rather than part of the
+    // store.
+    let depth = layout.depth + 2;
+    Ok(WalkDir::new(&directory)
+        .min_depth(depth)
+        .max_depth(depth)
         .into_iter()
         .filter_map(|r| r.ok())
-        .filter(|p| p.file_type().is_file())
+        .filter(move |e| {
+            let relative = e.path().strip_prefix(&directory).unwrap();
+            // Hidden directories are never part of the store, and may hold temporary files.
+            let hidden = relative
+                .components()
+                .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+            // A hash can't be read back from a path that isn't valid UTF-8.
+            !hidden && utf8_file_name(relative.as_os_str(), e.path()).is_some()
+        })
+        .filter(|p| p.file_type().is_file()))
+}
+
+/// The hash of the file stored at `path`, within the store's `directory`.
+fn stored_hash(directory: &Path, path: &Path) -> String {
+    // The hash is the algorithm, followed by the shard directories and file name joined up.
+    let mut components = path
+        .strip_prefix(directory)
+        .unwrap()
+        .iter()
+        .map(|c| c.to_string_lossy());
+    let algorithm = components.next().unwrap_or_default();
+    format!("{}:{}", algorithm, components.collect::<String>())
+}
+
+/// The hashes of every file in the store, in no particular order.
+///
+/// Files whose path doesn't make up a well-formed hash, such as ones left behind by other tools,
+/// can't be fetched by their hash, so they are logged and skipped.
+pub fn list_files(
+    root: &Path,
+    layout: FileStoreLayout,
+) -> io::Result<impl Iterator<Item = String>> {
+    let directory = root.join(".outpack").join("files");
+    Ok(enumerate_files(root, layout)?.filter_map(move |entry| {
+        let hash = stored_hash(&directory, entry.path());
+        match hash.parse::<hash::Hash>() {
+            Ok(parsed) if parsed.to_string() == hash => Some(hash),
+            _ => {
+                tracing::warn!(
+                    "skipping {}, which is not named after a valid hash",
+                    entry.path().display()
+                );
+                None
+            }
+        }
+    }))
+}
+
+/// The number of packets whose metadata references the file with the given hash.
+///
+/// Every packet's metadata is read, so this is slow for large repositories. Metadata which can't
+/// be read is reported as an error, rather than skipped, since the file may be referenced by it.
+pub fn file_reference_count(root: &Path, layout: FileStoreLayout, hash: &str) -> io::Result<usize> {
+    file_path(root, layout, hash)?;
+    let mut count = 0;
+    for packet in metadata::iter_packets(root) {
+        if packet?.files.iter().any(|f| f.hash == hash) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Delete a file from the store, but only if no packet references it. Returns whether the file
+/// was deleted; a file which is referenced, or isn't in the store, is left alone.
+///
+/// A packet referencing the file could still be added between it being counted and deleted, so
+/// this should only be used while nothing else is adding packets to the repository.
+pub fn remove_file_if_unreferenced(
+    root: &Path,
+    layout: FileStoreLayout,
+    hash: &str,
+) -> io::Result<bool> {
+    if file_reference_count(root, layout, hash)? > 0 {
+        return Ok(false);
+    }
+    match fs::remove_file(file_path(root, layout, hash)?) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Re-hash every file in the store, returning the hashes of any whose
+/// contents no longer match the hash they are stored under.
+pub fn verify(root: &Path, layout: FileStoreLayout) -> io::Result<Vec<String>> {
+    let mut corrupt = Vec::new();
+    let directory = root.join(".outpack").join("files");
+    for entry in enumerate_files(root, layout)? {
+        let path = entry.path();
+        let hash = stored_hash(&directory, path);
+        match hash::validate_hash_file(path, &hash) {
+            Ok(()) => {}
+            Err(e) if e.kind == hash::HashErrorKind::FileReadFailed => {
+                return Err(hash::hash_error_to_io_error(e));
+            }
+            Err(_) => corrupt.push(hash),
+        }
+    }
+    corrupt.sort();
+    Ok(corrupt)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::hash::{hash_data, HashAlgorithm};
-    use crate::test_utils::tests::{get_temp_outpack_root, vector_equals};
+    use crate::test_utils::tests::{
+        get_empty_outpack_root, get_temp_outpack_root, start_packet, vector_equals,
+    };
     use std::ffi::OsString;
 
+    const LAYOUT: FileStoreLayout = FileStoreLayout { depth: 1, width: 2 };
+
     #[test]
     fn can_get_path() {
+        let root = get_empty_outpack_root();
         let hash = "sha256:e9aa9f2212ab";
-        let res = file_path(Path::new("root"), hash).unwrap();
+        let res = file_path(&root, LAYOUT, hash).unwrap();
         assert_eq!(
             res,
-            Path::new("root")
-                .join(".outpack")
+            root.join(".outpack")
                 .join("files")
                 .join("sha256")
                 .join("e9")
@@ -79,10 +302,67 @@ mod tests {
         );
     }
 
+    #[test]
+    fn paths_are_only_built_from_hex_values() {
+        let root = get_empty_outpack_root();
+        let err = file_path(&root, LAYOUT, "sha256:../../../etc").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid hash format 'sha256:../../../etc'");
+
+        let hash = hash::Hash {
+            algorithm: HashAlgorithm::Sha256,
+            value: String::from("e9/../../../../etc/passwd"),
+        };
+        let err = layout_path(&root, LAYOUT, &hash).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "Invalid hash format 'sha256:e9/../../../../etc/passwd'"
+        );
+    }
+
+    #[test]
+    fn can_use_deeper_file_store_layout() {
+        let root = get_empty_outpack_root();
+        let mut config = config::read_config(&root).unwrap();
+        config.core.file_store_layout = FileStoreLayout { depth: 2, width: 2 };
+        config::write_config(&config, &root).unwrap();
+        let deeper = layout(&root).unwrap();
+        assert_eq!(deeper, config.core.file_store_layout);
+
+        let data = b"Testing";
+        let hash = hash_data(data, HashAlgorithm::Sha256).to_string();
+        let value = &hash["sha256:".len()..];
+        put_file(&root, &data[..], &hash).unwrap();
+
+        let expected = root
+            .join(".outpack/files/sha256")
+            .join(&value[..2])
+            .join(&value[2..4])
+            .join(&value[4..]);
+        assert_eq!(file_path(&root, deeper, &hash).unwrap(), expected);
+        assert!(expected.exists());
+        assert!(file_exists(&root, deeper, &hash).unwrap());
+        let present = get_present_files(&root, deeper, std::slice::from_ref(&hash)).unwrap();
+        assert_eq!(present, [hash]);
+
+        let files: Vec<_> = enumerate_files(&root, deeper)
+            .unwrap()
+            .map(|e| e.into_path())
+            .collect();
+        assert_eq!(files, std::slice::from_ref(&expected));
+        assert!(verify(&root, deeper).unwrap().is_empty());
+
+        fs::write(&expected, "corrupted").unwrap();
+        assert_eq!(verify(&root, deeper).unwrap().len(), 1);
+
+        let err = file_path(&root, deeper, "md5:abcd").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
     #[test]
     fn path_propagates_error_on_invalid_hash() {
         let hash = "sha256";
-        let res = file_path(Path::new("root"), hash);
+        let res = file_path(Path::new("root"), LAYOUT, hash);
         assert!(res.is_err());
         assert_eq!(res.unwrap_err().to_string(), "Invalid hash format 'sha256'")
     }
@@ -95,7 +375,7 @@ mod tests {
         let hash_str = hash.to_string();
 
         let res = put_file(&root, data, &hash.to_string());
-        let expected = file_path(&root, &hash_str).unwrap();
+        let expected = file_path(&root, LAYOUT, &hash_str).unwrap();
         let expected = expected.to_str().unwrap();
         assert!(res.is_ok());
         assert_eq!(fs::read(expected).unwrap(), data);
@@ -127,10 +407,124 @@ mod tests {
         );
     }
 
+    #[test]
+    fn can_verify_store() {
+        let root = get_temp_outpack_root();
+        assert_eq!(verify(&root, LAYOUT).unwrap(), Vec::<String>::new());
+
+        let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+        fs::write(file_path(&root, LAYOUT, hash).unwrap(), "corrupted").unwrap();
+        assert_eq!(verify(&root, LAYOUT).unwrap(), vec![hash]);
+    }
+
+    #[test]
+    fn put_file_uses_temp_dir() {
+        let root = get_temp_outpack_root();
+        let temp_dir = root.join("custom-tmp");
+        let data = b"Testing 123.";
+        let hash = hash_data(data, HashAlgorithm::Sha256).to_string();
+
+        put_file_via(&root, LAYOUT, &temp_dir, data, &hash).unwrap();
+        assert!(file_exists(&root, LAYOUT, &hash).unwrap());
+        assert!(temp_dir.is_dir());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn can_link_file() {
+        let root = get_temp_outpack_root();
+        let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+        let dest = root.join("archive").join("data").join("data.csv");
+
+        link_file(&root, LAYOUT, hash, &dest).unwrap();
+        assert_eq!(
+            fs::read(&dest).unwrap(),
+            fs::read(file_path(&root, LAYOUT, hash).unwrap()).unwrap()
+        );
+
+        let res = link_file(&root, LAYOUT, hash, &dest);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn link_file_errors_if_hash_missing() {
+        let root = get_temp_outpack_root();
+        let hash = "sha256:c7b512b2d14a7caae8968830760cb95980a98e18ca2c2991b87c71529e223164";
+        let dest = root.join("archive").join("data.csv");
+
+        let res = link_file(&root, LAYOUT, hash, &dest);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            format!("hash '{}' not found", hash)
+        );
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn can_count_file_references() {
+        let root = get_temp_outpack_root();
+        let shared = "sha256:02b8becbce68e8113bb3710a2474822d15c00e7ed9060ed94b10f0e137b2ee22";
+        assert_eq!(file_reference_count(&root, LAYOUT, shared).unwrap(), 4);
+
+        let unreferenced =
+            "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+        assert_eq!(
+            file_reference_count(&root, LAYOUT, unreferenced).unwrap(),
+            0
+        );
+
+        assert!(file_reference_count(&root, LAYOUT, "sha256").is_err());
+    }
+
+    #[test]
+    fn only_removes_unreferenced_files() {
+        let root = get_temp_outpack_root();
+        let referenced = hash_data(b"referenced", HashAlgorithm::Sha256).to_string();
+        put_file(&root, b"referenced", &referenced).unwrap();
+        let (_, data, packet_hash) = start_packet("data")
+            .add_file("data.txt", &referenced, 10)
+            .finish();
+        metadata::add_packet(&root, &data, &packet_hash, crate::config::LOCAL_LOCATION).unwrap();
+
+        assert!(!remove_file_if_unreferenced(&root, LAYOUT, &referenced).unwrap());
+        assert!(file_exists(&root, LAYOUT, &referenced).unwrap());
+
+        let unreferenced =
+            "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+        assert!(remove_file_if_unreferenced(&root, LAYOUT, unreferenced).unwrap());
+        assert!(!file_exists(&root, LAYOUT, unreferenced).unwrap());
+
+        // Already gone, so there's nothing to do.
+        assert!(!remove_file_if_unreferenced(&root, LAYOUT, unreferenced).unwrap());
+    }
+
+    #[test]
+    fn enumerate_files_ignores_hidden_directories() {
+        let root = get_temp_outpack_root();
+        let hidden = root.join(".outpack/files/.tmp/partial");
+        fs::create_dir_all(&hidden).unwrap();
+        fs::write(hidden.join("data"), "incomplete").unwrap();
+
+        assert_eq!(enumerate_files(&root, LAYOUT).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn enumerate_files_skips_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+        let root = get_temp_outpack_root();
+        let name = std::ffi::OsStr::from_bytes(b"89579a\xff");
+        fs::write(root.join(".outpack/files/sha256/b1").join(name), "stray").unwrap();
+
+        assert_eq!(enumerate_files(&root, LAYOUT).unwrap().count(), 1);
+        assert!(verify(&root, LAYOUT).unwrap().is_empty());
+    }
+
     #[test]
     fn enumerate_files_works() {
         let root = get_temp_outpack_root();
-        let files: Vec<_> = enumerate_files(&root)
+        let files: Vec<_> = enumerate_files(&root, LAYOUT)
+            .unwrap()
             .map(|entry| entry.file_name().to_owned())
             .collect();
 
@@ -145,4 +539,28 @@ mod tests {
             files
         );
     }
+
+    #[test]
+    fn list_files_skips_malformed_hashes() {
+        let root = get_temp_outpack_root();
+        let expected = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+        assert_eq!(
+            list_files(&root, LAYOUT).unwrap().collect::<Vec<_>>(),
+            vec![expected]
+        );
+
+        let files = root.join(".outpack/files");
+        fs::create_dir_all(files.join("sha256/zz")).unwrap();
+        fs::write(files.join("sha256/zz/notahash"), "stray").unwrap();
+        fs::create_dir_all(files.join("unknown/ab")).unwrap();
+        fs::write(files.join("unknown/ab/cdef"), "stray").unwrap();
+        fs::create_dir_all(files.join("SHA256/ab")).unwrap();
+        fs::write(files.join("SHA256/ab/cdef"), "stray").unwrap();
+
+        assert_eq!(enumerate_files(&root, LAYOUT).unwrap().count(), 4);
+        assert_eq!(
+            list_files(&root, LAYOUT).unwrap().collect::<Vec<_>>(),
+            vec![expected]
+        );
+    }
 }
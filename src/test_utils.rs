@@ -1,19 +1,20 @@
 #[cfg(test)]
 #[macro_use]
 pub mod tests {
+    use crate::config::{read_config, write_config, MetadataBackend};
     use crate::hash::{hash_data, Hash, HashAlgorithm};
     use crate::init::outpack_init;
     use crate::metadata::{DependencyFile, Packet, PacketDependency, PacketFile, PacketTime};
+    use crate::metadata_store::{LogMetadataStore, MetadataStore};
+    use crate::storage::Storage;
     use crate::utils::is_packet_str;
     use crate::utils::time_as_num;
 
     use rand::Rng;
     use std::collections::HashMap;
-    use std::fs::File;
-    use std::path::PathBuf;
-    use std::sync::Once;
+    use std::io;
+    use std::path::{Path, PathBuf};
     use std::time::SystemTime;
-    use tar::{Archive, Builder};
     use tempdir;
 
     pub fn vector_equals<T>(a: &[T], b: &[T]) -> bool
@@ -44,22 +45,28 @@ pub mod tests {
         )
     }
 
-    static INIT: Once = Once::new();
-
-    pub fn initialize() {
-        INIT.call_once(|| {
-            let mut ar = Builder::new(File::create("example.tar").expect("File created"));
-            ar.append_dir_all("example", "tests/example").unwrap();
-            ar.finish().unwrap();
-        });
+    pub fn get_temp_outpack_root() -> PathBuf {
+        test_utils::get_example_dir()
     }
 
-    pub fn get_temp_outpack_root() -> PathBuf {
-        initialize();
-        let tmp_dir = tempdir::TempDir::new("outpack").expect("Temp dir created");
-        let mut ar = Archive::new(File::open("example.tar").unwrap());
-        ar.unpack(&tmp_dir).expect("unwrapped");
-        tmp_dir.into_path().join("example")
+    /// A copy of the example repository, configured to keep its metadata in a log rather than
+    /// a directory.
+    pub fn get_log_outpack_root() -> PathBuf {
+        let root = get_temp_outpack_root();
+        let mut config = read_config(&root).unwrap();
+        config.core.metadata_backend = MetadataBackend::Log;
+        write_config(&config, &root).unwrap();
+
+        let store = LogMetadataStore::new(&root);
+        for entry in std::fs::read_dir(root.join(".outpack/metadata")).unwrap() {
+            let path = entry.unwrap().path();
+            let data = std::fs::read_to_string(&path).unwrap();
+            let hash = hash_data(data.as_bytes(), HashAlgorithm::Sha256);
+            let id = path.file_name().unwrap().to_str().unwrap();
+            store.add(id, &hash.to_string(), &data).unwrap();
+            std::fs::remove_file(path).unwrap();
+        }
+        root
     }
 
     pub fn get_empty_outpack_root() -> PathBuf {
@@ -70,6 +77,7 @@ pub mod tests {
             None,
             /* use_file_store */ true,
             /* require_complete_tree */ true,
+            /* default_branch */ None,
         )
         .unwrap();
 
@@ -114,6 +122,7 @@ pub mod tests {
                     start: time_as_num(SystemTime::now()),
                     end: 0.,
                 },
+                extra: HashMap::new(),
             },
         }
     }
@@ -133,6 +142,18 @@ pub mod tests {
             self
         }
 
+        pub fn parameter(
+            &mut self,
+            name: impl Into<String>,
+            value: impl Into<serde_json::Value>,
+        ) -> &mut PacketBuilder {
+            self.packet
+                .parameters
+                .get_or_insert_with(Default::default)
+                .insert(name.into(), value.into());
+            self
+        }
+
         pub fn add_dependency(
             &mut self,
             packet: impl Into<String>,
@@ -153,6 +174,37 @@ pub mod tests {
         }
     }
 
+    /// An in-memory repository, in which reading a file can also be made to fail.
+    #[derive(Default)]
+    pub struct MemoryStorage {
+        files: HashMap<PathBuf, Result<Vec<u8>, io::ErrorKind>>,
+    }
+
+    impl MemoryStorage {
+        pub fn add_file(&mut self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+            self.files.insert(path.into(), Ok(contents.into()));
+        }
+
+        /// Add a file which exists, but fails with the given error when read.
+        pub fn add_failing_file(&mut self, path: impl Into<PathBuf>, kind: io::ErrorKind) {
+            self.files.insert(path.into(), Err(kind));
+        }
+    }
+
+    impl Storage for MemoryStorage {
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            match self.files.get(path) {
+                Some(Ok(contents)) => Ok(contents.clone()),
+                Some(Err(kind)) => Err(io::Error::new(*kind, "injected failure")),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+            }
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.files.contains_key(path)
+        }
+    }
+
     pub use lazy_static::lazy_static;
     pub use regex::Regex;
     macro_rules! assert_regex {
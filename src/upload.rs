@@ -1,15 +1,19 @@
-use crate::responses::OutpackError;
-use axum::body::Bytes;
+use crate::hash;
+use crate::responses::{coded_error, ErrorCode, OutpackError};
+use axum::body::{Body, Bytes};
 use axum::extract::{FromRequest, FromRequestParts, Request};
 use axum::Extension;
 use futures::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tempfile::{NamedTempFile, TempPath};
 use tokio::io::AsyncWriteExt;
 use tokio_util::io::StreamReader;
 use tower::Layer;
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct UploadConfig {
@@ -64,6 +68,14 @@ impl Upload {
         }
         Ok(())
     }
+
+    /// Hash the uploaded data, in the same way as it is checked when it is added to the store.
+    pub fn hash(&self, algorithm: hash::HashAlgorithm) -> io::Result<hash::Hash> {
+        match self {
+            Upload::Buffered(data) => Ok(hash::hash_data(data, algorithm)),
+            Upload::File(path) => hash::hash_file(path, algorithm),
+        }
+    }
 }
 
 #[axum::async_trait]
@@ -92,6 +104,169 @@ where
     }
 }
 
+/// The state of a resumable upload, as reported to the client.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PartialUpload {
+    pub upload_id: String,
+    pub offset: u64,
+}
+
+/// Resumable uploads, which allow a large file to be sent as a sequence of chunks.
+///
+/// Each partial upload is stored as a single file in the `partial` subdirectory of the upload
+/// directory, named after the upload's id. The amount of data received so far is simply the size
+/// of that file, so no other state needs to be kept.
+impl UploadConfig {
+    fn partial_path(&self, id: &str) -> io::Result<PathBuf> {
+        // Parsing the id ensures it can't be used to escape the directory.
+        let path = Uuid::parse_str(id)
+            .ok()
+            .map(|id| self.directory.join("partial").join(id.to_string()));
+        match path {
+            Some(path) if path.is_file() => Ok(path),
+            _ => Err(coded_error(
+                io::ErrorKind::NotFound,
+                ErrorCode::UploadNotFound,
+                format!("upload '{}' does not exist", id),
+            )),
+        }
+    }
+
+    /// Start a new, empty, partial upload.
+    pub async fn start_partial(&self) -> io::Result<PartialUpload> {
+        let directory = self.directory.join("partial");
+        tokio::fs::create_dir_all(&directory).await?;
+
+        let upload_id = Uuid::new_v4().to_string();
+        tokio::fs::File::create(directory.join(&upload_id)).await?;
+        Ok(PartialUpload {
+            upload_id,
+            offset: 0,
+        })
+    }
+
+    /// Get the amount of data received so far by a partial upload.
+    pub async fn partial_status(&self, id: &str) -> io::Result<PartialUpload> {
+        let path = self.partial_path(id)?;
+        Ok(PartialUpload {
+            upload_id: id.to_owned(),
+            offset: tokio::fs::metadata(path).await?.len(),
+        })
+    }
+
+    /// Append a chunk of data to a partial upload.
+    ///
+    /// The offset must match the amount of data received so far, which guards against chunks
+    /// being sent twice or out of order.
+    pub async fn append_partial(
+        &self,
+        id: &str,
+        offset: u64,
+        body: Body,
+    ) -> io::Result<PartialUpload> {
+        let path = self.partial_path(id)?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await?;
+
+        let current = file.metadata().await?.len();
+        if current != offset {
+            return Err(coded_error(
+                io::ErrorKind::InvalidInput,
+                ErrorCode::InvalidChunkOffset,
+                format!(
+                    "Chunk offset {} does not match upload '{}', which has received {} bytes",
+                    offset, id, current
+                ),
+            ));
+        }
+
+        stream_into_file(&mut file, body.into_data_stream()).await?;
+        Ok(PartialUpload {
+            upload_id: id.to_owned(),
+            offset: file.metadata().await?.len(),
+        })
+    }
+
+    /// Complete a partial upload, returning its contents as an `Upload`.
+    ///
+    /// The partial upload no longer exists afterwards. The `Upload` is deleted when dropped,
+    /// unless it is persisted somewhere else first.
+    pub fn finish_partial(&self, id: &str) -> io::Result<Upload> {
+        let path = self.partial_path(id)?;
+        Ok(Upload::File(TempPath::from_path(path)))
+    }
+
+    /// Abandon a partial upload, deleting any data received so far.
+    pub async fn abort_partial(&self, id: &str) -> io::Result<()> {
+        let path = self.partial_path(id)?;
+        tokio::fs::remove_file(path).await
+    }
+
+    /// Delete any partial upload which has not received data for longer than `max_age`.
+    ///
+    /// Returns the number of uploads deleted.
+    pub async fn remove_stale_partials(&self, max_age: Duration) -> io::Result<usize> {
+        let mut entries = match tokio::fs::read_dir(self.directory.join("partial")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let now = SystemTime::now();
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let modified = entry.metadata().await?.modified()?;
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age > max_age {
+                tokio::fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// The prefix the `tempfile` crate gives to the files and directories it creates.
+const TEMP_PREFIX: &str = ".tmp";
+
+/// Delete temporary files and directories in an upload directory which are older than `max_age`,
+/// such as those left behind if the server stopped part way through an upload.
+///
+/// Only entries directly within the directory whose names have the prefix used for temporary
+/// files are considered, so nothing else that shares the directory, such as the partial uploads or
+/// files in the store, is removed. Returns the number of entries deleted.
+pub fn remove_stale_temp_files(directory: &Path, max_age: Duration) -> io::Result<usize> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+            continue;
+        }
+        // Symlinks are not followed, so the metadata describes the entry itself.
+        let metadata = entry.metadata()?;
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        if age <= max_age {
+            continue;
+        }
+        if metadata.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+        removed += 1;
+    }
+    Ok(removed)
+}
+
 impl<S> Layer<S> for UploadLayer {
     type Service = axum::middleware::AddExtension<S, UploadConfig>;
     fn layer(&self, inner: S) -> Self::Service {
@@ -101,14 +276,22 @@ impl<S> Layer<S> for UploadLayer {
 
 /// Stream a request body to an on-disk file.
 async fn stream_to_file<S>(path: &Path, stream: S) -> std::io::Result<()>
+where
+    S: Stream<Item = Result<Bytes, axum::Error>> + Unpin,
+{
+    let mut file = tokio::fs::File::create(path).await?;
+    stream_into_file(&mut file, stream).await
+}
+
+/// Write a request body to the end of an open file.
+async fn stream_into_file<S>(file: &mut tokio::fs::File, stream: S) -> std::io::Result<()>
 where
     S: Stream<Item = Result<Bytes, axum::Error>> + Unpin,
 {
     let stream = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
     let mut reader = StreamReader::new(stream);
 
-    let mut file = tokio::fs::File::create(path).await?;
-    tokio::io::copy(&mut reader, &mut file).await?;
+    tokio::io::copy(&mut reader, file).await?;
     file.flush().await?;
 
     Ok(())
@@ -160,4 +343,145 @@ mod tests {
         let contents = tokio::fs::read(&destination).await.unwrap();
         assert_eq!(contents, data);
     }
+
+    fn partial_config() -> (tempfile::TempDir, UploadConfig) {
+        let root = tempfile::tempdir().unwrap();
+        let config = UploadConfig {
+            directory: Arc::new(root.path().to_owned()),
+        };
+        (root, config)
+    }
+
+    #[tokio::test]
+    async fn can_upload_in_chunks() {
+        let (root, config) = partial_config();
+
+        let upload = config.start_partial().await.unwrap();
+        assert_eq!(upload.offset, 0);
+        let id = upload.upload_id;
+
+        let status = config
+            .append_partial(&id, 0, Body::from("Hello, "))
+            .await
+            .unwrap();
+        assert_eq!(status.offset, 7);
+
+        let status = config
+            .append_partial(&id, 7, Body::from("World!"))
+            .await
+            .unwrap();
+        assert_eq!(status.offset, 13);
+        assert_eq!(config.partial_status(&id).await.unwrap().offset, 13);
+
+        let destination = root.path().join("hello.txt");
+        config
+            .finish_partial(&id)
+            .unwrap()
+            .persist(&destination)
+            .unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), b"Hello, World!");
+
+        let err = config.partial_status(&id).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn chunk_offset_must_match() {
+        let (_root, config) = partial_config();
+        let id = config.start_partial().await.unwrap().upload_id;
+        config
+            .append_partial(&id, 0, Body::from("abc"))
+            .await
+            .unwrap();
+
+        let err = config
+            .append_partial(&id, 0, Body::from("abc"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Chunk offset 0 does not match upload '{}', which has received 3 bytes",
+                id
+            )
+        );
+        assert_eq!(config.partial_status(&id).await.unwrap().offset, 3);
+    }
+
+    #[tokio::test]
+    async fn unknown_uploads_are_not_found() {
+        let (_root, config) = partial_config();
+        for id in ["../../etc/passwd", "9a3c3c9e-0a71-4c1b-8b0e-7e5d2c4b1f00"] {
+            let err = config.partial_status(id).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert_eq!(err.to_string(), format!("upload '{}' does not exist", id));
+        }
+    }
+
+    #[tokio::test]
+    async fn can_hash_upload() {
+        let algorithm = hash::HashAlgorithm::Sha256;
+        let expected = hash::hash_data(b"Testing", algorithm);
+        assert_eq!(Upload::from(b"Testing").hash(algorithm).unwrap(), expected);
+
+        let request = Request::new(Body::from("Testing"));
+        let upload = Upload::from_request(request, &()).await.unwrap();
+        assert_eq!(upload.hash(algorithm).unwrap(), expected);
+    }
+
+    #[test]
+    fn can_remove_stale_temp_files() {
+        let root = tempfile::tempdir().unwrap();
+        let directory = root.path();
+        let old = SystemTime::now() - Duration::from_secs(2 * 3600);
+        let age = |path: &Path| {
+            let file = std::fs::File::open(path).unwrap();
+            file.set_modified(old).unwrap();
+        };
+
+        let stale = NamedTempFile::new_in(directory).unwrap();
+        age(stale.path());
+        let recent = NamedTempFile::new_in(directory).unwrap();
+        let stale_dir = tempfile::tempdir_in(directory).unwrap();
+        std::fs::write(stale_dir.path().join("data"), "data").unwrap();
+        age(stale_dir.path());
+        // Files which weren't made by the server are left alone, however old.
+        let other = directory.join("e9aa9f2212ab");
+        std::fs::write(&other, "data").unwrap();
+        age(&other);
+
+        let removed = remove_stale_temp_files(directory, Duration::from_secs(3600)).unwrap();
+        assert_eq!(removed, 2);
+        assert!(!stale.path().exists());
+        assert!(!stale_dir.path().exists());
+        assert!(recent.path().exists());
+        assert!(other.exists());
+
+        assert_eq!(
+            remove_stale_temp_files(&directory.join("missing"), Duration::ZERO).unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn can_abort_and_remove_stale_uploads() {
+        let (_root, config) = partial_config();
+        let first = config.start_partial().await.unwrap().upload_id;
+        let second = config.start_partial().await.unwrap().upload_id;
+
+        config.abort_partial(&first).await.unwrap();
+        assert!(config.partial_status(&first).await.is_err());
+
+        let removed = config
+            .remove_stale_partials(Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(removed, 0);
+        assert!(config.partial_status(&second).await.is_ok());
+
+        let removed = config.remove_stale_partials(Duration::ZERO).await.unwrap();
+        assert_eq!(removed, 1);
+        assert!(config.partial_status(&second).await.is_err());
+    }
 }
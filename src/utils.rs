@@ -1,7 +1,8 @@
 use cached::instant::SystemTime;
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
 use std::time::UNIX_EPOCH;
 
 lazy_static! {
@@ -10,13 +11,26 @@ lazy_static! {
 
 pub fn is_packet(name: &OsString) -> bool {
     let o = name.to_str();
-    o.map_or(false, is_packet_str)
+    o.is_some_and(is_packet_str)
 }
 
 pub fn is_packet_str(name: &str) -> bool {
     ID_REG.is_match(name)
 }
 
+/// The name of a directory entry at `path`, as a string.
+///
+/// Nothing outpack writes has a name that isn't valid UTF-8, so such entries are skipped by
+/// returning `None`. They shouldn't be there at all though, and would make listings silently
+/// disagree with what is on disk, so a warning is logged.
+pub fn utf8_file_name<'a>(name: &'a OsStr, path: &Path) -> Option<&'a str> {
+    let name = name.to_str();
+    if name.is_none() {
+        tracing::warn!("Skipping entry with non-UTF-8 name: {}", path.display());
+    }
+    name
+}
+
 pub fn time_as_num(time: SystemTime) -> f64 {
     (time.duration_since(UNIX_EPOCH).unwrap().as_millis() as f64) / 1000.0
 }
@@ -0,0 +1,302 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::store;
+use crate::{location, metadata, metadata_store};
+
+/// A problem found while verifying a repository.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Problem {
+    /// The packet's metadata could not be read or parsed.
+    InvalidMetadata { packet: String, detail: String },
+    /// A file in the store does not match the hash it is stored under.
+    CorruptFile { hash: String },
+    /// A file referenced by a packet is not in the store.
+    MissingFile { packet: String, hash: String },
+    /// A packet depends on a packet that is not in the repository.
+    MissingDependency { packet: String, dependency: String },
+    /// A location lists a packet whose metadata is not in the repository.
+    UnknownPacket { packet: String, location: String },
+    /// The entries of a location could not be read.
+    InvalidLocation { location: String, detail: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::InvalidMetadata { packet, detail } => {
+                write!(f, "Packet {} has invalid metadata: {}", packet, detail)
+            }
+            Problem::CorruptFile { hash } => write!(f, "File {} does not match its hash", hash),
+            Problem::MissingFile { packet, hash } => {
+                write!(f, "Packet {} is missing file {}", packet, hash)
+            }
+            Problem::MissingDependency { packet, dependency } => {
+                write!(f, "Packet {} is missing dependency {}", packet, dependency)
+            }
+            Problem::UnknownPacket { packet, location } => {
+                write!(
+                    f,
+                    "Location {} lists packet {}, which has no metadata",
+                    location, packet
+                )
+            }
+            Problem::InvalidLocation { location, detail } => {
+                write!(f, "Location {} could not be read: {}", location, detail)
+            }
+        }
+    }
+}
+
+/// The result of verifying a repository.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VerifyReport {
+    /// The number of packets whose metadata was checked.
+    pub packets: usize,
+    /// The number of files in the store.
+    pub files: usize,
+    /// Whether the contents of stored files were re-hashed.
+    pub hashes_checked: bool,
+    /// Every problem found, rather than just the first.
+    pub problems: Vec<Problem>,
+}
+
+/// Check the integrity of a repository.
+///
+/// Every packet's metadata must parse, and every file and dependency it references must be
+/// present. If `check_hashes` is true, every file in the store is also re-hashed, which can be
+/// slow for large repositories.
+///
+/// Packets are read one at a time, so memory use is bounded by the number of problems found
+/// rather than the size of the repository.
+pub fn verify_repository(root: &Path, check_hashes: bool) -> io::Result<VerifyReport> {
+    let mut problems = Vec::new();
+    let layout = store::layout(root)?;
+
+    if check_hashes {
+        problems.extend(
+            store::verify(root, layout)?
+                .into_iter()
+                .map(|hash| Problem::CorruptFile { hash }),
+        );
+    }
+
+    let metadata = metadata_store::open(root)?;
+    let ids = metadata::packet_ids(metadata.as_ref())?;
+    let known: HashSet<&String> = ids.iter().collect();
+
+    for id in &ids {
+        let packet = match metadata::read_packet(metadata.as_ref(), id) {
+            Ok(packet) => packet,
+            Err(e) => {
+                problems.push(Problem::InvalidMetadata {
+                    packet: id.clone(),
+                    detail: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let files: Vec<String> = packet.files.iter().map(|f| f.hash.clone()).collect();
+        for hash in store::get_missing_files(root, layout, &files)? {
+            problems.push(Problem::MissingFile {
+                packet: id.clone(),
+                hash,
+            });
+        }
+
+        for dependency in &packet.depends {
+            if !known.contains(&dependency.packet) {
+                problems.push(Problem::MissingDependency {
+                    packet: id.clone(),
+                    dependency: dependency.packet.clone(),
+                });
+            }
+        }
+    }
+
+    problems.extend(check_locations(root, &known)?);
+
+    Ok(VerifyReport {
+        packets: ids.len(),
+        files: store::enumerate_files(root, layout)?.count(),
+        hashes_checked: check_hashes,
+        problems,
+    })
+}
+
+/// Find location entries for packets which aren't among the known ids.
+///
+/// Each location is read separately, so that one which can't be read doesn't hide problems with
+/// the others.
+fn check_locations(root: &Path, known: &HashSet<&String>) -> io::Result<Vec<Problem>> {
+    let path = root.join(".outpack").join("location");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut dirs: Vec<_> = fs::read_dir(path)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    dirs.sort();
+
+    let mut problems = Vec::new();
+    for dir in dirs {
+        let name = dir.file_name().unwrap().to_string_lossy().into_owned();
+        match location::read_location(dir) {
+            Ok(entries) => problems.extend(
+                entries
+                    .into_iter()
+                    .filter(|entry| !known.contains(&entry.packet))
+                    .map(|entry| Problem::UnknownPacket {
+                        packet: entry.packet,
+                        location: name.clone(),
+                    }),
+            ),
+            Err(e) => problems.push(Problem::InvalidLocation {
+                location: name,
+                detail: e.to_string(),
+            }),
+        }
+    }
+    Ok(problems)
+}
+
+/// Quickly look for common inconsistencies in a repository: metadata which doesn't parse, and
+/// location entries for packets without metadata.
+///
+/// Unlike `verify_repository`, this doesn't look at the file store, so it is fast enough to run
+/// whenever the server starts.
+pub fn check_repository(root: &Path) -> io::Result<Vec<Problem>> {
+    let metadata = metadata_store::open(root)?;
+    let ids = metadata::packet_ids(metadata.as_ref())?;
+    let mut problems: Vec<Problem> = ids
+        .iter()
+        .filter_map(|id| {
+            metadata::read_packet(metadata.as_ref(), id)
+                .err()
+                .map(|e| Problem::InvalidMetadata {
+                    packet: id.clone(),
+                    detail: e.to_string(),
+                })
+        })
+        .collect();
+    problems.extend(check_locations(root, &ids.iter().collect())?);
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tests::{get_empty_outpack_root, get_temp_outpack_root};
+    use std::fs;
+
+    #[test]
+    fn empty_repository_has_no_problems() {
+        let root = get_empty_outpack_root();
+        let report = verify_repository(&root, true).unwrap();
+        assert_eq!(report.packets, 0);
+        assert_eq!(report.files, 0);
+        assert!(report.problems.is_empty());
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let root = get_temp_outpack_root();
+        let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+        fs::write(
+            store::file_path(&root, store::layout(&root).unwrap(), hash).unwrap(),
+            "corrupted",
+        )
+        .unwrap();
+        fs::write(
+            root.join(".outpack/metadata/20240101-000000-00000000"),
+            "{ not json",
+        )
+        .unwrap();
+
+        let report = verify_repository(&root, true).unwrap();
+        assert_eq!(report.packets, 5);
+        assert_eq!(report.files, 1);
+        assert!(report.problems.contains(&Problem::CorruptFile {
+            hash: hash.to_owned()
+        }));
+        assert!(report.problems.contains(&Problem::MissingDependency {
+            packet: String::from("20180818-164043-7cdcde4b"),
+            dependency: String::from("20170818-164043-7cdcde4b"),
+        }));
+        assert!(report.problems.iter().any(|p| matches!(
+            p,
+            Problem::InvalidMetadata { packet, .. } if packet == "20240101-000000-00000000"
+        )));
+        // Each of the four valid example packets is missing all 21 of its files.
+        let missing_files = report
+            .problems
+            .iter()
+            .filter(|p| matches!(p, Problem::MissingFile { .. }))
+            .count();
+        assert_eq!(missing_files, 84);
+
+        assert!(!report
+            .problems
+            .iter()
+            .any(|p| matches!(p, Problem::UnknownPacket { .. })));
+
+        let report = verify_repository(&root, false).unwrap();
+        assert!(!report.hashes_checked);
+        assert!(!report
+            .problems
+            .iter()
+            .any(|p| matches!(p, Problem::CorruptFile { .. })));
+    }
+
+    #[test]
+    fn reports_location_entries_without_metadata() {
+        let root = get_temp_outpack_root();
+        let id = "20170818-164847-7574883b";
+        fs::remove_file(root.join(".outpack/metadata").join(id)).unwrap();
+
+        let report = verify_repository(&root, false).unwrap();
+        let unknown: Vec<&Problem> = report
+            .problems
+            .iter()
+            .filter(|p| matches!(p, Problem::UnknownPacket { .. }))
+            .collect();
+        assert_eq!(
+            unknown,
+            vec![&Problem::UnknownPacket {
+                packet: String::from(id),
+                location: String::from("local"),
+            }]
+        );
+    }
+
+    #[test]
+    fn check_finds_inconsistencies() {
+        assert!(check_repository(Path::new("tests/example"))
+            .unwrap()
+            .is_empty());
+
+        let problems = check_repository(Path::new("tests/bad-example")).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert!(matches!(
+            &problems[0],
+            Problem::InvalidMetadata { packet, detail }
+                if packet == "20170818-164847-7574883b" && detail.contains("missing field `name`")
+        ));
+        assert!(matches!(
+            &problems[1],
+            Problem::InvalidLocation { location, detail }
+                if location == "another" && detail.contains("missing field `packet`")
+        ));
+        assert!(problems[1]
+            .to_string()
+            .starts_with("Location another could not be read: "));
+    }
+}
@@ -0,0 +1,114 @@
+//! A fixed-size pool of threads for CPU-bound work, such as hashing files.
+//!
+//! Tokio's blocking pool is also used for file I/O, including writing uploads to disk, and grows
+//! to hundreds of threads under load. Hashing many large files on it at once can saturate the CPU
+//! and hold up that other work. Running hashing on this pool instead bounds how many files are
+//! hashed at the same time, and queues any further work until a thread is free.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone)]
+pub struct WorkerPool {
+    sender: mpsc::Sender<Job>,
+}
+
+impl WorkerPool {
+    /// Start a pool with the given number of threads, which must be at least one.
+    ///
+    /// The threads exit once every clone of the pool has been dropped.
+    pub fn new(size: usize) -> WorkerPool {
+        assert!(size > 0, "worker pool must have at least one thread");
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..size {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("outpack-worker-{}", i))
+                .spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        // A panic is reported to the caller by its result being dropped, so the
+                        // thread can carry on with the next job.
+                        Ok(job) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        Err(_) => break,
+                    }
+                })
+                .expect("worker thread started");
+        }
+        WorkerPool { sender }
+    }
+
+    /// The number of threads used when no size is configured.
+    pub fn default_size() -> usize {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    }
+
+    /// Run a function on the pool, waiting for its result.
+    ///
+    /// Panics if the function does, as `spawn_blocking(f).await.unwrap()` would.
+    pub async fn run<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Box::new(move || {
+                let _ = sender.send(f());
+            }))
+            .expect("worker pool is running");
+        receiver.await.expect("worker pool job panicked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn can_run_jobs() {
+        let pool = WorkerPool::new(2);
+        assert_eq!(pool.run(|| 1 + 1).await, 2);
+        let name = pool
+            .run(|| thread::current().name().map(String::from))
+            .await;
+        assert!(name.unwrap().starts_with("outpack-worker-"));
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_jobs() {
+        let pool = WorkerPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let jobs = (0..8).map(|_| {
+            let (running, most) = (running.clone(), most.clone());
+            pool.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+        futures::future::join_all(jobs).await;
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn survives_panicking_jobs() {
+        let pool = WorkerPool::new(1);
+        let other = pool.clone();
+        let result = tokio::spawn(async move { other.run(|| panic!("oops")).await }).await;
+        assert!(result.is_err());
+        assert_eq!(pool.run(|| "still running").await, "still running");
+    }
+}
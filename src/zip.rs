@@ -0,0 +1,140 @@
+//! Streaming zip archives of files from the store.
+//!
+//! Entries are stored uncompressed and streamed into the archive as they are read, with their
+//! checksums and sizes following their contents, so that files are never read twice or buffered in
+//! memory. Streamed entries always use the zip64 extensions, so archives are not limited in size.
+
+use std::io;
+use std::path::PathBuf;
+
+use async_zip::base::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use tokio::io::AsyncWrite;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::responses::{coded_error, ErrorCode};
+
+/// Check that a path is suitable for naming an entry.
+///
+/// Names must be relative and may not refer to a parent directory, so that extracting the archive
+/// can never write outside of the destination.
+pub fn validate_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('/')
+        && !name.contains('\\')
+        && !name.contains(':')
+        && name
+            .split('/')
+            .all(|c| !c.is_empty() && c != "." && c != "..");
+    if valid {
+        Ok(())
+    } else {
+        Err(coded_error(
+            io::ErrorKind::InvalidInput,
+            ErrorCode::InvalidArchive,
+            format!("Invalid path in archive '{}'", name),
+        ))
+    }
+}
+
+/// Write an archive of the given files, each entry named by the first of its pair and with the
+/// contents of the file at the second.
+pub async fn write_archive(
+    writer: impl AsyncWrite + Unpin,
+    entries: Vec<(String, PathBuf)>,
+) -> io::Result<()> {
+    let mut archive = ZipFileWriter::with_tokio(writer);
+    for (name, path) in entries {
+        let file = tokio::fs::File::open(path).await?;
+        let entry = ZipEntryBuilder::new(name.into(), Compression::Stored);
+        let mut contents = archive.write_entry_stream(entry).await.map_err(zip_error)?;
+        futures::io::copy(file.compat(), &mut contents).await?;
+        contents.close().await.map_err(zip_error)?;
+    }
+    archive.close().await.map_err(zip_error)?;
+    Ok(())
+}
+
+fn zip_error(e: async_zip::error::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_zip::base::read::mem::ZipFileReader;
+
+    /// Read back the entries of an archive, checking each one's checksum.
+    async fn read_archive(data: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let archive = ZipFileReader::new(data).await.unwrap();
+        let mut entries = Vec::new();
+        for (i, entry) in archive.file().entries().iter().enumerate() {
+            let name = entry.filename().as_str().unwrap().to_owned();
+            let mut contents = Vec::new();
+            archive
+                .reader_with_entry(i)
+                .await
+                .unwrap()
+                .read_to_end_checked(&mut contents)
+                .await
+                .unwrap();
+            entries.push((name, contents));
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn can_write_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let hello = dir.path().join("hello");
+        let empty = dir.path().join("empty");
+        std::fs::write(&hello, "Hello").unwrap();
+        std::fs::write(&empty, "").unwrap();
+
+        let mut data = Vec::new();
+        write_archive(
+            &mut data,
+            vec![
+                (String::from("hello.txt"), hello),
+                (String::from("data/empty"), empty),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            read_archive(data).await,
+            vec![
+                (String::from("hello.txt"), b"Hello".to_vec()),
+                (String::from("data/empty"), Vec::new()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn can_write_empty_archive() {
+        let mut data = Vec::new();
+        write_archive(&mut data, Vec::new()).await.unwrap();
+        assert_eq!(read_archive(data).await, vec![]);
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(validate_name("data.csv").is_ok());
+        assert!(validate_name("outputs/data.csv").is_ok());
+        for name in [
+            "",
+            "/etc/passwd",
+            "../data.csv",
+            "a/../../b",
+            "a//b",
+            "./a",
+            "C:\\a",
+            "a/",
+        ] {
+            assert_eq!(
+                validate_name(name).unwrap_err().to_string(),
+                format!("Invalid path in archive '{}'", name)
+            );
+        }
+    }
+}
@@ -1,9 +1,35 @@
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use git2::{Branches, BranchType, Commit, Repository, Signature};
 use git2::build::RepoBuilder;
 use tempdir::TempDir;
 
+/// Copy the example repository, from `tests/example` relative to the working directory, into a
+/// new temporary directory and return the path of the copy.
+///
+/// The example is packed into an in-memory, gzipped tarball the first time this is called, and
+/// every copy is unpacked from it. Nothing is written outside the temporary directory, so separate test
+/// binaries running at the same time can't interfere with each other.
+pub fn get_example_dir() -> PathBuf {
+    static EXAMPLE: OnceLock<Vec<u8>> = OnceLock::new();
+    let archive = EXAMPLE.get_or_init(|| {
+        let encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        builder.append_dir_all("example", "tests/example").unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    });
+
+    let tmp_dir = TempDir::new("outpack").expect("Temp dir created");
+    tar::Archive::new(GzDecoder::new(archive.as_slice()))
+        .unpack(&tmp_dir)
+        .expect("Example unpacked");
+    tmp_dir.into_path().join("example")
+}
+
 pub struct TestGit {
     pub dir: TempDir,
     pub remote: Repository,
@@ -152,6 +178,6 @@ fn git_checkout(repo: &Repository, branch_name: &str, new_branch: bool) {
     repo.set_head(reference.unwrap().name().unwrap()).unwrap();
 }
 
-pub fn git_remote_branches(repo: &Repository) -> Branches {
+pub fn git_remote_branches(repo: &Repository) -> Branches<'_> {
     repo.branches(Some(BranchType::Remote)).unwrap()
 }
@@ -1,5 +1,5 @@
 import pytest
-from outpack_query_parser import parse_query, Latest, Literal, LookupName, Literal
+from outpack_query_parser import parse_query, Has, Latest, Literal, LookupCustom, LookupName, LookupParameter, LookupTime
 
 # Importing Test* types makes pytest freak out. Use a short module name instead.
 import outpack_query_parser as parser
@@ -8,7 +8,18 @@ def test_parse():
     assert parse_query("latest") == Latest(None)
     assert parse_query("latest()") == Latest(None)
     assert parse_query("name == 'foo'") == parser.Test(parser.TestOperator.Equal, LookupName(), Literal("foo"))
+    assert parse_query("time >= 1503074938") == parser.Test(parser.TestOperator.GreaterThanOrEqual, LookupTime("start"), Literal(1503074938))
+    assert parse_query("time.end < 1600000000") == parser.Test(parser.TestOperator.LessThan, LookupTime("end"), Literal(1600000000))
+    assert parse_query("hasfile(path == 'data.csv')") == parser.HasFile("path", "data.csv")
+    assert parse_query("has(parameter:disease)") == Has(LookupParameter("disease"))
+    assert parse_query("custom:orderly.displayname == 'Foo'") == parser.Test(parser.TestOperator.Equal, LookupCustom("orderly.displayname"), Literal("Foo"))
 
 def test_error():
     with pytest.raises(ValueError, match="expected query"):
         parse_query("foo")
+
+def test_error_position():
+    with pytest.raises(ValueError, match="at line 1, column 9") as e:
+        parse_query("name == ")
+    assert (e.value.offset, e.value.line, e.value.column) == (8, 1, 9)
+    assert str(e.value).endswith("name == \n        ^")
@@ -1,5 +1,6 @@
 use assert_cmd::prelude::*;
 use predicates::prelude::*;
+use std::path::Path;
 use std::process::Command;
 
 #[test]
@@ -7,3 +8,279 @@ fn prints_usage_if_args_invalid() {
     let mut cmd = Command::cargo_bin("outpack").unwrap();
     cmd.assert().stderr(predicate::str::contains("Usage:"));
 }
+
+#[test]
+fn can_parse_query_as_json() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    let output = cmd
+        .args(["parse", "--format", "json", "name == 'foo'"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let result: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        result,
+        serde_json::json!({
+            "type": "Test",
+            "operator": "Equal",
+            "lhs": { "type": "LookupName" },
+            "rhs": { "type": "Literal", "value": "foo" }
+        })
+    );
+}
+
+#[test]
+fn can_parse_query_to_canonical_form() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args([
+        "parse",
+        "--format",
+        "query",
+        "latest(name=='foo'&&(time>1||parameter:a==TRUE))",
+    ])
+    .assert()
+    .success()
+    .stdout(predicate::str::diff(
+        "latest(name == \"foo\" && (time.start > 1 || parameter:a == true))\n",
+    ));
+}
+
+#[test]
+fn parse_defaults_to_human_format() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["parse", "latest"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Latest(None)"));
+}
+
+fn init_repository(path: &Path) {
+    Command::cargo_bin("outpack")
+        .unwrap()
+        .args(["init", "--use-file-store"])
+        .arg(path)
+        .assert()
+        .success();
+}
+
+fn packet_metadata(id: &str, files: serde_json::Value) -> String {
+    serde_json::json!({
+        "id": id,
+        "name": "data",
+        "custom": null,
+        "parameters": null,
+        "files": files,
+        "depends": [],
+        "time": { "start": 1682607306.0, "end": 1682607306.5 }
+    })
+    .to_string()
+}
+
+#[test]
+fn can_import_packet() {
+    let tmp = tempdir::TempDir::new("outpack").unwrap();
+    let root = tmp.path().join("repo");
+    init_repository(&root);
+
+    let metadata_file = tmp.path().join("metadata.json");
+    let id = "20230427-150828-68772cee";
+    std::fs::write(&metadata_file, packet_metadata(id, serde_json::json!([]))).unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("import")
+        .arg("--root")
+        .arg(&root)
+        .arg(&metadata_file)
+        .assert()
+        .success()
+        .stdout(predicate::str::diff(format!("{}\n", id)));
+
+    assert!(root.join(".outpack").join("metadata").join(id).exists());
+    assert!(root
+        .join(".outpack")
+        .join("location")
+        .join("local")
+        .join(id)
+        .exists());
+}
+
+#[test]
+fn import_fails_if_files_are_missing() {
+    let tmp = tempdir::TempDir::new("outpack").unwrap();
+    let root = tmp.path().join("repo");
+    init_repository(&root);
+
+    let metadata_file = tmp.path().join("metadata.json");
+    let id = "20230427-150828-68772cee";
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let files = serde_json::json!([{ "path": "data.csv", "hash": hash, "size": 21 }]);
+    std::fs::write(&metadata_file, packet_metadata(id, files)).unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("import")
+        .arg("--root")
+        .arg(&root)
+        .arg(&metadata_file)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "Can't import metadata for {}, as files missing",
+            id
+        )))
+        .stderr(predicate::str::contains(hash));
+
+    assert!(!root.join(".outpack").join("metadata").join(id).exists());
+}
+
+#[test]
+fn import_fails_for_unknown_location() {
+    let tmp = tempdir::TempDir::new("outpack").unwrap();
+    let root = tmp.path().join("repo");
+    init_repository(&root);
+
+    let metadata_file = tmp.path().join("metadata.json");
+    let id = "20230427-150828-68772cee";
+    std::fs::write(&metadata_file, packet_metadata(id, serde_json::json!([]))).unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("import")
+        .arg("--root")
+        .arg(&root)
+        .arg("--location")
+        .arg("origin")
+        .arg(&metadata_file)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("location 'origin' does not exist"));
+
+    assert!(!root.join(".outpack").join("metadata").join(id).exists());
+}
+
+#[test]
+fn can_verify_repository() {
+    let tmp = tempdir::TempDir::new("outpack").unwrap();
+    let root = tmp.path().join("repo");
+    init_repository(&root);
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("verify")
+        .arg("--root")
+        .arg(&root)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("0 problem(s) found"));
+}
+
+#[test]
+fn verify_can_prune_location_entries() {
+    let tmp = tempdir::TempDir::new("outpack").unwrap();
+    let root = tmp.path().join("repo");
+    init_repository(&root);
+
+    let id = "20170818-164847-7574883b";
+    let location = root.join(".outpack").join("location").join("local");
+    std::fs::create_dir_all(&location).unwrap();
+    std::fs::write(
+        location.join(id),
+        r#"{"packet":"20170818-164847-7574883b","time":1662480556.1778,"hash":"sha256:af3c863f96898c6c88cee4daa1a6d6cfb756025e70059f5ea4dbe4d9cc5e0e36"}"#,
+    )
+    .unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify", "--root"])
+        .arg(&root)
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(format!(
+            "Location local lists packet {}, which has no metadata",
+            id
+        )));
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify", "--fix", "--root"])
+        .arg(&root)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "Removed entry for packet {} from location local",
+            id
+        )))
+        .stdout(predicate::str::contains("Pruned 1 location entries"))
+        .stdout(predicate::str::contains("0 problem(s) found"));
+    assert!(!location.join(id).exists());
+}
+
+#[test]
+fn verify_fails_on_corrupt_store_file() {
+    let tmp = tempdir::TempDir::new("outpack").unwrap();
+    let root = tmp.path().join("repo");
+    init_repository(&root);
+
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let dir = root
+        .join(".outpack")
+        .join("files")
+        .join("sha256")
+        .join("b1");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"),
+        "corrupted",
+    )
+    .unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("verify")
+        .arg("--root")
+        .arg(&root)
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(format!(
+            "File {} does not match its hash",
+            hash
+        )))
+        .stdout(predicate::str::contains("1 problem(s) found"));
+}
+
+#[test]
+fn verify_fails_on_missing_files_and_dependencies() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify", "--root", "tests/example"])
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains(
+            "Packet 20170818-164830-33e0ab01 is missing file sha256:",
+        ))
+        .stdout(predicate::str::contains(
+            "Packet 20180818-164043-7cdcde4b is missing dependency 20170818-164043-7cdcde4b",
+        ))
+        .stderr(predicate::str::contains("Repository verification failed"));
+}
+
+#[test]
+fn search_for_latest_in_empty_repository_succeeds() {
+    let tmp = tempdir::TempDir::new("outpack").unwrap();
+    let root = tmp.path().join("repo");
+    init_repository(&root);
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("search")
+        .arg("--root")
+        .arg(&root)
+        .arg("latest()")
+        .assert()
+        .success()
+        .stdout(predicate::str::diff("Found no packets\n"));
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("search")
+        .arg("--root")
+        .arg(&root)
+        .arg("single(latest())")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Query found 0 packets, but expected exactly one",
+        ));
+}
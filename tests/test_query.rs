@@ -94,6 +94,60 @@ fn can_get_packet_by_parameter() {
     );
 }
 
+#[test]
+fn can_get_packet_by_custom_field() {
+    let root_path = Path::new("tests/example");
+    test_query(
+        root_path,
+        r#"custom:orderly.displayname == "Modified Update""#,
+        "20170818-164830-33e0ab01\n20180220-095832-16a4bbed\n20180818-164043-7cdcde4b",
+    );
+    test_query(
+        root_path,
+        r#"latest(custom:orderly.displayname == "Modified Update")"#,
+        "20180818-164043-7cdcde4b",
+    );
+    // Missing paths, and paths to objects or nulls, never match.
+    for query in [
+        r#"custom:orderly.unknown == "Modified Update""#,
+        r#"custom:unknown.displayname != "Modified Update""#,
+        r#"custom:orderly == "Modified Update""#,
+        r#"custom:orderly.custom == "Modified Update""#,
+    ] {
+        test_query(root_path, query, "Found no packets");
+    }
+}
+
+#[test]
+fn can_get_packet_by_time() {
+    let root_path = Path::new("tests/example");
+    // Boolean operators don't preserve the order of packets.
+    let packets =
+        outpack::query::run_query(root_path, "time >= 1503074938 && time < 1510000000").unwrap();
+    let mut ids: Vec<&str> = packets.lines().collect();
+    ids.sort();
+    assert_eq!(
+        ids,
+        [
+            "20170818-164830-33e0ab01",
+            "20170818-164847-7574883b",
+            "20180818-164043-7cdcde4b"
+        ]
+    );
+    test_query(root_path, "time > 1510000000", "20180220-095832-16a4bbed");
+    test_query(root_path, "time.start > 1519120600", "Found no packets");
+    test_query(
+        root_path,
+        "time.end > 1519120600",
+        "20180220-095832-16a4bbed",
+    );
+    test_query(
+        root_path,
+        "latest(time < 1510000000)",
+        "20180818-164043-7cdcde4b",
+    );
+}
+
 #[test]
 fn can_get_packet_by_boolean_parameter() {
     let root_path = Path::new("tests/example");
@@ -354,3 +408,18 @@ fn comparisons_work_in_any_order_with_any_types() {
     test_query(root_path, "2 != 1",
                "20170818-164830-33e0ab01\n20170818-164847-7574883b\n20180220-095832-16a4bbed\n20180818-164043-7cdcde4b");
 }
+
+#[test]
+fn can_get_packets_by_file() {
+    let root_path = Path::new("tests/example");
+    test_query(
+        root_path,
+        r#"hasfile("sha256:8916131d0bebabc5ab098ae3a34b03389768e1ef15acba74ea265deddeba579f") && name == "modup-201707-params1""#,
+        "20180220-095832-16a4bbed",
+    );
+    test_query(
+        root_path,
+        r#"hasfile(path == "orderly.yml")"#,
+        "20170818-164830-33e0ab01\n20170818-164847-7574883b\n20180220-095832-16a4bbed\n20180818-164043-7cdcde4b",
+    );
+}